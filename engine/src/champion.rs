@@ -0,0 +1,65 @@
+use std::path::Path;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::load_definitions;
+
+/// A playable champion, as defined by a file in `assets/champions`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChampionDef {
+    /// Identifies the champion everywhere, including the lobby protocol.
+    pub id: String,
+    pub name: String,
+    pub stats: BaseStats,
+    /// Ids of the champion's abilities, in key order.
+    pub abilities: Vec<String>,
+    /// Image shown in champ select, relative to the assets directory.
+    pub portrait: String,
+}
+
+/// A champion's stats at level 1.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BaseStats {
+    pub health: f32,
+    pub mana: f32,
+    pub attack_damage: f32,
+    /// Attacks per second.
+    pub attack_speed: f32,
+    pub attack_range: f32,
+    pub armor: f32,
+    pub magic_resist: f32,
+    pub move_speed: f32,
+}
+
+/// Every champion definition, sorted by id.
+///
+/// The lobby server, game server and client all load this from the same files,
+/// so champions can be referred to by id alone.
+#[derive(Clone, Debug, Default)]
+pub struct ChampionRegistry {
+    champions: Vec<ChampionDef>,
+}
+
+impl ChampionRegistry {
+    /// Loads every `.json` file in `dir`, usually `assets/champions`.
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut champions = load_definitions::<ChampionDef>(dir.as_ref())?;
+        champions.sort_by(|a, b| a.id.cmp(&b.id));
+        if let Some(pair) = champions.windows(2).find(|pair| pair[0].id == pair[1].id) {
+            bail!("Champion {:?} is defined more than once", pair[0].id);
+        }
+        Ok(Self { champions })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ChampionDef> {
+        self.champions
+            .binary_search_by(|c| c.id.as_str().cmp(id))
+            .ok()
+            .map(|i| &self.champions[i])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ChampionDef> {
+        self.champions.iter()
+    }
+}
@@ -1,9 +1,13 @@
-mod map;
+pub mod champion;
+pub mod map;
+pub mod net;
 mod unit;
 
-use std::time::Duration;
+use std::{path::Path, time::Duration};
 
+use anyhow::Context;
 use lightyear::prelude::*;
+use serde::de::DeserializeOwned;
 
 pub const FIXED_TIMESTEP_HZ: f64 = 64.0;
 pub const SERVER_REPLICATION_INTERVAL: Duration = Duration::from_millis(100);
@@ -17,3 +21,20 @@ pub fn shared_config() -> SharedConfig {
         mode: Mode::Separate,
     }
 }
+
+/// Reads every `.json` file in `dir` as a `T`.
+fn load_definitions<T: DeserializeOwned>(dir: &Path) -> anyhow::Result<Vec<T>> {
+    let mut definitions = vec![];
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let definition = serde_json::from_str(&std::fs::read_to_string(&path)?)
+            .with_context(|| format!("Invalid definition {}", path.display()))?;
+        definitions.push(definition);
+    }
+    Ok(definitions)
+}
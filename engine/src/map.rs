@@ -1 +1,125 @@
+use std::path::Path;
 
+use anyhow::{bail, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::load_definitions;
+
+/// A map, as defined by a file in `assets/maps`.
+///
+/// Positions are on the ground plane, as `[x, z]`, with the origin at the center of the map.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MapDef {
+    /// Identifies the map, including in the lobby settings.
+    pub name: String,
+    pub min_teams: usize,
+    pub max_teams: usize,
+    /// Width and depth of the ground.
+    pub size: [f32; 2],
+    /// Where each team spawns, indexed by team; there is one for every team the map supports.
+    pub spawns: Vec<[f32; 2]>,
+    pub lanes: Vec<LaneDef>,
+    pub structures: Vec<StructureDef>,
+    /// The walkable area, which the navigation mesh is built from.
+    pub navmesh: NavMeshSource,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LaneDef {
+    pub name: String,
+    /// The path minions follow, from the first team's side of the map to the second's.
+    pub points: Vec<[f32; 2]>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StructureDef {
+    pub kind: StructureKind,
+    /// Index of the team owning the structure.
+    pub team: usize,
+    pub position: [f32; 2],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StructureKind {
+    Tower,
+    Inhibitor,
+    Nexus,
+}
+
+/// Convex polygons covering the walkable area, sharing vertices where they meet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NavMeshSource {
+    pub vertices: Vec<[f32; 2]>,
+    /// Indices into `vertices`, counter-clockwise.
+    pub polygons: Vec<Vec<usize>>,
+}
+
+impl MapDef {
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            1 <= self.min_teams && self.min_teams <= self.max_teams,
+            "invalid team range {}..={}",
+            self.min_teams,
+            self.max_teams
+        );
+        ensure!(
+            self.spawns.len() >= self.max_teams,
+            "{} spawns for up to {} teams",
+            self.spawns.len(),
+            self.max_teams
+        );
+        if let Some(structure) = self.structures.iter().find(|s| s.team >= self.max_teams) {
+            bail!(
+                "{:?} belongs to nonexistent team {}",
+                structure.kind,
+                structure.team
+            );
+        }
+        let vertex_count = self.navmesh.vertices.len();
+        for polygon in &self.navmesh.polygons {
+            ensure!(
+                polygon.len() >= 3,
+                "navmesh polygon with fewer than 3 vertices"
+            );
+            ensure!(
+                polygon.iter().all(|i| *i < vertex_count),
+                "navmesh polygon refers to a nonexistent vertex"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Every map definition, sorted by name.
+#[derive(Clone, Debug, Default)]
+pub struct MapRegistry {
+    maps: Vec<MapDef>,
+}
+
+impl MapRegistry {
+    /// Loads every `.json` file in `dir`, usually `assets/maps`.
+    pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let mut maps = load_definitions::<MapDef>(dir.as_ref())?;
+        for map in &maps {
+            if let Err(e) = map.validate() {
+                bail!("Invalid map {:?}: {e}", map.name);
+            }
+        }
+        maps.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(pair) = maps.windows(2).find(|pair| pair[0].name == pair[1].name) {
+            bail!("Map {:?} is defined more than once", pair[0].name);
+        }
+        Ok(Self { maps })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MapDef> {
+        self.maps
+            .binary_search_by(|m| m.name.as_str().cmp(name))
+            .ok()
+            .map(|i| &self.maps[i])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MapDef> {
+        self.maps.iter()
+    }
+}
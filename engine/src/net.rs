@@ -0,0 +1,24 @@
+use bevy::prelude::*;
+use lightyear::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The champion a player controls.
+#[derive(Component, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Champion {
+    /// The champion's id in the [`ChampionRegistry`](crate::champion::ChampionRegistry).
+    pub id: String,
+    /// Index of the player's team.
+    pub team: usize,
+    /// The netcode client ID of the player.
+    pub client: u64,
+}
+
+/// Registers everything sent between the game server and its clients.
+/// Both sides must add it, after the lightyear plugins.
+pub struct ProtocolPlugin;
+
+impl Plugin for ProtocolPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_component::<Champion>(ChannelDirection::ServerToClient);
+    }
+}
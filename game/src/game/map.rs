@@ -1,4 +1,6 @@
 use bevy::prelude::*;
+use engine::map::{MapDef, StructureKind};
+use protocol::Team;
 
 use super::camera::CameraTarget;
 
@@ -6,11 +8,89 @@ pub fn map(app: &mut App) {
     app.add_systems(OnEnter(crate::State::InGame), spawn_map);
 }
 
-pub fn spawn_map(assets: Res<AssetServer>, mut commands: Commands) {
+/// The map of the game being played, set when the lobby server starts the game.
+#[derive(Resource)]
+pub struct CurrentMap {
+    pub def: MapDef,
+    /// Our team, which decides where the camera starts.
+    pub team: Option<Team>,
+}
+
+fn ground(position: [f32; 2]) -> Vec3 {
+    Vec3::new(position[0], 0.0, position[1])
+}
+
+pub fn spawn_map(map: Option<Res<CurrentMap>>, assets: Res<AssetServer>, mut commands: Commands) {
+    let Some(map) = map else {
+        error!("No map to spawn");
+        return;
+    };
+    let def = &map.def;
+
     commands.spawn((
-        Mesh3d(assets.add(Plane3d::new(Vec3::Y, Vec2::new(50.0, 50.0)).into())),
+        Mesh3d(assets.add(Plane3d::new(Vec3::Y, Vec2::from(def.size) / 2.0).into())),
         MeshMaterial3d(assets.add(StandardMaterial { ..default() })),
+        StateScoped(crate::State::InGame),
     ));
 
-    commands.spawn((Transform::from_translation(Vec3::ZERO), CameraTarget));
+    // Lanes, as flat strips between their points
+    let lane_material = assets.add(StandardMaterial {
+        base_color: Color::srgb(0.6, 0.5, 0.35),
+        ..default()
+    });
+    for lane in &def.lanes {
+        for segment in lane.points.windows(2) {
+            let (start, end) = (ground(segment[0]), ground(segment[1]));
+            let length = start.distance(end);
+            commands.spawn((
+                Mesh3d(assets.add(Cuboid::new(4.0, 0.02, length).into())),
+                MeshMaterial3d(lane_material.clone()),
+                Transform::from_translation((start + end) / 2.0).looking_at(end, Vec3::Y),
+                StateScoped(crate::State::InGame),
+            ));
+        }
+    }
+
+    let team_materials = (0..def.max_teams)
+        .map(|team| {
+            assets.add(StandardMaterial {
+                base_color: Color::hsl(team as f32 * 360.0 / def.max_teams as f32, 0.6, 0.5),
+                ..default()
+            })
+        })
+        .collect::<Vec<_>>();
+    for structure in &def.structures {
+        let mesh = match structure.kind {
+            StructureKind::Tower => Cuboid::new(1.5, 6.0, 1.5),
+            StructureKind::Inhibitor => Cuboid::new(3.0, 2.0, 3.0),
+            StructureKind::Nexus => Cuboid::new(5.0, 4.0, 5.0),
+        };
+        let height = mesh.half_size.y;
+        commands.spawn((
+            Mesh3d(assets.add(mesh.into())),
+            MeshMaterial3d(team_materials[structure.team].clone()),
+            Transform::from_translation(ground(structure.position) + Vec3::Y * height),
+            StateScoped(crate::State::InGame),
+        ));
+    }
+
+    // Spawn platforms, one for every team the map supports
+    for (team, spawn) in def.spawns.iter().enumerate().take(def.max_teams) {
+        commands.spawn((
+            Mesh3d(assets.add(Cylinder::new(4.0, 0.05).into())),
+            MeshMaterial3d(team_materials[team].clone()),
+            Transform::from_translation(ground(*spawn)),
+            StateScoped(crate::State::InGame),
+        ));
+    }
+
+    let start = map
+        .team
+        .and_then(|Team(team)| def.spawns.get(team))
+        .map_or(Vec3::ZERO, |spawn| ground(*spawn));
+    commands.spawn((
+        Transform::from_translation(start),
+        CameraTarget,
+        StateScoped(crate::State::InGame),
+    ));
 }
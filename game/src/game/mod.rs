@@ -10,6 +10,7 @@ pub fn game(app: &mut App) {
     app.add_plugins((camera::camera, map::map));
 
     app.add_systems(OnEnter(crate::State::InGame), setup);
+    app.add_systems(OnExit(crate::State::InGame), disconnect);
 }
 
 fn setup(
@@ -21,5 +22,11 @@ fn setup(
         unreachable!()
     };
     *auth = Authentication::Token(token.0.clone());
+
     commands.connect_client();
 }
+
+/// Leaves the game server once the lobby server tells us the game is over.
+fn disconnect(mut commands: Commands) {
+    commands.disconnect_client();
+}
@@ -1,7 +1,4 @@
-use std::{
-    net::{IpAddr, Ipv6Addr, SocketAddr},
-    time::Duration,
-};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
 use bevy::prelude::*;
 use client::ClientPlugins;
@@ -12,6 +9,11 @@ use server::{NetConfig, ServerPlugins};
 #[derive(Resource)]
 pub struct GameServerToken(pub ConnectToken);
 
+/// Present while we are watching the game instead of playing in it.
+/// The game server keeps spectators behind the game on its own.
+#[derive(Resource)]
+pub struct Spectating;
+
 pub fn build_client_plugin() -> ClientPlugins {
     let auth = client::Authentication::None;
     let io = client::IoConfig {
@@ -0,0 +1,176 @@
+use bevy::prelude::*;
+use bevy_cosmic_edit::{
+    cosmic_text::{Attrs, AttrsOwned, BufferRef, Edit as _, FontSystem},
+    editor::CosmicEditor,
+    BufferRefExtras as _, CosmicEditBuffer, CosmicFontSystem,
+};
+use protocol::{InviteCode, LobbyAccess, LobbyId, MessageFromPlayer};
+
+use crate::ui::{build_password_edit, build_textedit, create_modal, CloseModal, OnClickExt};
+
+use super::SendMessage;
+
+pub fn access(app: &mut App) {
+    app.add_observer(on_lobby_access);
+}
+
+#[derive(Event)]
+pub struct LobbyAccessReceived(pub LobbyAccess);
+
+pub(super) fn get_text(
+    e: Entity,
+    eq: &Query<&CosmicEditor>,
+    bq: &Query<&CosmicEditBuffer>,
+) -> String {
+    match eq.get(e) {
+        Ok(x) => match x.editor.buffer_ref() {
+            BufferRef::Owned(buffer) => buffer.get_text(),
+            BufferRef::Borrowed(buffer) => buffer.get_text(),
+            BufferRef::Arc(buffer) => buffer.get_text(),
+        },
+        Err(_) => bq
+            .get(e)
+            .unwrap()
+            .get_text_spans(AttrsOwned::new(Attrs::new()))
+            .into_iter()
+            .map(|l| l.into_iter().map(|(t, _)| t).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+/// Asks for the password of a password protected lobby, then tries to join it.
+pub fn prompt_lobby_password(commands: &mut Commands, font_system: &mut FontSystem, id: LobbyId) {
+    create_modal(commands, "Lobby Password", true, |parent| {
+        let input = build_password_edit(parent, font_system);
+        parent.spawn((Button, Text::new("[Join]"))).on_click(
+            move |bq: Query<&CosmicEditBuffer>,
+                  eq: Query<&CosmicEditor>,
+                  send: Res<SendMessage>,
+                  mut commands: Commands| {
+                let password = get_text(input, &eq, &bq);
+                let _ = send.send(MessageFromPlayer::JoinLobby(id, Some(password)));
+                commands.queue(CloseModal);
+            },
+        );
+    });
+}
+
+/// Asks for an invite code, then tries to join the lobby it belongs to.
+pub fn prompt_invite_code(commands: &mut Commands, font_system: &mut FontSystem) {
+    create_modal(commands, "Join with Code", true, |parent| {
+        let input = build_textedit(parent, "", font_system);
+        parent.spawn((Button, Text::new("[Join]"))).on_click(
+            move |bq: Query<&CosmicEditBuffer>,
+                  eq: Query<&CosmicEditor>,
+                  send: Res<SendMessage>,
+                  mut commands: Commands| {
+                // Codes are shown in upper case, but accept them typed in any case
+                let code = get_text(input, &eq, &bq).trim().to_uppercase();
+                let _ = send.send(MessageFromPlayer::JoinLobbyWithCode(InviteCode(code)));
+                commands.queue(CloseModal);
+            },
+        );
+    });
+}
+
+/// (Re)opens the access menu whenever the server tells us the current access settings.
+fn on_lobby_access(
+    trigger: Trigger<LobbyAccessReceived>,
+    mut font_system: ResMut<CosmicFontSystem>,
+    mut commands: Commands,
+) {
+    let access = &trigger.event().0;
+
+    commands.queue(CloseModal);
+    create_modal(&mut commands, "Lobby Access", true, |parent| {
+        // Password
+        parent.spawn(Text::new(if access.password_protected {
+            "Password: set"
+        } else {
+            "Password: none"
+        }));
+        parent
+            .spawn(Node {
+                width: Val::Percent(100.0),
+                column_gap: Val::Px(10.0),
+                ..default()
+            })
+            .with_children(|parent| {
+                let input = build_password_edit(parent, &mut font_system.0);
+                parent.spawn((Button, Text::new("[Set Password]"))).observe(
+                    move |mut trigger: Trigger<Pointer<Click>>,
+                          bq: Query<&CosmicEditBuffer>,
+                          eq: Query<&CosmicEditor>,
+                          send: Res<SendMessage>| {
+                        trigger.propagate(false);
+                        let password = get_text(input, &eq, &bq);
+                        send.send_from(
+                            trigger.entity(),
+                            MessageFromPlayer::SetLobbyPassword(Some(password)),
+                        );
+                    },
+                );
+                if access.password_protected {
+                    parent
+                        .spawn((Button, Text::new("[Remove Password]")))
+                        .observe(
+                            |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                                trigger.propagate(false);
+                                send.send_from(
+                                    trigger.entity(),
+                                    MessageFromPlayer::SetLobbyPassword(None),
+                                );
+                            },
+                        );
+                }
+            });
+
+        // Invite codes
+        parent.spawn((
+            Text::new("Invite codes:"),
+            Node {
+                margin: UiRect::top(Val::Px(10.0)),
+                ..default()
+            },
+        ));
+        if access.invite_codes.is_empty() {
+            parent.spawn(Text::new("(none)"));
+        }
+        for code in &access.invite_codes {
+            parent
+                .spawn(Node {
+                    width: Val::Percent(100.0),
+                    column_gap: Val::Px(10.0),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent.spawn((
+                        Text::new(code.to_string()),
+                        Node {
+                            flex_grow: 1.0,
+                            ..default()
+                        },
+                    ));
+                    let code = code.clone();
+                    parent.spawn((Button, Text::new("[Revoke]"))).observe(
+                        move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                            trigger.propagate(false);
+                            send.send_from(
+                                trigger.entity(),
+                                MessageFromPlayer::RevokeInviteCode(code.clone()),
+                            );
+                        },
+                    );
+                });
+        }
+        parent
+            .spawn((Button, Text::new("[New Invite Code]")))
+            .observe(
+                |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                    trigger.propagate(false);
+                    send.send_from(trigger.entity(), MessageFromPlayer::CreateInviteCode);
+                },
+            );
+    });
+}
@@ -0,0 +1,160 @@
+use bevy::prelude::*;
+use bevy_cosmic_edit::{cosmic_text::FontSystem, editor::CosmicEditor, CosmicEditBuffer};
+use protocol::{
+    LobbyFilter, LobbyListCursor, LobbyListQuery, LobbySort, LobbyStateKind, MessageFromPlayer,
+};
+
+use crate::{
+    ui::{
+        build_textedit,
+        checkbox::{build_checkbox, Checkbox},
+        OnClickExt,
+    },
+    Maps,
+};
+
+use super::{access::get_text, SendMessage};
+
+pub fn browser(app: &mut App) {
+    app.init_resource::<LobbyBrowser>();
+}
+
+/// The filters and sort order of the lobby browser, kept while we are in a lobby.
+#[derive(Resource, Default)]
+pub struct LobbyBrowser {
+    filter: LobbyFilter,
+    sort: LobbySort,
+    /// Where the next page of lobbies starts, if there is one.
+    pub next: Option<LobbyListCursor>,
+}
+
+impl LobbyBrowser {
+    /// Asks for the first page of lobbies, or the page after `cursor`.
+    pub fn request(&self, send: &SendMessage, cursor: Option<LobbyListCursor>) {
+        let _ = send.send(MessageFromPlayer::GetLobbyList(LobbyListQuery {
+            filter: self.filter.clone(),
+            sort: self.sort,
+            cursor,
+        }));
+    }
+}
+
+#[derive(Component)]
+struct MapChoice(Option<String>);
+
+#[derive(Component)]
+struct SortChoice(LobbySort);
+
+fn map_text(map: Option<&str>) -> String {
+    format!("[Map: {}]", map.unwrap_or("Any"))
+}
+
+fn sort_text(sort: LobbySort) -> String {
+    format!("[Sort: {sort}]")
+}
+
+pub fn state_text(state: LobbyStateKind) -> &'static str {
+    match state {
+        LobbyStateKind::Normal => "Waiting",
+        LobbyStateKind::ReadyCheck => "Ready check",
+        LobbyStateKind::ChampSelect => "Champ select",
+        LobbyStateKind::InGame => "In game",
+    }
+}
+
+/// The filter bar above the lobby list; [Apply] fetches the first page again.
+pub fn build_lobby_filters(
+    parent: &mut ChildBuilder,
+    browser: &LobbyBrowser,
+    font_system: &mut FontSystem,
+) {
+    parent
+        .spawn(Node {
+            flex_direction: FlexDirection::Row,
+            column_gap: Val::Px(10.0),
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn(Text::new("Name:"));
+            let name = build_textedit(
+                parent,
+                browser.filter.name_contains.as_deref().unwrap_or(""),
+                font_system,
+            );
+
+            let map = browser.filter.map.clone();
+            let map = parent
+                .spawn((Button, Text::new(map_text(map.as_deref())), MapChoice(map)))
+                .observe(
+                    |mut trigger: Trigger<Pointer<Click>>,
+                     maps: Res<Maps>,
+                     mut q: Query<(&mut MapChoice, &mut Text)>| {
+                        trigger.propagate(false);
+                        let (mut choice, mut text) = q.get_mut(trigger.entity()).unwrap();
+                        // Any map, then every map in turn
+                        let mut names = maps.0.iter().map(|m| m.name.clone());
+                        choice.0 = match &choice.0 {
+                            None => names.next(),
+                            Some(current) => names.skip_while(|n| n != current).nth(1),
+                        };
+                        text.0 = map_text(choice.0.as_deref());
+                    },
+                )
+                .id();
+
+            parent.spawn(Text::new("Free slot:"));
+            let has_free_slot = build_checkbox(parent, browser.filter.has_free_slot);
+            parent.spawn(Text::new("No password:"));
+            let no_password = build_checkbox(parent, browser.filter.no_password);
+
+            let sort = parent
+                .spawn((
+                    Button,
+                    Text::new(sort_text(browser.sort)),
+                    SortChoice(browser.sort),
+                ))
+                .observe(
+                    |mut trigger: Trigger<Pointer<Click>>,
+                     mut q: Query<(&mut SortChoice, &mut Text)>| {
+                        trigger.propagate(false);
+                        let (mut choice, mut text) = q.get_mut(trigger.entity()).unwrap();
+                        let index = LobbySort::ALL.iter().position(|s| *s == choice.0).unwrap();
+                        choice.0 = LobbySort::ALL[(index + 1) % LobbySort::ALL.len()];
+                        text.0 = sort_text(choice.0);
+                    },
+                )
+                .id();
+
+            parent.spawn((Button, Text::new("[Apply]"))).on_click(
+                move |bq: Query<&CosmicEditBuffer>,
+                      eq: Query<&CosmicEditor>,
+                      maps: Query<&MapChoice>,
+                      sorts: Query<&SortChoice>,
+                      checkboxes: Query<&Checkbox>,
+                      mut browser: ResMut<LobbyBrowser>,
+                      send: Res<SendMessage>| {
+                    let name = get_text(name, &eq, &bq);
+                    browser.filter = LobbyFilter {
+                        map: maps.get(map).unwrap().0.clone(),
+                        has_free_slot: checkboxes.get(has_free_slot).unwrap().checked,
+                        no_password: checkboxes.get(no_password).unwrap().checked,
+                        name_contains: (!name.is_empty()).then_some(name),
+                    };
+                    browser.sort = sorts.get(sort).unwrap().0;
+                    browser.request(&send, None);
+                },
+            );
+        });
+}
+
+#[derive(Component)]
+pub struct LoadMoreButton;
+
+/// Ends a page of the lobby list when there are more pages; replaced by the next page.
+pub fn build_load_more(parent: &mut ChildBuilder) {
+    parent
+        .spawn((Button, Text::new("[Load More]"), LoadMoreButton))
+        .on_click(|browser: Res<LobbyBrowser>, send: Res<SendMessage>| {
+            browser.request(&send, browser.next.clone());
+        });
+}
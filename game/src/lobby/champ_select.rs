@@ -1,15 +1,68 @@
+use std::time::Instant;
+
 use bevy::prelude::*;
-use lobby_server::{LobbyState, MessageFromPlayer, Team};
+use engine::champion::ChampionDef;
+use protocol::{DraftTurnKind, LobbyState, MessageFromPlayer, Team};
+
+use crate::ui::ScrollEvent;
+
+use super::{CurrentLobby, LobbyBuildingContext, SendMessage};
+
+pub fn champ_select(app: &mut App) {
+    app.add_systems(Update, update_pick_countdown);
+}
 
-use crate::ui::{OnClickExt, ScrollEvent};
+#[derive(Component)]
+struct PickCountdown;
 
-use super::{LobbyBuildingContext, SendMessage};
+/// The champion's display name, falling back to its id for unknown champions.
+fn champion_name<'a>(ctx: &'a LobbyBuildingContext, id: &'a str) -> &'a str {
+    ctx.champions.get(id).map_or(id, |c| c.name.as_str())
+}
+
+fn stats_text(champion: &ChampionDef) -> String {
+    let stats = &champion.stats;
+    format!(
+        "{}: Health {} | Mana {} | AD {} | AS {:.2} | Range {} | Armor {} | MR {} | MS {}",
+        champion.name,
+        stats.health,
+        stats.mana,
+        stats.attack_damage,
+        stats.attack_speed,
+        stats.attack_range,
+        stats.armor,
+        stats.magic_resist,
+        stats.move_speed
+    )
+}
 
 pub fn build_champ_select(ctx: &LobbyBuildingContext, parent: &mut ChildBuilder) {
     let LobbyState::ChampSelect(state) = &ctx.lobby.lobby_state else {
         unreachable!()
     };
 
+    // Whose turn it is in a draft
+    if let Some(turn) = state.draft.as_ref().and_then(|d| d.current_turn()) {
+        let action = match turn.kind {
+            DraftTurnKind::Ban => "ban",
+            DraftTurnKind::Pick => "pick",
+        };
+        let text = match turn.player == ctx.my_id {
+            true => format!("Your turn to {action}!"),
+            false => {
+                let name = &ctx.player_cache.players.get(&turn.player).unwrap().name;
+                format!("{name} ({}) is about to {action}", turn.team)
+            }
+        };
+        parent
+            .spawn(Node {
+                width: Val::Percent(100.0),
+                justify_content: JustifyContent::Center,
+                ..default()
+            })
+            .with_child(Text::new(text));
+    }
+
     // We need three columns; Leftmost and rightmost for teams, and middle for champions
     // Left column contains teams 2n, right column contains 2n + 1, for n € N*
 
@@ -74,6 +127,28 @@ pub fn build_champ_select(ctx: &LobbyBuildingContext, parent: &mut ChildBuilder)
             observer.watch_entity(right_sync);
             parent.spawn(observer);
         });
+    // Stats of the champion we have selected
+    let draft_hover = state
+        .draft
+        .as_ref()
+        .filter(|d| d.current_turn().is_some_and(|t| t.player == ctx.my_id))
+        .and_then(|d| d.hovered.as_ref());
+    let selected = state
+        .selected_champs
+        .get(&ctx.my_id)
+        .and_then(|s| s.as_ref())
+        .map(|s| &s.champion)
+        .or(draft_hover);
+    if let Some(champion) = selected.and_then(|id| ctx.champions.get(id)) {
+        parent
+            .spawn(Node {
+                width: Val::Percent(100.0),
+                justify_content: JustifyContent::Center,
+                ..default()
+            })
+            .with_child(Text::new(stats_text(champion)));
+    }
+
     // Bottom button bar
     parent
         .spawn(Node {
@@ -84,11 +159,28 @@ pub fn build_champ_select(ctx: &LobbyBuildingContext, parent: &mut ChildBuilder)
             ..default()
         })
         .with_children(|parent| {
-            parent
-                .spawn((Button, Text::new("[Lock]")))
-                .on_click(|send: Res<SendMessage>| {
-                    let _ = send.send(MessageFromPlayer::LockChampSelection);
-                });
+            if state.time_left.is_some() {
+                parent.spawn((Text::new(""), PickCountdown));
+            }
+            if ctx.i_am_spectator() {
+                parent.spawn(Text::new("Spectating"));
+                return;
+            }
+            let my_ban_turn = state
+                .draft
+                .as_ref()
+                .and_then(|d| d.current_turn())
+                .is_some_and(|t| t.player == ctx.my_id && t.kind == DraftTurnKind::Ban);
+            let label = match my_ban_turn {
+                true => "[Ban]",
+                false => "[Lock]",
+            };
+            parent.spawn((Button, Text::new(label))).observe(
+                |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                    trigger.propagate(false);
+                    send.send_from(trigger.entity(), MessageFromPlayer::LockChampSelection);
+                },
+            );
         });
 }
 
@@ -117,6 +209,22 @@ pub fn build_team_list(ctx: &LobbyBuildingContext, offset: usize, parent: &mut C
             .with_children(|parent| {
                 // Team title
                 parent.spawn(Text::new(team.to_string()));
+                // Bans made by this team in a draft
+                if let Some(draft) = &state.draft {
+                    let bans = draft.bans.get(&team).map_or(&[][..], Vec::as_slice);
+                    let bans = match bans.is_empty() {
+                        true => "-".to_string(),
+                        false => bans
+                            .iter()
+                            .map(|id| champion_name(ctx, id))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    };
+                    parent.spawn((
+                        Text::new(format!("Bans: {bans}")),
+                        TextColor(Color::srgb(1.0, 0.4, 0.4)),
+                    ));
+                }
                 // List of players and their chosen champion
                 for player in ctx.lobby.players.get(&team).unwrap() {
                     // Player entry container
@@ -131,9 +239,26 @@ pub fn build_team_list(ctx: &LobbyBuildingContext, offset: usize, parent: &mut C
                             parent.spawn(Text::new(name));
                             // If champ has been chosen, the chosen champ
                             if let Some(champ) = state.selected_champs.get(player).unwrap() {
+                                let name = champion_name(ctx, &champ.champion);
                                 let text = match champ.locked {
-                                    true => format!("*{}*", champ.champion),
-                                    false => champ.champion.clone(),
+                                    true => format!("*{name}*"),
+                                    false => name.to_string(),
+                                };
+                                parent.spawn(Text::new(text));
+                            } else if let Some(draft) = &state.draft
+                                && let Some(turn) = draft.current_turn()
+                                && turn.player == *player
+                            {
+                                // The champion being considered for the current turn
+                                let hovered =
+                                    draft.hovered.as_deref().map(|id| champion_name(ctx, id));
+                                let text = match (turn.kind, hovered) {
+                                    (DraftTurnKind::Ban, Some(champ)) => {
+                                        format!("banning {champ}...")
+                                    }
+                                    (DraftTurnKind::Ban, None) => "banning...".into(),
+                                    (DraftTurnKind::Pick, Some(champ)) => format!("{champ}..."),
+                                    (DraftTurnKind::Pick, None) => "picking...".into(),
                                 };
                                 parent.spawn(Text::new(text));
                             }
@@ -150,10 +275,62 @@ pub fn build_champ_list(ctx: &LobbyBuildingContext, parent: &mut ChildBuilder) {
 
     for champ in &state.available_champs {
         let champ_clone = champ.clone();
+        // Banned and picked champions can't be chosen in a draft
+        let color = match state.champion_taken(champ) {
+            true => Color::srgb(0.4, 0.4, 0.4),
+            false => Color::WHITE,
+        };
+        let def = ctx.champions.get(champ);
         parent
-            .spawn((Button, Text::new(format!("[{champ}]"))))
-            .on_click(move |send: Res<SendMessage>| {
-                let _ = send.send(MessageFromPlayer::SelectChampion(champ_clone.clone()));
-            });
+            .spawn((
+                Button,
+                Node {
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    ..default()
+                },
+            ))
+            .with_children(|parent| {
+                if let Some(def) = def {
+                    parent.spawn((
+                        ImageNode::new(ctx.asset_server.load(&def.portrait)).with_color(color),
+                        Node {
+                            width: Val::Px(64.0),
+                            height: Val::Px(64.0),
+                            ..default()
+                        },
+                    ));
+                }
+                parent.spawn((Text::new(champion_name(ctx, champ)), TextColor(color)));
+            })
+            .observe(
+                move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                    trigger.propagate(false);
+                    send.send_from(
+                        trigger.entity(),
+                        MessageFromPlayer::SelectChampion(champ_clone.clone()),
+                    );
+                },
+            );
+    }
+}
+
+fn update_pick_countdown(
+    lobby: Option<Res<CurrentLobby>>,
+    mut q: Query<&mut Text, With<PickCountdown>>,
+) {
+    let Some(LobbyState::ChampSelect(state)) = lobby
+        .as_ref()
+        .and_then(|l| l.info.as_ref())
+        .map(|l| &l.lobby_state)
+    else {
+        return;
+    };
+    let Some(deadline) = state.deadline else {
+        return;
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    for mut text in &mut q {
+        text.0 = format!("{}s left ", remaining.as_secs_f32().ceil());
     }
 }
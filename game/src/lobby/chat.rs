@@ -0,0 +1,333 @@
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_cosmic_edit::{
+    cosmic_text::{Attrs, AttrsOwned, BufferRef, Edit as _, FontSystem},
+    editor::CosmicEditor,
+    BufferRefExtras as _, CosmicEditBuffer, CosmicFontSystem, FocusedWidget,
+};
+use protocol::{ChatChannel, MessageFromPlayer, PlayerId, PlayerInfo};
+
+use crate::{login::MyPlayerId, ui::build_textedit};
+
+use super::{scroll, SendMessage};
+
+/// How many chat lines are kept.
+const SCROLLBACK: usize = 200;
+
+pub fn chat(app: &mut App) {
+    app.init_resource::<ChatLog>();
+    app.init_resource::<ChatTarget>();
+    app.add_observer(on_chat_message);
+    app.add_systems(Update, send_chat_on_enter);
+}
+
+/// Every chat message received, kept outside of the UI so it survives the UI being rebuilt.
+#[derive(Resource, Default)]
+pub struct ChatLog {
+    lines: VecDeque<ChatLine>,
+    /// Names of the players seen in chat, for showing who whispers were sent to.
+    names: HashMap<PlayerId, String>,
+}
+
+impl ChatLog {
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+}
+
+struct ChatLine {
+    from: PlayerId,
+    channel: ChatChannel,
+    text: String,
+}
+
+/// The channel chat messages are sent to.
+#[derive(Resource)]
+pub struct ChatTarget(ChatChannel);
+
+impl Default for ChatTarget {
+    fn default() -> Self {
+        Self(ChatChannel::Lobby)
+    }
+}
+
+#[derive(Event)]
+pub struct ChatMessageReceived {
+    pub from: PlayerInfo,
+    pub channel: ChatChannel,
+    pub text: String,
+}
+
+#[derive(Component)]
+struct ChatLogAnchor;
+
+/// Holds the chat text input as its only child.
+#[derive(Component)]
+struct ChatInputAnchor;
+
+#[derive(Component)]
+struct ChatChannelButton;
+
+fn channel_label(channel: ChatChannel, names: &HashMap<PlayerId, String>) -> String {
+    match channel {
+        ChatChannel::Lobby => "[Lobby]".into(),
+        ChatChannel::Team => "[Team]".into(),
+        ChatChannel::Whisper(to) => format!("[To {}]", player_name(to, names)),
+    }
+}
+
+fn player_name(id: PlayerId, names: &HashMap<PlayerId, String>) -> &str {
+    names.get(&id).map(String::as_str).unwrap_or("?")
+}
+
+fn line_text(line: &ChatLine, names: &HashMap<PlayerId, String>, my_id: PlayerId) -> String {
+    let from = player_name(line.from, names);
+    match line.channel {
+        ChatChannel::Lobby => format!("[Lobby] {from}: {}", line.text),
+        ChatChannel::Team => format!("[Team] {from}: {}", line.text),
+        ChatChannel::Whisper(to) if line.from == my_id => {
+            format!("[To {}] {}", player_name(to, names), line.text)
+        }
+        ChatChannel::Whisper(_) => format!("[From {from}] {}", line.text),
+    }
+}
+
+fn line_color(channel: ChatChannel) -> Color {
+    match channel {
+        ChatChannel::Lobby => Color::WHITE,
+        ChatChannel::Team => Color::srgb(0.5, 0.7, 1.0),
+        ChatChannel::Whisper(_) => Color::srgb(1.0, 0.5, 0.9),
+    }
+}
+
+fn build_chat_line(
+    parent: &mut ChildBuilder,
+    line: &ChatLine,
+    names: &HashMap<PlayerId, String>,
+    my_id: PlayerId,
+) {
+    let from = line.from;
+    let mut entity = parent.spawn((
+        Text::new(line_text(line, names, my_id)),
+        TextColor(line_color(line.channel)),
+    ));
+    // Clicking someone else's message starts whispering them
+    if from != my_id {
+        entity.observe(
+            move |mut trigger: Trigger<Pointer<Click>>,
+                  mut target: ResMut<ChatTarget>,
+                  log: Res<ChatLog>,
+                  mut button: Query<&mut Text, With<ChatChannelButton>>| {
+                trigger.propagate(false);
+                target.0 = ChatChannel::Whisper(from);
+                for mut text in &mut button {
+                    text.0 = channel_label(target.0, &log.names);
+                }
+            },
+        );
+    }
+}
+
+pub fn build_chat_panel(
+    parent: &mut ChildBuilder,
+    log: &ChatLog,
+    target: &ChatTarget,
+    my_id: PlayerId,
+    font_system: &mut FontSystem,
+) {
+    parent
+        .spawn(Node {
+            width: Val::Percent(100.0),
+            height: Val::Percent(25.0),
+            flex_direction: FlexDirection::Column,
+            border: UiRect::top(Val::Px(1.0)),
+            ..default()
+        })
+        .with_children(|parent| {
+            parent
+                .spawn((
+                    Node {
+                        flex_grow: 1.0,
+                        flex_direction: FlexDirection::Column,
+                        overflow: Overflow::scroll_y(),
+                        ..default()
+                    },
+                    // Start scrolled to the newest message
+                    ScrollPosition {
+                        offset_y: f32::MAX,
+                        ..default()
+                    },
+                    ChatLogAnchor,
+                ))
+                .observe(scroll)
+                .with_children(|parent| {
+                    for line in &log.lines {
+                        build_chat_line(parent, line, &log.names, my_id);
+                    }
+                });
+
+            parent
+                .spawn(Node {
+                    width: Val::Percent(100.0),
+                    column_gap: Val::Px(10.0),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    // Switches between the lobby and team channels
+                    parent
+                        .spawn((
+                            Button,
+                            Text::new(channel_label(target.0, &log.names)),
+                            ChatChannelButton,
+                        ))
+                        .observe(
+                            |mut trigger: Trigger<Pointer<Click>>,
+                             mut target: ResMut<ChatTarget>,
+                             log: Res<ChatLog>,
+                             mut text: Query<&mut Text>| {
+                                trigger.propagate(false);
+                                target.0 = match target.0 {
+                                    ChatChannel::Lobby => ChatChannel::Team,
+                                    ChatChannel::Team | ChatChannel::Whisper(_) => {
+                                        ChatChannel::Lobby
+                                    }
+                                };
+                                if let Ok(mut text) = text.get_mut(trigger.entity()) {
+                                    text.0 = channel_label(target.0, &log.names);
+                                }
+                            },
+                        );
+                    parent
+                        .spawn((
+                            Node {
+                                flex_grow: 1.0,
+                                ..default()
+                            },
+                            ChatInputAnchor,
+                        ))
+                        .with_children(|parent| {
+                            build_textedit(parent, "", font_system);
+                        });
+                    parent.spawn((Button, Text::new("[Send]"))).observe(
+                        |mut trigger: Trigger<Pointer<Click>>, mut commands: Commands| {
+                            trigger.propagate(false);
+                            commands.run_system_cached(send_chat);
+                        },
+                    );
+                });
+        });
+}
+
+fn send_chat_on_enter(
+    keys: Res<ButtonInput<KeyCode>>,
+    focused: Res<FocusedWidget>,
+    parents: Query<&Parent>,
+    input_anchor: Query<(), With<ChatInputAnchor>>,
+    mut commands: Commands,
+) {
+    let chat_focused = focused
+        .0
+        .and_then(|e| parents.get(e).ok())
+        .is_some_and(|parent| input_anchor.contains(parent.get()));
+    if keys.just_pressed(KeyCode::Enter) && chat_focused {
+        commands.run_system_cached(send_chat);
+    }
+}
+
+fn send_chat(
+    input_anchor: Option<Single<(Entity, &Children), With<ChatInputAnchor>>>,
+    bq: Query<&CosmicEditBuffer>,
+    eq: Query<&CosmicEditor>,
+    target: Res<ChatTarget>,
+    send: Res<SendMessage>,
+    mut font_system: ResMut<CosmicFontSystem>,
+    mut commands: Commands,
+) {
+    let Some(input_anchor) = input_anchor else {
+        return;
+    };
+    let (input_anchor, children) = *input_anchor;
+    let Some(&input) = children.first() else {
+        return;
+    };
+    let text = match eq.get(input) {
+        Ok(x) => match x.editor.buffer_ref() {
+            BufferRef::Owned(buffer) => buffer.get_text(),
+            BufferRef::Borrowed(buffer) => buffer.get_text(),
+            BufferRef::Arc(buffer) => buffer.get_text(),
+        },
+        Err(_) => bq
+            .get(input)
+            .unwrap()
+            .get_text_spans(AttrsOwned::new(Attrs::new()))
+            .into_iter()
+            .map(|l| l.into_iter().map(|(t, _)| t).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+    if text.trim().is_empty() {
+        return;
+    }
+
+    send.send_from(
+        input_anchor,
+        MessageFromPlayer::SendChat {
+            channel: target.0,
+            text,
+        },
+    );
+
+    // Replace the input with an empty one, keeping it focused
+    let mut new_input = Entity::PLACEHOLDER;
+    commands
+        .entity(input_anchor)
+        .despawn_descendants()
+        .with_children(|parent| {
+            new_input = build_textedit(parent, "", &mut font_system.0);
+        });
+    commands.insert_resource(FocusedWidget(Some(new_input)));
+}
+
+fn on_chat_message(
+    trigger: Trigger<ChatMessageReceived>,
+    mut log: ResMut<ChatLog>,
+    my_id: Res<MyPlayerId>,
+    mut anchor: Query<(Entity, &mut ScrollPosition), With<ChatLogAnchor>>,
+    mut commands: Commands,
+) {
+    let ChatMessageReceived {
+        from,
+        channel,
+        text,
+    } = trigger.event();
+
+    log.names.insert(from.id, from.name.clone());
+    let line = ChatLine {
+        from: from.id,
+        channel: *channel,
+        text: text.clone(),
+    };
+
+    if let Ok((anchor, mut scroll)) = anchor.get_single_mut() {
+        commands.entity(anchor).with_children(|parent| {
+            build_chat_line(parent, &line, &log.names, my_id.0);
+        });
+        scroll.offset_y = f32::MAX;
+    }
+
+    log.lines.push_back(line);
+    if log.lines.len() > SCROLLBACK {
+        log.lines.pop_front();
+        if let Ok((anchor, _)) = anchor.get_single() {
+            commands.queue(move |world: &mut World| {
+                let first = world
+                    .get::<Children>(anchor)
+                    .and_then(|children| children.first().copied());
+                if let Some(first) = first {
+                    world.entity_mut(first).despawn_recursive();
+                }
+            });
+        }
+    }
+}
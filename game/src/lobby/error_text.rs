@@ -0,0 +1,113 @@
+use protocol::{DisbandReason, LobbyError, LobbyStateKind};
+
+fn state_name(state: LobbyStateKind) -> &'static str {
+    match state {
+        LobbyStateKind::Normal => "the lobby",
+        LobbyStateKind::ReadyCheck => "a ready check",
+        LobbyStateKind::ChampSelect => "champ select",
+        LobbyStateKind::InGame => "a game",
+    }
+}
+
+/// The text shown to the player when the lobby server refuses a request.
+pub fn error_text(error: &LobbyError) -> String {
+    match error {
+        LobbyError::AlreadyInLobby => "You are already in a lobby.".into(),
+        LobbyError::NotInLobby => "You are not in a lobby.".into(),
+        LobbyError::LobbyNotFound => "That lobby does not exist.".into(),
+        LobbyError::PlayerNotFound => "That player does not exist.".into(),
+        LobbyError::LobbyClosed => "The lobby is closed.".into(),
+        LobbyError::LobbyFull => "The lobby is full.".into(),
+        LobbyError::NotLeader => "Only the lobby leader can do that.".into(),
+        LobbyError::InvalidState { expected, actual } => format!(
+            "That can only be done in {}, but the lobby is in {}.",
+            state_name(*expected),
+            state_name(*actual)
+        ),
+        LobbyError::TeamSwitchingDisabled => "Team switching is disabled in this lobby.".into(),
+        LobbyError::CannotMoveOtherPlayer => "You cannot switch the team of another player.".into(),
+        LobbyError::TeamNotFound(team) => format!("{team} does not exist."),
+        LobbyError::TeamFull(team) => format!("{team} is full."),
+        LobbyError::EmptyLobbyName => "Lobby name cannot be empty.".into(),
+        LobbyError::UnknownMap(map) => format!("No map {map:?} exists."),
+        LobbyError::NoTeams => "There must be at least 1 team.".into(),
+        LobbyError::UnsupportedTeamCount { min, max } => {
+            format!("That map supports between {min} and {max} teams.")
+        }
+        LobbyError::UnknownChampion => "That champion does not exist.".into(),
+        LobbyError::SelectionLocked => "You cannot change a locked selection.".into(),
+        LobbyError::NoChampionSelected => "Select a champion before locking in.".into(),
+        LobbyError::NotYourTurn => "It is not your turn.".into(),
+        LobbyError::ChampionUnavailable => {
+            "That champion has already been banned or picked.".into()
+        }
+        LobbyError::GameStartsAutomatically => {
+            "The game starts once every champion is locked.".into()
+        }
+        LobbyError::InvalidName => {
+            "Names must be between 1 and 24 characters, and cannot contain control characters."
+                .into()
+        }
+        LobbyError::NameTaken => "That name is already taken.".into(),
+        LobbyError::PasswordTooShort(len) => {
+            format!("Passwords must be at least {len} characters long.")
+        }
+        LobbyError::InvalidCredentials => "Wrong name or password.".into(),
+        LobbyError::TooManyLoginAttempts => {
+            "Too many failed logins for that name; try again in a few minutes.".into()
+        }
+        LobbyError::AlreadyLoggedIn => "That account is already logged in.".into(),
+        LobbyError::EmptyChatMessage => "Chat messages cannot be empty.".into(),
+        LobbyError::ChatMessageTooLong(len) => {
+            format!("Chat messages can be at most {len} characters long.")
+        }
+        LobbyError::ChatRateLimited => "You are sending messages too quickly.".into(),
+        LobbyError::NotOnATeam => "Only players on a team can use team chat.".into(),
+        LobbyError::InvalidResumeToken => "Your session has expired; please log in again.".into(),
+        LobbyError::PasswordRequired => "That lobby requires a password.".into(),
+        LobbyError::WrongPassword => "Wrong lobby password.".into(),
+        LobbyError::InvalidInviteCode => "That invite code is not valid.".into(),
+        LobbyError::TooManyInviteCodes(max) => {
+            format!("A lobby can have at most {max} invite codes.")
+        }
+        LobbyError::AlreadyInQueue => "You are already waiting for a match.".into(),
+        LobbyError::NotInQueue => "You are not waiting for a match.".into(),
+        LobbyError::AlreadyInParty => "That player is already in a party.".into(),
+        LobbyError::NotInParty => "You are not in a party.".into(),
+        LobbyError::PartyNotFound => "That party no longer exists.".into(),
+        LobbyError::NotPartyLeader => "Only the party leader can do that.".into(),
+        LobbyError::PartyFull(max) => format!("A party can have at most {max} members."),
+        LobbyError::PartyBusy => {
+            "Someone in the party is in a lobby or waiting for a match.".into()
+        }
+        LobbyError::PartyTooLarge(size) => {
+            format!("The party is too large for teams of {size}.")
+        }
+        LobbyError::SpectatorsFull => "There are no free spectator slots.".into(),
+        LobbyError::Spectating => "Spectators can't pick champions.".into(),
+        LobbyError::SpectatorSlotsInUse(count) => {
+            format!("The lobby has {count} spectators; there must be a slot for each of them.")
+        }
+        LobbyError::AlreadyFriends => "You are already friends.".into(),
+        LobbyError::NotFriends => "You are not friends with that player.".into(),
+        LobbyError::FriendRequestNotFound => "That friend request no longer exists.".into(),
+        LobbyError::FriendNotInLobby => "Your friend is not in a lobby you can join.".into(),
+        LobbyError::AccountStoreFailed => {
+            "The server could not access its account database;\nplease try again later.".into()
+        }
+    }
+}
+
+/// The text shown to the player when the lobby server closes their lobby.
+pub fn disband_text(reason: &DisbandReason) -> String {
+    match reason {
+        DisbandReason::NotEnoughChampions => {
+            "There are not enough champions for every player to pick one.".into()
+        }
+        DisbandReason::NoGameServerAvailable => {
+            "No game server is available right now;\nplease try again later.".into()
+        }
+        DisbandReason::GameServerFailed => "The game server failed;\nthe game was ended.".into(),
+        DisbandReason::MatchRefused => "The game server could not start the game.".into(),
+    }
+}
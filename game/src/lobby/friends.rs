@@ -0,0 +1,210 @@
+use bevy::prelude::*;
+use bevy_cosmic_edit::{
+    cosmic_text::FontSystem, editor::CosmicEditor, CosmicEditBuffer, CosmicFontSystem,
+};
+use protocol::{Friend, MessageFromPlayer, PlayerId, PlayerInfo, Presence};
+
+use crate::ui::{build_textedit, create_modal, CloseModal, OnClickExt};
+
+use super::{access::get_text, SendMessage};
+
+pub fn friends(app: &mut App) {
+    app.init_resource::<Friends>();
+    app.add_observer(on_friends_changed);
+}
+
+/// Our friends and the friend requests we haven't answered, as last told by the server.
+#[derive(Resource, Default)]
+pub struct Friends {
+    pub friends: Vec<Friend>,
+    pub requests: Vec<PlayerInfo>,
+}
+
+#[derive(Event)]
+pub enum FriendsChanged {
+    List {
+        friends: Vec<Friend>,
+        requests: Vec<PlayerInfo>,
+    },
+    Updated(Friend),
+    Removed(PlayerId),
+    RequestReceived(PlayerInfo),
+}
+
+#[derive(Component)]
+struct FriendsPanelAnchor;
+
+/// The sidebar listing our friends, rebuilt whenever they change.
+pub fn build_friends_panel(parent: &mut ChildBuilder, friends: &Friends) {
+    parent
+        .spawn((
+            Node {
+                flex_direction: FlexDirection::Column,
+                min_width: Val::Px(250.0),
+                overflow: Overflow::scroll_y(),
+                ..default()
+            },
+            FriendsPanelAnchor,
+        ))
+        .with_children(|parent| build_friend_list(parent, friends));
+}
+
+fn presence_text(presence: Presence) -> &'static str {
+    match presence {
+        Presence::Offline => "Offline",
+        Presence::Online => "Online",
+        Presence::InLobby(_) => "In lobby",
+        Presence::InChampSelect => "In champ select",
+        Presence::InGame => "In game",
+    }
+}
+
+fn on_friends_changed(
+    trigger: Trigger<FriendsChanged>,
+    anchor: Option<Single<Entity, With<FriendsPanelAnchor>>>,
+    mut friends: ResMut<Friends>,
+    mut commands: Commands,
+) {
+    match trigger.event() {
+        FriendsChanged::List {
+            friends: list,
+            requests,
+        } => {
+            friends.friends = list.clone();
+            friends.requests = requests.clone();
+        }
+        FriendsChanged::Updated(friend) => {
+            friends.requests.retain(|p| p.id != friend.player.id);
+            match friends
+                .friends
+                .iter_mut()
+                .find(|f| f.player.id == friend.player.id)
+            {
+                Some(existing) => *existing = friend.clone(),
+                None => friends.friends.push(friend.clone()),
+            }
+        }
+        FriendsChanged::Removed(id) => {
+            friends.friends.retain(|f| f.player.id != *id);
+            friends.requests.retain(|p| p.id != *id);
+        }
+        FriendsChanged::RequestReceived(player) => {
+            if !friends.requests.iter().any(|p| p.id == player.id) {
+                friends.requests.push(player.clone());
+            }
+        }
+    }
+    // Online friends first
+    friends.friends.sort_by(|a, b| {
+        (a.presence == Presence::Offline)
+            .cmp(&(b.presence == Presence::Offline))
+            .then_with(|| a.player.name.cmp(&b.player.name))
+    });
+
+    let Some(anchor) = anchor else {
+        return;
+    };
+    commands
+        .entity(*anchor)
+        .despawn_descendants()
+        .with_children(|parent| build_friend_list(parent, &friends));
+}
+
+fn build_friend_list(parent: &mut ChildBuilder, friends: &Friends) {
+    parent.spawn(Text::new("Friends"));
+    parent.spawn((Button, Text::new("[Add Friend]"))).on_click(
+        |mut font_system: ResMut<CosmicFontSystem>, mut commands: Commands| {
+            prompt_friend_name(&mut commands, &mut font_system.0);
+        },
+    );
+
+    for request in &friends.requests {
+        let id = request.id;
+        parent
+            .spawn(Node {
+                flex_direction: FlexDirection::Row,
+                column_gap: Val::Px(10.0),
+                ..default()
+            })
+            .with_children(|parent| {
+                parent.spawn(Text::new(format!("{} wants to be friends", request.name)));
+                parent.spawn((Button, Text::new("[Accept]"))).observe(
+                    move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                        trigger.propagate(false);
+                        send.send_from(
+                            trigger.entity(),
+                            MessageFromPlayer::AcceptFriendRequest(id),
+                        );
+                    },
+                );
+                parent.spawn((Button, Text::new("[Decline]"))).observe(
+                    move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                        trigger.propagate(false);
+                        send.send_from(trigger.entity(), MessageFromPlayer::RemoveFriend(id));
+                    },
+                );
+            });
+    }
+
+    if friends.friends.is_empty() {
+        parent.spawn(Text::new("No friends yet."));
+    }
+    for friend in &friends.friends {
+        let id = friend.player.id;
+        parent
+            .spawn(Node {
+                flex_direction: FlexDirection::Row,
+                column_gap: Val::Px(10.0),
+                ..default()
+            })
+            .with_children(|parent| {
+                parent.spawn((
+                    Node {
+                        flex_grow: 1.0,
+                        ..default()
+                    },
+                    Text::new(format!(
+                        "{} - {}",
+                        friend.player.name,
+                        presence_text(friend.presence)
+                    )),
+                ));
+                if let Presence::InLobby(_) = friend.presence {
+                    parent.spawn((Button, Text::new("[Join]"))).observe(
+                        move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                            trigger.propagate(false);
+                            send.send_from(
+                                trigger.entity(),
+                                MessageFromPlayer::JoinFriendLobby(id),
+                            );
+                        },
+                    );
+                }
+                parent.spawn((Button, Text::new("[Remove]"))).observe(
+                    move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                        trigger.propagate(false);
+                        send.send_from(trigger.entity(), MessageFromPlayer::RemoveFriend(id));
+                    },
+                );
+            });
+    }
+}
+
+/// Asks for the name of a player, then sends them a friend request.
+fn prompt_friend_name(commands: &mut Commands, font_system: &mut FontSystem) {
+    create_modal(commands, "Add Friend", true, |parent| {
+        let input = build_textedit(parent, "", font_system);
+        parent
+            .spawn((Button, Text::new("[Send Request]")))
+            .on_click(
+                move |bq: Query<&CosmicEditBuffer>,
+                      eq: Query<&CosmicEditor>,
+                      send: Res<SendMessage>,
+                      mut commands: Commands| {
+                    let name = get_text(input, &eq, &bq);
+                    let _ = send.send(MessageFromPlayer::SendFriendRequest(name));
+                    commands.queue(CloseModal);
+                },
+            );
+    });
+}
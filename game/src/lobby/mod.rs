@@ -1,7 +1,24 @@
+mod access;
+mod browser;
 mod champ_select;
+mod chat;
+mod error_text;
+mod friends;
+mod party;
+mod profile;
+mod queue;
+mod ready_check;
+
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
 
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
-
+use access::{access, prompt_invite_code, prompt_lobby_password, LobbyAccessReceived};
 use bevy::{ecs::component::StorageType, prelude::*, utils::HashMap};
 use bevy_cosmic_edit::{
     cosmic_text::{Attrs, AttrsOwned, BufferRef, Edit as _, FontSystem},
@@ -9,8 +26,15 @@ use bevy_cosmic_edit::{
     input::drag,
     BufferRefExtras as _, CosmicEditBuffer, CosmicFontSystem,
 };
-use bevy_tokio_tasks::TokioTasksRuntime;
-use champ_select::build_champ_select;
+use bevy_tokio_tasks::{TaskContext, TokioTasksRuntime};
+use browser::{
+    browser, build_load_more, build_lobby_filters, state_text, LoadMoreButton, LobbyBrowser,
+};
+use champ_select::{build_champ_select, champ_select};
+use chat::{build_chat_panel, chat, ChatLog, ChatMessageReceived, ChatTarget};
+use engine::champion::ChampionRegistry;
+pub(crate) use error_text::{disband_text, error_text};
+use friends::{build_friends_panel, friends, Friends, FriendsChanged};
 use lightyear::{
     client::config::{ClientConfig, NetcodeConfig},
     prelude::{
@@ -18,20 +42,33 @@ use lightyear::{
         ConnectToken, SharedConfig,
     },
 };
-use lobby_server::{
-    Lobby, LobbyId, LobbySettings, LobbyShortInfo, LobbyState as LState, MessageFromPlayer,
-    MessageFromServer, PlayerId, PlayerInfo, ReadMessage, Team, WriteMessage,
+use party::{
+    build_invite_button, build_party_panel, party, CurrentParty, PartyInviteReceived, PartyUpdated,
+};
+use profile::{build_profile_button, profile, MatchHistoryReceived, ProfileReceived};
+use protocol::{
+    ApplyResult, ChampSelectMode, Credentials, Lobby, LobbyId, LobbyListCursor, LobbySettings,
+    LobbyShortInfo, LobbyState as LState, LobbyUpdate, MessageFromPlayer, MessageFromServer,
+    PlayerId, PlayerInfo, PlayerRequest, ReadMessage, RequestId, ResumeToken, ServerMessage, Team,
+    WriteMessage,
 };
+use queue::{build_queue_buttons, hide_queue_status, queue, show_queue_status, InQueue};
+use ready_check::{build_ready_check, ready_check};
 use tokio::task::JoinHandle;
+use wtransport::{RecvStream, SendStream};
 
 use crate::{
-    game::network::GameServerToken,
-    login::{LobbyConnection, MyPlayerId},
+    game::{
+        map::CurrentMap,
+        network::{GameServerToken, Spectating},
+    },
+    login::{try_connect, CertificateValidation, LobbyConnection, MyPlayerId},
     ui::{
         build_textedit,
         checkbox::{build_checkbox, Checkbox},
         create_modal, CloseModal, CreateModal, OnClickExt, ScrollEvent,
     },
+    Champions, Maps,
 };
 
 pub fn lobby(app: &mut App) {
@@ -44,10 +81,25 @@ pub fn lobby(app: &mut App) {
     app.add_observer(refresh_lobby_list);
     app.add_observer(refresh_lobby_interface);
     app.add_observer(on_player_info_updated);
-    app.add_systems(OnEnter(crate::State::Lobby), (setup, setup_ui));
+    app.add_systems(
+        OnEnter(crate::State::Lobby),
+        (setup, setup_ui, return_to_lobby),
+    );
     app.add_systems(OnExit(crate::State::Lobby), cleanup);
     app.add_systems(OnEnter(LobbyState::LobbyBrowser), on_enter_lobby_browser);
     app.add_systems(OnEnter(LobbyState::InLobby), on_enter_lobby);
+    app.add_systems(Update, expire_refusal_labels);
+    app.add_plugins((
+        chat,
+        access,
+        browser,
+        queue,
+        party,
+        friends,
+        profile,
+        ready_check,
+        champ_select,
+    ));
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, SubStates, Default)]
@@ -60,13 +112,10 @@ enum LobbyState {
 }
 
 #[derive(Event)]
-pub struct MsgEvent(MessageFromServer);
-
-#[derive(Resource)]
-pub struct RecvLobbyTask(JoinHandle<()>);
+pub struct MsgEvent(ServerMessage);
 
 #[derive(Resource)]
-pub struct SendLobbyTask(JoinHandle<()>);
+pub struct LobbySessionTask(JoinHandle<()>);
 
 #[derive(Resource)]
 pub struct CurrentLobby {
@@ -74,72 +123,189 @@ pub struct CurrentLobby {
     info: Option<Lobby>,
 }
 
-#[derive(Resource, Deref)]
-pub struct SendMessage(tokio::sync::mpsc::UnboundedSender<MessageFromPlayer>);
+#[derive(Resource)]
+pub struct SendMessage {
+    sender: tokio::sync::mpsc::UnboundedSender<PlayerRequest>,
+    next_request_id: AtomicU64,
+    /// The UI controls that sent requests we are still waiting on a reply for.
+    origins: Mutex<HashMap<RequestId, Entity>>,
+}
+
+impl SendMessage {
+    fn new(sender: tokio::sync::mpsc::UnboundedSender<PlayerRequest>) -> Self {
+        Self {
+            sender,
+            next_request_id: AtomicU64::new(0),
+            origins: default(),
+        }
+    }
+
+    /// Sends a request to the lobby server, returning the id that its reply will carry.
+    pub fn send(&self, message: MessageFromPlayer) -> RequestId {
+        let id = RequestId(self.next_request_id.fetch_add(1, Ordering::Relaxed));
+        let _ = self.sender.send(PlayerRequest { id, message });
+        id
+    }
+
+    /// Sends a request on behalf of a UI control, so that a refusal can be shown next to it.
+    pub fn send_from(&self, origin: Entity, message: MessageFromPlayer) -> RequestId {
+        let id = self.send(message);
+        self.origins.lock().unwrap().insert(id, origin);
+        id
+    }
+
+    fn take_origin(&self, id: RequestId) -> Option<Entity> {
+        self.origins.lock().unwrap().remove(&id)
+    }
+}
 
 fn setup(
     runtime: Res<TokioTasksRuntime>,
-    connection: Res<LobbyConnection>,
+    mut connection: ResMut<LobbyConnection>,
     mut commands: Commands,
 ) {
-    let conn = connection.0.clone();
-    let reciever = runtime.spawn_background_task(|mut ctx| async move {
+    // The streams are only handed out once per connection;
+    // if they are gone, the session task is already running.
+    let Some(streams) = connection.streams.take() else {
+        return;
+    };
+    let server = connection.server.clone();
+    let validation = connection.validation.clone();
+    let resume_token = connection.resume_token;
+
+    let (send, recv) = tokio::sync::mpsc::unbounded_channel();
+
+    let session = runtime.spawn_background_task(move |ctx| async move {
+        run_session(ctx, streams, recv, server, validation, resume_token).await;
+    });
+
+    commands.insert_resource(LobbySessionTask(session));
+    commands.insert_resource(SendMessage::new(send));
+}
+
+/// How often to try resuming the session after losing connection, before giving up.
+const RESUME_ATTEMPTS: u32 = 5;
+const RESUME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Runs the session with the lobby server, resuming it on a new connection if the old one drops.
+///
+/// Requests queued while reconnecting are sent once the session is resumed.
+async fn run_session(
+    mut ctx: TaskContext,
+    mut streams: (SendStream, RecvStream),
+    mut outgoing: tokio::sync::mpsc::UnboundedReceiver<PlayerRequest>,
+    server: String,
+    validation: CertificateValidation,
+    mut resume_token: ResumeToken,
+) {
+    // Keeps a resumed connection alive; the first one is kept in `LobbyConnection`
+    let mut _connection = None;
+
+    loop {
+        let Err(e) = run_connection(&mut ctx, streams, &mut outgoing).await else {
+            return;
+        };
+        warn!("Lost connection to lobby server: {e}");
+
+        let mut resumed = None;
+        for attempt in 1..=RESUME_ATTEMPTS {
+            tokio::time::sleep(RESUME_INTERVAL).await;
+            info!("Resuming session, attempt {attempt}/{RESUME_ATTEMPTS}");
+            let credentials = Credentials::Resume(resume_token);
+            match try_connect(server.clone(), validation.clone(), credentials).await {
+                Ok((connection, _)) => {
+                    resumed = Some(connection);
+                    break;
+                }
+                Err(e) => warn!("Failed to resume session: {e}"),
+            }
+        }
+        let Some(mut connection) = resumed else {
+            ctx.run_on_main_thread(move |ctx| {
+                ctx.world.trigger(MsgEvent(ServerMessage {
+                    in_reply_to: None,
+                    message: MessageFromServer::ServerShutdown,
+                }));
+            })
+            .await;
+            return;
+        };
+        info!("Session resumed");
+        streams = connection.streams.take().unwrap();
+        resume_token = connection.resume_token;
+        _connection = Some(connection);
+    }
+}
+
+/// Runs the session on one connection, returning an error if the connection is lost.
+async fn run_connection(
+    ctx: &mut TaskContext,
+    (mut send_stream, mut recv_stream): (SendStream, RecvStream),
+    outgoing: &mut tokio::sync::mpsc::UnboundedReceiver<PlayerRequest>,
+) -> anyhow::Result<()> {
+    // Reading a message is not cancel safe, so it gets its own task
+    let (incoming_send, mut incoming) = tokio::sync::mpsc::unbounded_channel();
+    let reader = tokio::spawn(async move {
         let Err(e): anyhow::Result<!> = try {
             loop {
-                let mut stream = conn.accept_uni().await?;
-                let message = stream.read_message().await?;
+                let message = recv_stream.read_message().await?;
+                if incoming_send.send(message).is_err() {
+                    return anyhow::anyhow!("Session ended");
+                }
+            }
+        };
+        e
+    });
+
+    loop {
+        tokio::select! {
+            message = incoming.recv() => {
+                let Some(message) = message else {
+                    return Err(reader.await?);
+                };
                 ctx.run_on_main_thread(move |ctx| {
                     info!("Message received: {message:?}");
                     ctx.world.trigger(MsgEvent(message));
                 })
                 .await;
             }
-        };
-        warn!("Error receiving lobby message: {e}");
-        ctx.run_on_main_thread(move |ctx| {
-            ctx.world
-                .trigger(MsgEvent(MessageFromServer::ServerShutdown));
-        })
-        .await;
-    });
-
-    let (send, mut recv) = tokio::sync::mpsc::unbounded_channel();
-
-    let conn = connection.0.clone();
-    let sender = runtime.spawn_background_task(|_| async move {
-        let x: anyhow::Result<()> = try {
-            while let Some(msg) = recv.recv().await {
-                let should_exit = matches!(msg, MessageFromPlayer::Disconnecting);
+            msg = outgoing.recv() => {
+                let Some(msg) = msg else { break };
+                let should_exit = matches!(msg.message, MessageFromPlayer::Disconnecting);
                 info!("Message sent: {msg:?}");
-                conn.open_uni().await?.await?.write_message(msg).await?;
+                send_stream.write_message(msg).await?;
                 if should_exit {
                     break;
                 }
             }
-        };
-        if let Err(e) = x {
-            warn!("Error sending lobby message: {e}");
         }
-    });
+    }
+    reader.abort();
+    send_stream.finish().await?;
+    Ok(())
+}
 
-    commands.insert_resource(RecvLobbyTask(reciever));
-    commands.insert_resource(SendLobbyTask(sender));
-    commands.insert_resource(SendMessage(send));
+/// Coming back from a game, players land in the lobby they played it from, if it is still there.
+fn return_to_lobby(
+    current_lobby: Option<Res<CurrentLobby>>,
+    mut next_state: ResMut<NextState<LobbyState>>,
+) {
+    if current_lobby.is_some() {
+        next_state.set(LobbyState::InLobby);
+    }
 }
 
-fn cleanup(recv_task: Res<RecvLobbyTask>, send_task: Res<SendLobbyTask>, mut commands: Commands) {
-    // recv_task.0.abort();
-    // send_task.0.abort();
+fn cleanup(session_task: Res<LobbySessionTask>, mut commands: Commands) {
+    // session_task.0.abort();
 
-    // commands.remove_resource::<RecvLobbyTask>();
-    // commands.remove_resource::<SendLobbyTask>();
+    // commands.remove_resource::<LobbySessionTask>();
     // commands.remove_resource::<SendMessage>();
 }
 
 #[derive(Component)]
 struct LobbyTabAnchor;
 
-fn setup_ui(mut commands: Commands) {
+fn setup_ui(party: Option<Res<CurrentParty>>, friends: Res<Friends>, mut commands: Commands) {
     commands
         .spawn((
             Node {
@@ -158,10 +324,13 @@ fn setup_ui(mut commands: Commands) {
                     width: Val::Percent(100.0),
                     max_height: Val::Percent(100.0),
                     flex_grow: 1.0,
+                    flex_direction: FlexDirection::Column,
                     ..default()
                 },
                 LobbyTabAnchor,
             ));
+            build_party_panel(parent, party.as_deref());
+            build_friends_panel(parent, &friends);
             // build_lobby_list(parent);
         });
 }
@@ -169,22 +338,28 @@ fn setup_ui(mut commands: Commands) {
 fn on_enter_lobby_browser(
     lobby_tab_anchor: Single<Entity, With<LobbyTabAnchor>>,
     send: Res<SendMessage>,
+    browser: Res<LobbyBrowser>,
+    mut font_system: ResMut<CosmicFontSystem>,
     mut commands: Commands,
 ) {
     // Create lobby list
     commands
         .entity(*lobby_tab_anchor)
         .despawn_descendants()
-        .with_children(build_lobby_list);
+        .with_children(|parent| build_lobby_list(parent, &browser, &mut font_system.0));
     // Send request to fetch lobbies
     // The response message triggers building the list
-    let _ = send.send(MessageFromPlayer::GetLobbyList);
+    browser.request(&send, None);
 }
 
 #[derive(Component)]
 struct LobbyListAnchor;
 
-fn build_lobby_list(parent: &mut ChildBuilder) {
+fn build_lobby_list(
+    parent: &mut ChildBuilder,
+    browser: &LobbyBrowser,
+    font_system: &mut FontSystem,
+) {
     // Root container
     parent
         .spawn(Node {
@@ -204,20 +379,48 @@ fn build_lobby_list(parent: &mut ChildBuilder) {
                     // Create new lobby button
                     parent.spawn((Button, Text::new("[Create Lobby]"))).observe(
                         |mut trigger: Trigger<Pointer<Click>>, res: Res<SendMessage>| {
-                            let _ = res.0.send(MessageFromPlayer::CreateLobby);
+                            res.send_from(trigger.entity(), MessageFromPlayer::CreateLobby);
                             trigger.propagate(false);
                         },
                     );
 
                     // Refresh lobby list button
                     parent.spawn((Button, Text::new("[Refresh]"))).observe(
-                        |mut trigger: Trigger<Pointer<Click>>, res: Res<SendMessage>| {
-                            let _ = res.0.send(MessageFromPlayer::GetLobbyList);
+                        |mut trigger: Trigger<Pointer<Click>>,
+                         browser: Res<LobbyBrowser>,
+                         res: Res<SendMessage>| {
+                            browser.request(&res, None);
                             trigger.propagate(false);
                         },
                     );
+
+                    // Join a private lobby
+                    parent
+                        .spawn((Button, Text::new("[Join with Code]")))
+                        .on_click(
+                            |mut font_system: ResMut<CosmicFontSystem>, mut commands: Commands| {
+                                prompt_invite_code(&mut commands, &mut font_system.0);
+                            },
+                        );
+
+                    // Matchmaking
+                    build_queue_buttons(parent);
+
+                    parent.spawn((Button, Text::new("[Profile]"))).observe(
+                        |mut trigger: Trigger<Pointer<Click>>,
+                         my_id: Res<MyPlayerId>,
+                         send: Res<SendMessage>| {
+                            trigger.propagate(false);
+                            send.send_from(
+                                trigger.entity(),
+                                MessageFromPlayer::GetProfile(my_id.0),
+                            );
+                        },
+                    );
                 });
 
+            build_lobby_filters(parent, browser, font_system);
+
             // Lobby list anchor
             parent
                 .spawn((
@@ -230,39 +433,47 @@ fn build_lobby_list(parent: &mut ChildBuilder) {
                     },
                     LobbyListAnchor,
                 ))
-                .with_children(|parent| {
-                    for i in 0..100 {
-                        build_lobby_list_entry(
-                            parent,
-                            &LobbyShortInfo {
-                                id: LobbyId::new(),
-                                name: format!("Lobby {i}"),
-                                player_count: 0,
-                                max_player_count: 10,
-                            },
-                        );
-                    }
-                })
                 .observe(scroll);
         });
 }
 
 #[derive(Event)]
-struct RefreshLobbyList(Vec<LobbyShortInfo>);
+struct RefreshLobbyList {
+    after: Option<LobbyListCursor>,
+    lobbies: Vec<LobbyShortInfo>,
+    next: Option<LobbyListCursor>,
+}
 
+/// Replaces the list with its first page, or appends the page after it.
 fn refresh_lobby_list(
     trigger: Trigger<RefreshLobbyList>,
     lobby_anchor: Single<Entity, With<LobbyListAnchor>>,
+    load_more: Query<Entity, With<LoadMoreButton>>,
+    mut browser: ResMut<LobbyBrowser>,
     mut commands: Commands,
 ) {
-    let mut entity_commands = commands.entity(*lobby_anchor);
-    entity_commands
-        .despawn_descendants()
-        .with_children(|parent| {
-            for lobby in &trigger.0 {
-                build_lobby_list_entry(parent, lobby);
-            }
-        });
+    let RefreshLobbyList {
+        after,
+        lobbies,
+        next,
+    } = trigger.event();
+    browser.next = next.clone();
+
+    if after.is_none() {
+        commands.entity(*lobby_anchor).despawn_descendants();
+    } else {
+        for button in &load_more {
+            commands.entity(button).despawn_recursive();
+        }
+    }
+    commands.entity(*lobby_anchor).with_children(|parent| {
+        for lobby in lobbies {
+            build_lobby_list_entry(parent, lobby);
+        }
+        if next.is_some() {
+            build_load_more(parent);
+        }
+    });
 }
 
 fn build_lobby_list_entry(parent: &mut ChildBuilder, lobby_info: &LobbyShortInfo) {
@@ -280,14 +491,28 @@ fn build_lobby_list_entry(parent: &mut ChildBuilder, lobby_info: &LobbyShortInfo
                     ..default()
                 },
             ));
+            parent.spawn(Text::new(&lobby_info.map));
+            parent.spawn(Text::new(&lobby_info.leader_name));
+            parent.spawn(Text::new(state_text(lobby_info.state)));
+            if lobby_info.password_protected {
+                parent.spawn(Text::new("[Locked]"));
+            }
             parent.spawn(Text::new(format!(
                 "{}/{}",
                 lobby_info.player_count, lobby_info.max_player_count
             )));
             let id = lobby_info.id;
+            let password_protected = lobby_info.password_protected;
             parent.spawn((Button, Text::new("[Join]"))).observe(
-                move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
-                    let _ = send.send(MessageFromPlayer::JoinLobby(id));
+                move |mut trigger: Trigger<Pointer<Click>>,
+                      send: Res<SendMessage>,
+                      mut font_system: ResMut<CosmicFontSystem>,
+                      mut commands: Commands| {
+                    if password_protected {
+                        prompt_lobby_password(&mut commands, &mut font_system.0, id);
+                    } else {
+                        send.send_from(trigger.entity(), MessageFromPlayer::JoinLobby(id, None));
+                    }
                     trigger.propagate(false);
                 },
             );
@@ -308,13 +533,22 @@ fn on_enter_lobby(
     lobby_tab_anchor: Single<Entity, With<LobbyTabAnchor>>,
     current_lobby: Res<CurrentLobby>,
     send: Res<SendMessage>,
+    chat_log: Res<ChatLog>,
+    chat_target: Res<ChatTarget>,
+    my_id: Res<MyPlayerId>,
+    mut font_system: ResMut<CosmicFontSystem>,
     mut commands: Commands,
 ) {
     // Create lobby interface
     commands
         .entity(*lobby_tab_anchor)
         .despawn_descendants()
-        .with_children(build_lobby_interface);
+        .with_children(|parent| {
+            build_lobby_interface(parent);
+            // The chat panel lives outside of the lobby interface,
+            // so that it isn't rebuilt with every lobby update.
+            build_chat_panel(parent, &chat_log, &chat_target, my_id.0, &mut font_system.0);
+        });
 
     let _ = send.send(MessageFromPlayer::GetLobbyInfo(current_lobby.id));
 }
@@ -326,7 +560,7 @@ fn build_lobby_interface(parent: &mut ChildBuilder) {
     parent.spawn((
         Node {
             width: Val::Percent(100.0),
-            max_height: Val::Percent(100.0),
+            max_height: Val::Percent(75.0),
             flex_direction: FlexDirection::Column,
             ..default()
         },
@@ -340,6 +574,8 @@ struct RefreshLobbyInterface;
 struct LobbyBuildingContext<'a> {
     lobby: &'a Lobby,
     player_cache: &'a PlayerCache,
+    champions: &'a ChampionRegistry,
+    asset_server: &'a AssetServer,
     send: &'a SendMessage,
     my_id: PlayerId,
 }
@@ -349,13 +585,17 @@ impl LobbyBuildingContext<'_> {
         self.lobby.leader == self.my_id
     }
 
-    fn my_team(&self) -> Team {
+    /// `None` while we are spectating.
+    fn my_team(&self) -> Option<Team> {
         self.lobby
             .players
             .iter()
             .find(|(_, v)| v.contains(&self.my_id))
             .map(|(t, _)| *t)
-            .unwrap()
+    }
+
+    fn i_am_spectator(&self) -> bool {
+        self.lobby.spectators.contains(&self.my_id)
     }
 }
 
@@ -364,6 +604,8 @@ fn refresh_lobby_interface(
     lobby: Res<CurrentLobby>,
     lobby_interface_anchor: Single<Entity, With<LobbyInterfaceAnchor>>,
     player_cache: Res<PlayerCache>,
+    champions: Res<Champions>,
+    asset_server: Res<AssetServer>,
     send: Res<SendMessage>,
     my_id: Res<MyPlayerId>,
     mut commands: Commands,
@@ -376,6 +618,8 @@ fn refresh_lobby_interface(
                 &LobbyBuildingContext {
                     lobby: lobby.info.as_ref().unwrap(),
                     player_cache: &player_cache,
+                    champions: &champions.0,
+                    asset_server: &asset_server,
                     send: &send,
                     my_id: my_id.0,
                 },
@@ -388,11 +632,19 @@ fn update_lobby_interface(ctx: &LobbyBuildingContext, parent: &mut ChildBuilder)
     match &ctx.lobby.lobby_state {
         LState::Normal => { /* TODO: break out into separate module instead of continuing in this function */
         }
+        LState::ReadyCheck(_) => {
+            build_ready_check(ctx, parent);
+            return;
+        }
         LState::ChampSelect(_) => {
             build_champ_select(ctx, parent);
             return;
         }
-        LState::InGame => unreachable!("Should never need to draw UI for this"),
+        // Members are about to join the game
+        LState::InGame => {
+            parent.spawn(Text::new("Game in progress"));
+            return;
+        }
     }
 
     // Top bar
@@ -421,11 +673,20 @@ fn update_lobby_interface(ctx: &LobbyBuildingContext, parent: &mut ChildBuilder)
                             });
                         },
                     );
+                // Password and invite codes
                 parent
-                    .spawn((Button, Text::new("[Enter Champ Select]")))
-                    .on_click(move |send: Res<SendMessage>| {
-                        let _ = send.send(MessageFromPlayer::EnterChampSelect);
+                    .spawn((Button, Text::new("[Access]")))
+                    .on_click(|send: Res<SendMessage>| {
+                        let _ = send.send(MessageFromPlayer::GetLobbyAccess);
                     });
+                parent
+                    .spawn((Button, Text::new("[Enter Champ Select]")))
+                    .observe(
+                        |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                            trigger.propagate(false);
+                            send.send_from(trigger.entity(), MessageFromPlayer::EnterChampSelect);
+                        },
+                    );
             }
         });
 
@@ -465,6 +726,57 @@ fn update_lobby_interface(ctx: &LobbyBuildingContext, parent: &mut ChildBuilder)
                         build_team_list(Team(ctx.lobby.settings.team_count - 1), ctx, parent);
                     });
             }
+
+            if ctx.lobby.settings.spectator_slots > 0 {
+                build_spectator_list(ctx, parent);
+            }
+        });
+}
+
+fn build_spectator_list(ctx: &LobbyBuildingContext, parent: &mut ChildBuilder) {
+    parent
+        .spawn(Node {
+            width: Val::Percent(100.0),
+            flex_direction: FlexDirection::Column,
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn(Node { ..default() }).with_children(|parent| {
+                parent.spawn(Text::new(format!(
+                    "Spectators ({}/{})",
+                    ctx.lobby.spectators.len(),
+                    ctx.lobby.settings.spectator_slots
+                )));
+                if !ctx.i_am_spectator()
+                    && ctx.lobby.spectators.len() < ctx.lobby.settings.spectator_slots
+                {
+                    let player_id = ctx.my_id;
+                    parent.spawn((Button, Text::new("[Spectate]"))).observe(
+                        move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                            trigger.propagate(false);
+                            send.send_from(
+                                trigger.entity(),
+                                MessageFromPlayer::Spectate(player_id),
+                            );
+                        },
+                    );
+                }
+            });
+
+            for player in ctx.lobby.spectators.iter().copied() {
+                let name = if let Some(info) = ctx.player_cache.players.get(&player) {
+                    info.name.clone()
+                } else {
+                    let _ = ctx.send.send(MessageFromPlayer::GetPlayerInfo(player));
+                    "Loading...".into()
+                };
+                parent
+                    .spawn(Node {
+                        width: Val::Percent(100.0),
+                        ..default()
+                    })
+                    .with_children(|parent| build_player_slot_contents(name, player, ctx, parent));
+            }
         });
 }
 
@@ -500,6 +812,9 @@ fn build_settings_menu(
     let can_change_team = row(parent, "Players can change Team: ", |parent| {
         build_checkbox(parent, settings.players_can_change_team)
     });
+    let draft = row(parent, "Draft pick: ", |parent| {
+        build_checkbox(parent, settings.champ_select_mode == ChampSelectMode::Draft)
+    });
     let map = row(parent, "Map: ", |parent| {
         build_textedit(parent, &settings.map, font_system)
     });
@@ -565,6 +880,115 @@ fn build_settings_menu(
         });
         t
     });
+    let pick_time = row(parent, "Pick time (seconds, 0 for none): ", |parent| {
+        let d = parent.spawn((Button, Text::new("[-] "))).id();
+        let t = parent
+            .spawn(Text::new(settings.pick_time_secs.to_string()))
+            .id();
+        let u = parent.spawn((Button, Text::new(" [+]"))).id();
+
+        parent.enqueue_command(move |world: &mut World| {
+            world.spawn(
+                Observer::new(
+                    move |mut trigger: Trigger<Pointer<Click>>, mut q: Query<&mut Text>| {
+                        trigger.propagate(false);
+                        let mut text = q.get_mut(t).unwrap();
+                        text.0 = text
+                            .0
+                            .parse::<u64>()
+                            .unwrap()
+                            .saturating_sub(10)
+                            .to_string();
+                    },
+                )
+                .with_entity(d),
+            );
+            world.spawn(
+                Observer::new(
+                    move |mut trigger: Trigger<Pointer<Click>>, mut q: Query<&mut Text>| {
+                        trigger.propagate(false);
+                        let mut text = q.get_mut(t).unwrap();
+                        text.0 = (text.0.parse::<u64>().unwrap() + 10).to_string();
+                    },
+                )
+                .with_entity(u),
+            );
+        });
+        t
+    });
+
+    let spectator_slots = row(parent, "Spectator slots: ", |parent| {
+        let d = parent.spawn((Button, Text::new("[-] "))).id();
+        let t = parent
+            .spawn(Text::new(settings.spectator_slots.to_string()))
+            .id();
+        let u = parent.spawn((Button, Text::new(" [+]"))).id();
+
+        parent.enqueue_command(move |world: &mut World| {
+            world.spawn(
+                Observer::new(
+                    move |mut trigger: Trigger<Pointer<Click>>, mut q: Query<&mut Text>| {
+                        trigger.propagate(false);
+                        let mut text = q.get_mut(t).unwrap();
+                        text.0 = text
+                            .0
+                            .parse::<usize>()
+                            .unwrap()
+                            .saturating_sub(1)
+                            .to_string();
+                    },
+                )
+                .with_entity(d),
+            );
+            world.spawn(
+                Observer::new(
+                    move |mut trigger: Trigger<Pointer<Click>>, mut q: Query<&mut Text>| {
+                        trigger.propagate(false);
+                        let mut text = q.get_mut(t).unwrap();
+                        text.0 = (text.0.parse::<usize>().unwrap() + 1).to_string();
+                    },
+                )
+                .with_entity(u),
+            );
+        });
+        t
+    });
+    let spectator_delay = row(parent, "Spectator delay (seconds): ", |parent| {
+        let d = parent.spawn((Button, Text::new("[-] "))).id();
+        let t = parent
+            .spawn(Text::new(settings.spectator_delay_secs.to_string()))
+            .id();
+        let u = parent.spawn((Button, Text::new(" [+]"))).id();
+
+        parent.enqueue_command(move |world: &mut World| {
+            world.spawn(
+                Observer::new(
+                    move |mut trigger: Trigger<Pointer<Click>>, mut q: Query<&mut Text>| {
+                        trigger.propagate(false);
+                        let mut text = q.get_mut(t).unwrap();
+                        text.0 = text
+                            .0
+                            .parse::<u64>()
+                            .unwrap()
+                            .saturating_sub(30)
+                            .to_string();
+                    },
+                )
+                .with_entity(d),
+            );
+            world.spawn(
+                Observer::new(
+                    move |mut trigger: Trigger<Pointer<Click>>, mut q: Query<&mut Text>| {
+                        trigger.propagate(false);
+                        let mut text = q.get_mut(t).unwrap();
+                        text.0 = (text.0.parse::<u64>().unwrap() + 30).to_string();
+                    },
+                )
+                .with_entity(u),
+            );
+        });
+        t
+    });
 
     parent
         .spawn(Node {
@@ -603,10 +1027,17 @@ fn build_settings_menu(
                     let lobby_name = get_text(lobby_name);
                     let lobby_is_open = cq.get(allow_joining).unwrap().checked;
                     let players_can_change_team = cq.get(can_change_team).unwrap().checked;
+                    let champ_select_mode = match cq.get(draft).unwrap().checked {
+                        true => ChampSelectMode::Draft,
+                        false => ChampSelectMode::Blind,
+                    };
                     let map = get_text(map);
                     let team_count = tq.get(team_count).unwrap().0.parse().unwrap();
                     let player_limit_per_team =
                         tq.get(players_per_team).unwrap().0.parse().unwrap();
+                    let pick_time_secs = tq.get(pick_time).unwrap().0.parse().unwrap();
+                    let spectator_slots = tq.get(spectator_slots).unwrap().0.parse().unwrap();
+                    let spectator_delay_secs = tq.get(spectator_delay).unwrap().0.parse().unwrap();
 
                     let settings = LobbySettings {
                         name: lobby_name,
@@ -615,6 +1046,10 @@ fn build_settings_menu(
                         player_limit_per_team,
                         players_can_change_team,
                         lobby_is_open,
+                        pick_time_secs,
+                        champ_select_mode,
+                        spectator_slots,
+                        spectator_delay_secs,
                     };
 
                     let _ = send.send(MessageFromPlayer::UpdateSettings(settings));
@@ -636,15 +1071,19 @@ fn build_team_list(team: Team, ctx: &LobbyBuildingContext, parent: &mut ChildBui
             // Team title
             parent.spawn(Node { ..default() }).with_children(|parent| {
                 parent.spawn((Node { ..default() }, Text::new(team.to_string())));
-                if team != ctx.my_team()
+                if Some(team) != ctx.my_team()
                     && ctx.lobby.players.get(&team).unwrap().len()
                         < ctx.lobby.settings.player_limit_per_team
                     && (ctx.lobby.settings.players_can_change_team || ctx.i_am_leader())
                 {
                     let player_id = ctx.my_id;
-                    parent.spawn((Button, Text::new("[Move]"))).on_click(
-                        move |send: Res<SendMessage>| {
-                            let _ = send.send(MessageFromPlayer::SwitchTeam(player_id, team));
+                    parent.spawn((Button, Text::new("[Move]"))).observe(
+                        move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                            trigger.propagate(false);
+                            send.send_from(
+                                trigger.entity(),
+                                MessageFromPlayer::SwitchTeam(player_id, team),
+                            );
                         },
                     );
                 }
@@ -868,11 +1307,17 @@ fn build_player_slot_contents(
         PickingBehavior::IGNORE,
     ));
 
+    build_profile_button(parent, player);
+
+    if player != ctx.my_id {
+        build_invite_button(parent, player);
+    }
+
     if ctx.i_am_leader() && player != ctx.my_id {
         parent.spawn((Button, Text::new("[Kick]"))).observe(
             move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
                 trigger.propagate(false);
-                let _ = send.send(MessageFromPlayer::KickPlayer(player));
+                send.send_from(trigger.entity(), MessageFromPlayer::KickPlayer(player));
             },
         );
     }
@@ -882,6 +1327,8 @@ fn on_player_info_updated(
     trigger: Trigger<PlayerInfoUpdated>,
     lobby: Res<CurrentLobby>,
     mut cache: ResMut<PlayerCache>,
+    champions: Res<Champions>,
+    asset_server: Res<AssetServer>,
     send: Res<SendMessage>,
     my_id: Res<MyPlayerId>,
     slot_map: Res<PlayerSlotAnchorMap>,
@@ -892,6 +1339,8 @@ fn on_player_info_updated(
     let ctx = &LobbyBuildingContext {
         lobby: lobby.info.as_ref().unwrap(),
         player_cache: &cache,
+        champions: &champions.0,
+        asset_server: &asset_server,
         send: &send,
         my_id: my_id.0,
     };
@@ -904,31 +1353,103 @@ fn on_player_info_updated(
                 build_player_slot_contents(info.name.clone(), info.id, ctx, parent)
             });
     }
+    // Spectators don't have slots of their own
+    if ctx.lobby.spectators.contains(&info.id) {
+        commands.trigger(RefreshLobbyInterface);
+    }
 
     cache.players.insert(info.id, info);
 }
 
+/// Shows why a request was refused, right after the control that sent it.
+#[derive(Component)]
+struct RefusalLabel(Timer);
+
+fn expire_refusal_labels(
+    mut q: Query<(Entity, &mut RefusalLabel)>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (e, mut label) in &mut q {
+        if label.0.tick(time.delta()).finished() {
+            commands.entity(e).despawn_recursive();
+        }
+    }
+}
+
 fn on_msg_send(
     trigger: Trigger<MsgEvent>,
     current_state: Option<Res<State<LobbyState>>>,
     mut next_state: Option<ResMut<NextState<LobbyState>>>,
+    game_state: Res<State<crate::State>>,
     mut next_game_state: ResMut<NextState<crate::State>>,
     send: Res<SendMessage>,
-    current_lobby: Option<Res<CurrentLobby>>,
+    current_lobby: Option<ResMut<CurrentLobby>>,
+    mut chat_log: ResMut<ChatLog>,
+    player_cache: Res<PlayerCache>,
+    my_id: Res<MyPlayerId>,
+    maps: Res<Maps>,
+    in_queue: Option<Res<InQueue>>,
+    parents: Query<&Parent>,
+    children: Query<&Children>,
     mut commands: Commands,
 ) {
-    let event = &trigger.event().0;
+    let ServerMessage {
+        in_reply_to,
+        message: event,
+    } = &trigger.event().0;
+
+    let origin = in_reply_to.and_then(|id| send.take_origin(id));
 
     match event {
-        MessageFromServer::RequestRefused(msg) => {
-            create_modal(&mut commands, "Message from Server", true, |parent| {
-                parent.spawn(Text::new(msg));
+        MessageFromServer::RequestAccepted => {}
+        MessageFromServer::RequestRefused(error) => {
+            let msg = error_text(error);
+            // The control might be gone if the UI was rebuilt in the meantime;
+            // fall back to a modal in that case.
+            let slot = origin.and_then(|origin| {
+                let parent = parents.get(origin).ok()?.get();
+                let index = children
+                    .get(parent)
+                    .ok()?
+                    .iter()
+                    .position(|c| *c == origin)?;
+                Some((parent, index))
             });
+            match slot {
+                Some((parent, index)) => {
+                    let label = commands
+                        .spawn((
+                            Text::new(msg),
+                            TextColor(Color::srgb(1.0, 0.3, 0.3)),
+                            RefusalLabel(Timer::new(Duration::from_secs(5), TimerMode::Once)),
+                        ))
+                        .id();
+                    commands.entity(parent).insert_children(index + 1, &[label]);
+                }
+                None => {
+                    create_modal(&mut commands, "Message from Server", true, |parent| {
+                        parent.spawn(Text::new(msg));
+                    });
+                }
+            }
         }
-        MessageFromServer::LobbyList(list) => {
-            commands.trigger(RefreshLobbyList(list.clone()));
+        MessageFromServer::LobbyList {
+            after,
+            lobbies,
+            next,
+        } => {
+            commands.trigger(RefreshLobbyList {
+                after: after.clone(),
+                lobbies: lobbies.clone(),
+                next: next.clone(),
+            });
         }
         MessageFromServer::YouJoinedLobby(id) => {
+            // A match was found
+            if in_queue.is_some() {
+                hide_queue_status(&mut commands);
+            }
             commands.insert_resource(CurrentLobby {
                 id: *id,
                 info: None,
@@ -939,43 +1460,161 @@ fn on_msg_send(
         }
         MessageFromServer::YouLeftLobby => {
             commands.remove_resource::<CurrentLobby>();
+            chat_log.clear();
             if let Some(mut next_state) = next_state {
                 next_state.set(LobbyState::LobbyBrowser);
             }
         }
+        MessageFromServer::LobbyDisbanded(reason) => {
+            commands.remove_resource::<CurrentLobby>();
+            chat_log.clear();
+            if let Some(mut next_state) = next_state {
+                next_state.set(LobbyState::LobbyBrowser);
+            }
+            // Lobbies are only disbanded mid-game when their game server fails
+            if *game_state.get() == crate::State::InGame {
+                next_game_state.set(crate::State::Lobby);
+            }
+            let msg = disband_text(reason);
+            create_modal(&mut commands, "Lobby Closed", true, |parent| {
+                parent.spawn(Text::new(msg));
+            });
+        }
         MessageFromServer::LobbyInfo(lobby) => {
             if let Some(current_state) = current_state
                 && *current_state == LobbyState::InLobby
             {
+                let mut lobby = Lobby::clone(lobby);
+                if let LState::ChampSelect(state) = &mut lobby.lobby_state {
+                    state.start_timer();
+                }
                 commands.insert_resource(CurrentLobby {
                     id: lobby.id,
-                    info: Some(lobby.clone()),
+                    info: Some(lobby),
                 });
                 commands.trigger(RefreshLobbyInterface);
             }
         }
+        MessageFromServer::YouJoinedQueue {
+            mode,
+            expected_wait,
+            waited,
+        } => {
+            show_queue_status(&mut commands, *mode, *expected_wait, *waited);
+        }
+        MessageFromServer::YouLeftQueue => {
+            hide_queue_status(&mut commands);
+        }
+        MessageFromServer::PartyUpdated(party) => {
+            commands.trigger(PartyUpdated(party.clone()));
+        }
+        MessageFromServer::PartyInvite { party, from } => {
+            commands.trigger(PartyInviteReceived {
+                party: *party,
+                from: from.clone(),
+            });
+        }
+        MessageFromServer::FriendList { friends, requests } => {
+            commands.trigger(FriendsChanged::List {
+                friends: friends.clone(),
+                requests: requests.clone(),
+            });
+        }
+        MessageFromServer::FriendUpdated(friend) => {
+            commands.trigger(FriendsChanged::Updated(friend.clone()));
+        }
+        MessageFromServer::FriendRemoved(id) => {
+            commands.trigger(FriendsChanged::Removed(*id));
+        }
+        MessageFromServer::FriendRequestReceived(player) => {
+            commands.trigger(FriendsChanged::RequestReceived(player.clone()));
+        }
+        MessageFromServer::LobbyAccess(access) => {
+            commands.trigger(LobbyAccessReceived(access.clone()));
+        }
         MessageFromServer::PlayerInfo(player) => {
             commands.trigger(PlayerInfoUpdated(player.clone()));
         }
-        MessageFromServer::PlayerJoinedYourLobby(_)
-        | MessageFromServer::LobbyLeaderChanged(_)
-        | MessageFromServer::PlayerLeftYourLobby(_)
-        | MessageFromServer::PlayerSwitchedTeam(_, _)
-        | MessageFromServer::SettingsUpdated(_)
-        | MessageFromServer::PlayersSwitched(_, _)
-        | MessageFromServer::ChampSelectEntered
-        | MessageFromServer::PlayerSelectedChampion(_, _)
-        | MessageFromServer::ChampSelectionLocked(_) => {
-            if current_lobby.is_some() {
-                let _ = send.send(MessageFromPlayer::GetLobbyInfo(current_lobby.unwrap().id));
+        MessageFromServer::Profile(profile) => {
+            commands.trigger(ProfileReceived(profile.clone()));
+        }
+        MessageFromServer::MatchHistory { player, matches } => {
+            commands.trigger(MatchHistoryReceived {
+                player: *player,
+                matches: matches.clone(),
+            });
+        }
+        MessageFromServer::LobbyUpdated { update, .. } => {
+            let Some(mut current_lobby) = current_lobby else {
+                return;
+            };
+            let Some(lobby) = &mut current_lobby.info else {
+                // Still waiting for the initial lobby info; it will include this update.
+                return;
+            };
+            match lobby.apply(event) {
+                ApplyResult::Applied => {
+                    if let LobbyUpdate::ReadyCheckFailed(players) = update {
+                        let names = players
+                            .iter()
+                            .map(|p| player_cache.players.get(p).map_or("?", |p| &p.name))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        commands.queue(CreateModal::info(format!(
+                            "Ready check failed; not accepted by: {names}"
+                        )));
+                    }
+                    if matches!(update, LobbyUpdate::GameEnded)
+                        && *game_state.get() == crate::State::InGame
+                    {
+                        next_game_state.set(crate::State::Lobby);
+                    }
+                    commands.trigger(RefreshLobbyInterface);
+                }
+                ApplyResult::NotApplicable | ApplyResult::Stale => {}
+                ApplyResult::OutOfSync => {
+                    warn!("Lobby state out of sync, refetching");
+                    let _ = send.send(MessageFromPlayer::GetLobbyInfo(current_lobby.id));
+                }
             }
         }
         MessageFromServer::GameStarted(address) => {
             let token = ConnectToken::try_from_bytes(&address.0).unwrap();
             info!("Token received");
             commands.insert_resource(GameServerToken(token));
+            commands.remove_resource::<Spectating>();
+            if let Some(lobby) = current_lobby.as_ref().and_then(|l| l.info.as_ref()) {
+                if lobby.spectators.contains(&my_id.0) {
+                    commands.insert_resource(Spectating);
+                }
+                match maps.0.get(&lobby.settings.map) {
+                    Some(def) => {
+                        let team = lobby
+                            .players
+                            .iter()
+                            .find(|(_, v)| v.contains(&my_id.0))
+                            .map(|(t, _)| *t);
+                        commands.insert_resource(CurrentMap {
+                            def: def.clone(),
+                            team,
+                        });
+                    }
+                    None => error!("Unknown map {:?}", lobby.settings.map),
+                }
+            }
             next_game_state.set(crate::State::InGame);
         }
+        MessageFromServer::ChatMessage {
+            from,
+            channel,
+            text,
+        } => {
+            commands.trigger(ChatMessageReceived {
+                from: from.clone(),
+                channel: *channel,
+                text: text.clone(),
+            });
+        }
         MessageFromServer::ServerShutdown => {
             commands.queue(CreateModal::info("Lobby server was shut down".into()));
             next_game_state.set(crate::State::Login);
@@ -0,0 +1,131 @@
+use bevy::prelude::*;
+use protocol::{MessageFromPlayer, Party, PartyId, PlayerId, PlayerInfo};
+
+use crate::ui::{create_modal, CloseModal};
+
+use super::SendMessage;
+
+pub fn party(app: &mut App) {
+    app.add_observer(on_party_updated);
+    app.add_observer(on_party_invite);
+}
+
+/// The party we are in, if any.
+#[derive(Resource)]
+pub struct CurrentParty(pub Party);
+
+#[derive(Event)]
+pub struct PartyUpdated(pub Option<Party>);
+
+#[derive(Event)]
+pub struct PartyInviteReceived {
+    pub party: PartyId,
+    pub from: PlayerInfo,
+}
+
+#[derive(Component)]
+struct PartyPanelAnchor;
+
+/// The sidebar listing the members of our party, which is empty while we are not in one.
+pub fn build_party_panel(parent: &mut ChildBuilder, party: Option<&CurrentParty>) {
+    parent
+        .spawn((
+            Node {
+                flex_direction: FlexDirection::Column,
+                min_width: Val::Px(200.0),
+                ..default()
+            },
+            PartyPanelAnchor,
+        ))
+        .with_children(|parent| {
+            if let Some(party) = party {
+                build_party_members(parent, &party.0);
+            }
+        });
+}
+
+/// A button that invites `player` to our party.
+pub fn build_invite_button(parent: &mut ChildBuilder, player: PlayerId) {
+    parent.spawn((Button, Text::new("[Invite]"))).observe(
+        move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+            trigger.propagate(false);
+            send.send_from(trigger.entity(), MessageFromPlayer::InviteToParty(player));
+        },
+    );
+}
+
+fn on_party_updated(
+    trigger: Trigger<PartyUpdated>,
+    anchor: Option<Single<Entity, With<PartyPanelAnchor>>>,
+    mut commands: Commands,
+) {
+    let party = &trigger.event().0;
+    match party {
+        Some(party) => commands.insert_resource(CurrentParty(party.clone())),
+        None => commands.remove_resource::<CurrentParty>(),
+    }
+
+    let Some(anchor) = anchor else {
+        return;
+    };
+    let mut anchor = commands.entity(*anchor);
+    anchor.despawn_descendants();
+    if let Some(party) = party {
+        anchor.with_children(|parent| build_party_members(parent, party));
+    }
+}
+
+fn build_party_members(parent: &mut ChildBuilder, party: &Party) {
+    parent.spawn(Text::new("Party"));
+    for member in &party.members {
+        let leader = if member.id == party.leader {
+            "[L] "
+        } else {
+            ""
+        };
+        parent.spawn(Text::new(format!("{leader}{}", member.name)));
+    }
+    parent.spawn((Button, Text::new("[Leave Party]"))).observe(
+        |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+            trigger.propagate(false);
+            send.send_from(trigger.entity(), MessageFromPlayer::LeaveParty);
+        },
+    );
+}
+
+fn on_party_invite(trigger: Trigger<PartyInviteReceived>, mut commands: Commands) {
+    let PartyInviteReceived { party, from } = trigger.event();
+    let party = *party;
+
+    create_modal(&mut commands, "Party Invite", true, |parent| {
+        parent.spawn(Text::new(format!(
+            "{} invited you to their party.",
+            from.name
+        )));
+        parent
+            .spawn(Node {
+                flex_direction: FlexDirection::Row,
+                ..default()
+            })
+            .with_children(|parent| {
+                parent.spawn((Button, Text::new("[Accept]"))).observe(
+                    move |mut trigger: Trigger<Pointer<Click>>,
+                          send: Res<SendMessage>,
+                          mut commands: Commands| {
+                        trigger.propagate(false);
+                        send.send_from(
+                            trigger.entity(),
+                            MessageFromPlayer::AcceptPartyInvite(party),
+                        );
+                        commands.queue(CloseModal);
+                    },
+                );
+                parent.spawn((Button, Text::new("[Decline]"))).observe(
+                    |mut trigger: Trigger<Pointer<Click>>, mut commands: Commands| {
+                        trigger.propagate(false);
+                        commands.queue(CloseModal);
+                    },
+                );
+            });
+    });
+}
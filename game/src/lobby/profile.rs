@@ -0,0 +1,131 @@
+use std::time::SystemTime;
+
+use bevy::prelude::*;
+use protocol::{MatchRecord, MessageFromPlayer, PlayerId, PlayerProfile};
+
+use crate::{
+    ui::{create_modal, CloseModal},
+    Champions,
+};
+
+use super::{queue::format_duration, SendMessage};
+
+pub fn profile(app: &mut App) {
+    app.add_observer(on_profile);
+    app.add_observer(on_match_history);
+}
+
+#[derive(Event)]
+pub struct ProfileReceived(pub PlayerProfile);
+
+#[derive(Event)]
+pub struct MatchHistoryReceived {
+    pub player: PlayerId,
+    pub matches: Vec<MatchRecord>,
+}
+
+/// A button that opens the profile of `player`.
+pub fn build_profile_button(parent: &mut ChildBuilder, player: PlayerId) {
+    parent.spawn((Button, Text::new("[Profile]"))).observe(
+        move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+            trigger.propagate(false);
+            send.send_from(trigger.entity(), MessageFromPlayer::GetProfile(player));
+        },
+    );
+}
+
+/// How long ago a match was played, from its Unix time.
+fn time_ago(played_at: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match now.saturating_sub(played_at) {
+        secs if secs < 60 * 60 => format!("{} minutes ago", secs / 60),
+        secs if secs < 24 * 60 * 60 => format!("{} hours ago", secs / (60 * 60)),
+        secs => format!("{} days ago", secs / (24 * 60 * 60)),
+    }
+}
+
+/// A match score, or a dash if the game didn't count it.
+fn score(value: Option<u32>) -> String {
+    value.map_or("-".into(), |value| value.to_string())
+}
+
+fn on_profile(trigger: Trigger<ProfileReceived>, mut commands: Commands) {
+    let profile = &trigger.event().0;
+
+    commands.queue(CloseModal);
+    create_modal(&mut commands, "Profile", true, |parent| {
+        parent.spawn(Text::new(&profile.player.name));
+        parent.spawn(Text::new(format!("Rating: {:.0}", profile.rating)));
+        parent.spawn(Text::new(format!(
+            "Matches: {} ({} won, {} lost)",
+            profile.matches_played, profile.wins, profile.losses
+        )));
+
+        let player = profile.player.id;
+        parent
+            .spawn((Button, Text::new("[Match History]")))
+            .observe(
+                move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                    trigger.propagate(false);
+                    send.send_from(trigger.entity(), MessageFromPlayer::GetMatchHistory(player));
+                },
+            );
+    });
+}
+
+fn on_match_history(
+    trigger: Trigger<MatchHistoryReceived>,
+    champions: Res<Champions>,
+    mut commands: Commands,
+) {
+    let MatchHistoryReceived { player, matches } = trigger.event();
+
+    commands.queue(CloseModal);
+    create_modal(&mut commands, "Match History", true, |parent| {
+        if matches.is_empty() {
+            parent.spawn(Text::new("No matches played yet."));
+        }
+        for record in matches {
+            let result = &record.result;
+            let Some(stats) = result.players.iter().find(|s| s.player.id == *player) else {
+                continue;
+            };
+            let outcome = match result.winner {
+                Some(team) if team == stats.team && result.forfeit => "Victory by forfeit",
+                Some(team) if team == stats.team => "Victory",
+                Some(_) if result.forfeit => "Defeat by forfeit",
+                Some(_) => "Defeat",
+                None => "No result",
+            };
+            let champion = champions
+                .0
+                .get(&stats.champion)
+                .map_or(stats.champion.as_str(), |c| c.name.as_str());
+            let change = record.rating_changes.get(player).copied().unwrap_or(0.0);
+
+            parent
+                .spawn(Node {
+                    flex_direction: FlexDirection::Column,
+                    margin: UiRect::bottom(Val::Px(10.0)),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent.spawn(Text::new(format!(
+                        "{outcome} ({change:+.0}) - {} - {}",
+                        format_duration(result.duration),
+                        time_ago(record.played_at)
+                    )));
+                    parent.spawn(Text::new(format!(
+                        "{champion}: {}/{}/{}, {} gold",
+                        score(stats.kills),
+                        score(stats.deaths),
+                        score(stats.assists),
+                        score(stats.gold)
+                    )));
+                });
+        }
+    });
+}
@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use protocol::{MessageFromPlayer, QueueMode};
+
+use crate::ui::{create_modal, CloseModal};
+
+use super::SendMessage;
+
+pub fn queue(app: &mut App) {
+    app.add_systems(Update, update_queue_timer);
+}
+
+/// The queue we are waiting in, if any.
+#[derive(Resource)]
+pub struct InQueue {
+    mode: QueueMode,
+    joined: Instant,
+    expected_wait: Option<Duration>,
+}
+
+#[derive(Component)]
+struct QueueTimer;
+
+/// A button for every queue mode, for the lobby browser.
+pub fn build_queue_buttons(parent: &mut ChildBuilder) {
+    for mode in QueueMode::ALL {
+        parent
+            .spawn((Button, Text::new(format!("[Find {mode} Match]"))))
+            .observe(
+                move |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                    trigger.propagate(false);
+                    send.send_from(trigger.entity(), MessageFromPlayer::JoinQueue { mode });
+                },
+            );
+    }
+}
+
+/// Shows that we are waiting for a match, until we leave the queue or join a lobby.
+pub fn show_queue_status(
+    commands: &mut Commands,
+    mode: QueueMode,
+    expected_wait: Option<Duration>,
+    waited: Duration,
+) {
+    commands.insert_resource(InQueue {
+        mode,
+        joined: Instant::now() - waited,
+        expected_wait,
+    });
+
+    // We might be told again after resuming the session
+    commands.queue(CloseModal);
+    create_modal(commands, "Finding Match", false, |parent| {
+        parent.spawn(Text::new(format!("Searching for a {mode} match...")));
+        parent.spawn((Text::new(""), QueueTimer));
+        parent.spawn((Button, Text::new("[Leave Queue]"))).observe(
+            |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                trigger.propagate(false);
+                send.send_from(trigger.entity(), MessageFromPlayer::LeaveQueue);
+            },
+        );
+    });
+}
+
+pub fn hide_queue_status(commands: &mut Commands) {
+    commands.remove_resource::<InQueue>();
+    commands.queue(CloseModal);
+}
+
+/// Formats a duration as minutes and seconds.
+pub(super) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn update_queue_timer(in_queue: Option<Res<InQueue>>, mut q: Query<&mut Text, With<QueueTimer>>) {
+    let Some(in_queue) = in_queue else {
+        return;
+    };
+    let waited = format_duration(in_queue.joined.elapsed());
+    let expected = match in_queue.expected_wait {
+        Some(expected) => format!("expected {}", format_duration(expected)),
+        None => format!("no estimate for {} yet", in_queue.mode),
+    };
+    for mut text in &mut q {
+        text.0 = format!("{waited} ({expected})");
+    }
+}
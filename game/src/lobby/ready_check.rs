@@ -0,0 +1,106 @@
+use std::time::Instant;
+
+use bevy::prelude::*;
+use protocol::{LobbyState, MessageFromPlayer};
+use uuid::Uuid;
+
+use super::{LobbyBuildingContext, SendMessage};
+
+pub fn ready_check(app: &mut App) {
+    app.add_systems(Update, update_countdown);
+}
+
+/// When the current ready check runs out.
+///
+/// Kept outside of the UI, as the UI is rebuilt every time someone accepts.
+#[derive(Resource)]
+struct ReadyCheckDeadline {
+    id: Uuid,
+    deadline: Instant,
+}
+
+#[derive(Component)]
+struct ReadyCheckCountdown;
+
+pub fn build_ready_check(ctx: &LobbyBuildingContext, parent: &mut ChildBuilder) {
+    let LobbyState::ReadyCheck(state) = &ctx.lobby.lobby_state else {
+        unreachable!()
+    };
+
+    let (id, duration) = (state.id, state.duration);
+    parent.enqueue_command(move |world: &mut World| {
+        if world
+            .get_resource::<ReadyCheckDeadline>()
+            .is_none_or(|d| d.id != id)
+        {
+            world.insert_resource(ReadyCheckDeadline {
+                id,
+                deadline: Instant::now() + duration,
+            });
+        }
+    });
+
+    parent
+        .spawn(Node {
+            width: Val::Percent(100.0),
+            flex_direction: FlexDirection::Column,
+            align_items: AlignItems::Center,
+            row_gap: Val::Px(10.0),
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn(Text::new("Ready check"));
+            parent.spawn((Text::new(""), ReadyCheckCountdown));
+
+            // Who has accepted so far
+            for player in ctx.lobby.players.values().flatten() {
+                let name = &ctx.player_cache.players.get(player).unwrap().name;
+                let status = match state.accepted.contains(player) {
+                    true => "Accepted",
+                    false => "Waiting...",
+                };
+                parent.spawn(Text::new(format!("{name}: {status}")));
+            }
+
+            if !state.accepted.contains(&ctx.my_id) {
+                parent
+                    .spawn(Node {
+                        column_gap: Val::Px(20.0),
+                        ..default()
+                    })
+                    .with_children(|parent| {
+                        parent.spawn((Button, Text::new("[Accept]"))).observe(
+                            |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                                trigger.propagate(false);
+                                send.send_from(
+                                    trigger.entity(),
+                                    MessageFromPlayer::AcceptReadyCheck,
+                                );
+                            },
+                        );
+                        parent.spawn((Button, Text::new("[Decline]"))).observe(
+                            |mut trigger: Trigger<Pointer<Click>>, send: Res<SendMessage>| {
+                                trigger.propagate(false);
+                                send.send_from(
+                                    trigger.entity(),
+                                    MessageFromPlayer::DeclineReadyCheck,
+                                );
+                            },
+                        );
+                    });
+            }
+        });
+}
+
+fn update_countdown(
+    deadline: Option<Res<ReadyCheckDeadline>>,
+    mut q: Query<&mut Text, With<ReadyCheckCountdown>>,
+) {
+    let Some(deadline) = deadline else {
+        return;
+    };
+    let remaining = deadline.deadline.saturating_duration_since(Instant::now());
+    for mut text in &mut q {
+        text.0 = format!("{}s left", remaining.as_secs_f32().ceil());
+    }
+}
@@ -4,7 +4,11 @@ use std::{
     time::Duration,
 };
 
-use crate::{lobby::SendMessage, ui::CommandModalExt, FlattenResult, State};
+use crate::{
+    lobby::{error_text, SendMessage},
+    ui::{build_password_edit, CommandModalExt},
+    FlattenResult, State,
+};
 use bevy::prelude::*;
 use bevy_cosmic_edit::{
     cosmic_text::{Attrs, AttrsOwned, BufferRef, Color as CosmicColor, Edit, Family, Metrics},
@@ -14,14 +18,19 @@ use bevy_cosmic_edit::{
     CosmicWrap, FocusedWidget, MaxLines, ScrollEnabled,
 };
 use bevy_tokio_tasks::TokioTasksRuntime;
-use lobby_server::{
-    MessageFromPlayer, MessageFromServer, PlayerId, ReadMessage, WriteMessage as _,
+use protocol::{
+    CodecError, Credentials, MessageFromPlayer, MessageFromServer, PlayerId, PlayerRequest,
+    ReadMessage, RequestId, ResumeToken, ServerMessage, WriteMessage as _,
+};
+use wtransport::{
+    config::Ipv6DualStackConfig, tls::Sha256Digest, ClientConfig, Connection, Endpoint, RecvStream,
+    SendStream,
 };
-use wtransport::{config::Ipv6DualStackConfig, ClientConfig, Connection, Endpoint};
 
 pub fn login(app: &mut App) {
     app.add_sub_state::<LoginState>();
     app.enable_state_scoped_entities::<LoginState>();
+    app.init_resource::<LoginMode>();
 
     app.add_event::<ConnectionSuccessful>();
     app.add_event::<ConnectionFailed>();
@@ -51,20 +60,78 @@ pub struct LoginName(pub String);
 #[derive(Resource)]
 struct LoginServer(String);
 
+/// How we check the certificate of the lobby server.
+#[derive(Resource, Clone, Debug)]
+pub enum CertificateValidation {
+    /// Trust the certificate authorities of the system.
+    NativeCerts,
+    /// Accept only certificates with one of these SHA-256 hashes, like WebTransport's
+    /// `serverCertificateHashes`. The lobby server prints the hash of its certificate on startup.
+    Pinned(Vec<Sha256Digest>),
+    /// Accept any certificate, for local development only.
+    Disabled,
+}
+
+impl CertificateValidation {
+    fn client_config(&self) -> ClientConfig {
+        let builder = ClientConfig::builder().with_bind_address_v6(
+            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0),
+            Ipv6DualStackConfig::Allow,
+        );
+        let builder = match self {
+            CertificateValidation::NativeCerts => builder.with_native_certs(),
+            CertificateValidation::Pinned(hashes) => {
+                builder.with_server_certificate_hashes(hashes.clone())
+            }
+            CertificateValidation::Disabled => builder.with_no_cert_validation(),
+        };
+        builder.build()
+    }
+}
+
+/// Whether the login screen signs in to an existing account or registers a new one.
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Default)]
+enum LoginMode {
+    #[default]
+    SignIn,
+    Register,
+}
+
+impl LoginMode {
+    fn label(self) -> &'static str {
+        match self {
+            LoginMode::SignIn => "[Mode: Sign in]",
+            LoginMode::Register => "[Mode: Register]",
+        }
+    }
+}
+
+/// Only kept until the connection attempt starts, so the password doesn't linger.
+#[derive(Resource)]
+struct LoginCredentials(Credentials);
+
 #[derive(Event)]
 struct ConnectionSuccessful;
 #[derive(Event)]
-struct ConnectionFailed;
+struct ConnectionFailed(String);
 
 #[derive(Resource)]
 pub struct MyPlayerId(pub PlayerId);
 
 #[derive(Resource)]
-pub struct LobbyConnection(pub Connection);
+pub struct LobbyConnection {
+    pub conn: Connection,
+    /// The session stream, taken by the lobby when it starts its send and receive tasks.
+    pub streams: Option<(SendStream, RecvStream)>,
+    /// The address the connection was made to, for resuming the session.
+    pub server: String,
+    pub validation: CertificateValidation,
+    pub resume_token: ResumeToken,
+}
 
 enum ConnectionEvent {
-    ConnectionSuccessful(Connection, PlayerId),
-    ConnectionFailed,
+    ConnectionSuccessful(LobbyConnection, PlayerId),
+    ConnectionFailed(String),
 }
 
 #[derive(Resource)]
@@ -99,6 +166,7 @@ fn build_ui_root<'a>(state: LoginState, commands: &'a mut Commands) -> EntityCom
 fn setup_ui(
     login_name: Option<Res<LoginName>>,
     login_server: Option<Res<LoginServer>>,
+    login_mode: Res<LoginMode>,
     mut font_system: ResMut<CosmicFontSystem>,
     mut commands: Commands,
 ) {
@@ -111,6 +179,7 @@ fn setup_ui(
 
     let mut server_field = Entity::PLACEHOLDER;
     let mut username_field = Entity::PLACEHOLDER;
+    let mut password_field = Entity::PLACEHOLDER;
 
     let mut attrs = Attrs::new().metrics(Metrics::new(16.0, 16.0));
     attrs = attrs.family(Family::Name("Fira Mono"));
@@ -160,6 +229,26 @@ fn setup_ui(
                     .observe(focus_on_click)
                     .id();
             });
+            parent.spawn(Node { ..default() }).with_children(|parent| {
+                parent.spawn(Text::new("Password: "));
+                password_field = build_password_edit(parent, &mut font_system);
+            });
+            parent
+                .spawn((Button, Text::new(login_mode.label())))
+                .observe(
+                    |mut trigger: Trigger<Pointer<Click>>,
+                     mut mode: ResMut<LoginMode>,
+                     mut text: Query<&mut Text>| {
+                        trigger.propagate(false);
+                        *mode = match *mode {
+                            LoginMode::SignIn => LoginMode::Register,
+                            LoginMode::Register => LoginMode::SignIn,
+                        };
+                        if let Ok(mut text) = text.get_mut(trigger.entity()) {
+                            text.0 = mode.label().into();
+                        }
+                    },
+                );
             parent
                 .spawn((Button, ))
                 .with_child(Text::new("Connect"))
@@ -167,6 +256,7 @@ fn setup_ui(
                     move |mut trigger: Trigger<Pointer<Click>>,
                           q: Query<&CosmicEditBuffer>,
                           q2: Query<&CosmicEditor>,
+                          mode: Res<LoginMode>,
                           mut next_state: ResMut<NextState<LoginState>>,
                           mut commands: Commands| {
                         trigger.propagate(false);
@@ -185,7 +275,13 @@ fn setup_ui(
 
                         info!("Server: {}", get_text(server_field));
                         info!("Username: {}", get_text(username_field));
+                        let name = get_text(username_field);
+                        let password = get_text(password_field);
                         next_state.set(LoginState::Connecting);
+                        commands.insert_resource(LoginCredentials(match *mode {
+                            LoginMode::SignIn => Credentials::Login { name, password },
+                            LoginMode::Register => Credentials::Register { name, password },
+                        }));
                         commands.insert_resource(LoginName(
                             get_text(username_field),
                         ));
@@ -224,12 +320,12 @@ fn wait_for_connection_event(
 ) {
     match event_reader.0.try_recv() {
         Ok(ev) => match ev {
-            ConnectionEvent::ConnectionSuccessful(conn, player_id) => {
+            ConnectionEvent::ConnectionSuccessful(connection, player_id) => {
                 commands.insert_resource(MyPlayerId(player_id));
-                commands.insert_resource(LobbyConnection(conn));
+                commands.insert_resource(connection);
                 commands.trigger(ConnectionSuccessful)
             }
-            ConnectionEvent::ConnectionFailed => commands.trigger(ConnectionFailed),
+            ConnectionEvent::ConnectionFailed(reason) => commands.trigger(ConnectionFailed(reason)),
         },
         Err(TryRecvError::Empty) => {}
         Err(_) => todo!(),
@@ -244,17 +340,18 @@ fn on_successful_connection(
 }
 
 fn on_failed_connection(
-    _: Trigger<ConnectionFailed>,
+    trigger: Trigger<ConnectionFailed>,
     mut next_state: ResMut<NextState<LoginState>>,
     mut commands: Commands,
 ) {
     next_state.set(LoginState::Login);
-    commands.info("Connection failed");
+    commands.info(format!("Connection failed:\n{}", trigger.event().0));
 }
 
 fn setup_connecting(
     server: Res<LoginServer>,
-    username: Res<LoginName>,
+    validation: Res<CertificateValidation>,
+    credentials: Res<LoginCredentials>,
     runtime: Res<TokioTasksRuntime>,
     mut commands: Commands,
 ) {
@@ -270,7 +367,9 @@ fn setup_connecting(
     });
 
     let server = server.0.clone();
-    let username = username.0.clone();
+    let validation = validation.clone();
+    let credentials = credentials.0.clone();
+    commands.remove_resource::<LoginCredentials>();
 
     let default_port = 54765;
 
@@ -298,48 +397,65 @@ fn setup_connecting(
     });
 
     runtime.spawn_background_task(|_ctx| async move {
-        match tokio::time::timeout(Duration::from_secs(30), try_connect(server, username))
+        let connect = try_connect(server, validation, credentials);
+        match tokio::time::timeout(Duration::from_secs(30), connect)
             .await
             .flatten2()
         {
-            Ok((conn, player_id)) => {
+            Ok((connection, player_id)) => {
                 info!("Connected!");
-                let _ = send.send(ConnectionEvent::ConnectionSuccessful(conn, player_id));
+                let _ = send.send(ConnectionEvent::ConnectionSuccessful(connection, player_id));
             }
             Err(e) => {
                 info!("Connection failed: {e}");
-                let _ = send.send(ConnectionEvent::ConnectionFailed);
+                let _ = send.send(ConnectionEvent::ConnectionFailed(e.to_string()));
             }
         }
     });
 }
 
-async fn try_connect(addr: String, name: String) -> anyhow::Result<(Connection, PlayerId)> {
+pub async fn try_connect(
+    addr: String,
+    validation: CertificateValidation,
+    credentials: Credentials,
+) -> anyhow::Result<(LobbyConnection, PlayerId)> {
     println!("Building endpoint");
-    let client = Endpoint::client(
-        ClientConfig::builder()
-            .with_bind_address_v6(
-                SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0),
-                Ipv6DualStackConfig::Allow,
-            )
-            .with_no_cert_validation()
-            .build(),
-    )?;
+    let client = Endpoint::client(validation.client_config())?;
     println!("Connecting...");
-    let conn = client.connect(addr).await?;
+    let conn = client.connect(&addr).await?;
     println!("Connected...");
+    // The whole session runs over this one stream
+    let (mut send_stream, mut recv_stream) = conn.open_bi().await?.await?;
     println!("Sending handshake...");
     // Initiate handshake
-    conn.open_uni()
-        .await?
-        .await?
-        .write_message(MessageFromPlayer::InitialHandshake { name })
+    send_stream
+        .write_message(PlayerRequest {
+            id: RequestId(0),
+            message: MessageFromPlayer::InitialHandshake { credentials },
+        })
         .await?;
     println!("Waiting for id...");
-    let id = conn.accept_uni().await?.read_message().await?;
-    let id = match id {
-        MessageFromServer::InitialHandshakeResponse { id } => id,
+    // A server on another version answers with a bare header carrying its version
+    let ServerMessage { message, .. } = match recv_stream.read_message().await {
+        Ok(message) => message,
+        Err(e) => match e.downcast_ref() {
+            Some(CodecError::VersionMismatch { local, remote }) => anyhow::bail!(
+                "Server speaks protocol version {remote}, but this client speaks version {local}.\nPlease update your game."
+            ),
+            _ => return Err(e),
+        },
+    };
+    let (id, resume_token) = match message {
+        MessageFromServer::InitialHandshakeResponse { id, resume_token } => (id, resume_token),
+        MessageFromServer::RequestRefused(error) => anyhow::bail!("{}", error_text(&error)),
         _ => anyhow::bail!("Received invalid response from handshake"),
     };
-    Ok((conn, id))
+    let connection = LobbyConnection {
+        conn,
+        streams: Some((send_stream, recv_stream)),
+        server: addr,
+        validation,
+        resume_token,
+    };
+    Ok((connection, id))
 }
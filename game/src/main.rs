@@ -12,6 +12,7 @@ mod ui;
 use std::{
     collections::HashMap,
     net::{Ipv6Addr, SocketAddrV6},
+    path::PathBuf,
     time::Duration,
 };
 
@@ -19,35 +20,73 @@ use bevy::prelude::*;
 use bevy_cosmic_edit::CosmicEditPlugin;
 use bevy_tokio_tasks::TokioTasksPlugin;
 use clap::Parser;
+use engine::{champion::ChampionRegistry, map::MapRegistry, net::ProtocolPlugin};
 use game::network::build_client_plugin;
 use lightyear::prelude::{generate_key, ConnectToken};
 use lobby::{lobby, SendMessage};
-use lobby_server::{
+use login::{login, CertificateValidation, LobbyConnection, LoginName};
+use protocol::{
     ConnectTokenWrapper, MessageFromGameServerToLobby, MessageFromLobbyToGameServer, ReadMessage,
     WriteMessage,
 };
-use login::{login, LobbyConnection, LoginName};
 use tokio::io::AsyncWriteExt;
 use ui::ui;
 use uuid::Uuid;
-use wtransport::{config::Ipv6DualStackConfig, Endpoint, Identity, ServerConfig, VarInt};
+use wtransport::{
+    config::Ipv6DualStackConfig, tls::Sha256Digest, Endpoint, Identity, ServerConfig, VarInt,
+};
 
 #[derive(Debug, clap::Parser)]
 struct Options {
     name: Option<String>,
+    /// Send messages to the lobby server as JSON instead of binary, for debugging.
+    #[arg(long)]
+    debug_json: bool,
+    /// The game's data files, shared with the lobby and game servers.
+    #[arg(long, default_value = "assets")]
+    assets: PathBuf,
+    /// Only accept a lobby server whose certificate has this SHA-256 hash, as printed by the
+    /// lobby server. Can be given more than once.
+    #[arg(long = "server-cert-hash", conflicts_with = "no_cert_validation")]
+    server_cert_hashes: Vec<Sha256Digest>,
+    /// Accept any lobby server certificate, for local development only.
+    #[arg(long)]
+    no_cert_validation: bool,
 }
 
+/// Every champion in the game, loaded at startup.
+#[derive(Resource)]
+pub struct Champions(pub ChampionRegistry);
+
+/// Every map in the game, loaded at startup.
+#[derive(Resource)]
+pub struct Maps(pub MapRegistry);
+
 fn main() -> AppExit {
     let options = Options::parse();
+    protocol::set_json_debug(options.debug_json);
+
+    let champions = ChampionRegistry::load(options.assets.join("champions"))
+        .unwrap_or_else(|e| panic!("Could not load champions: {e:#}"));
+    let maps = MapRegistry::load(options.assets.join("maps"))
+        .unwrap_or_else(|e| panic!("Could not load maps: {e:#}"));
+    // Bevy resolves relative asset paths against the crate directory, not the working directory
+    let asset_dir = std::path::absolute(&options.assets).unwrap();
 
     let mut app = App::new();
 
     app.add_plugins((
-        DefaultPlugins,
+        DefaultPlugins.set(AssetPlugin {
+            file_path: asset_dir.to_string_lossy().into(),
+            ..default()
+        }),
         TokioTasksPlugin::default(),
         build_client_plugin(),
     ))
+    .add_plugins(ProtocolPlugin)
     .insert_state(State::Login)
+    .insert_resource(Champions(champions))
+    .insert_resource(Maps(maps))
     .add_plugins(ui)
     .add_plugins(login)
     .add_plugins(lobby)
@@ -58,6 +97,13 @@ fn main() -> AppExit {
     if let Some(name) = options.name {
         app.insert_resource(LoginName(name));
     }
+    app.insert_resource(if options.no_cert_validation {
+        CertificateValidation::Disabled
+    } else if !options.server_cert_hashes.is_empty() {
+        CertificateValidation::Pinned(options.server_cert_hashes)
+    } else {
+        CertificateValidation::NativeCerts
+    });
     app.run()
 }
 
@@ -65,7 +111,7 @@ fn listen_for_exit(event_reader: EventReader<AppExit>, send: Option<Res<SendMess
     if !event_reader.is_empty()
         && let Some(send) = send
     {
-        let _ = send.send(lobby_server::MessageFromPlayer::Disconnecting);
+        let _ = send.send(protocol::MessageFromPlayer::Disconnecting);
     }
 }
 
@@ -240,6 +240,20 @@ pub fn build_textedit(
     parent: &mut ChildBuilder,
     initial: impl AsRef<str>,
     font_system: &mut FontSystem,
+) -> Entity {
+    spawn_textedit(parent, initial, font_system, ())
+}
+
+/// Like [`build_textedit`], but shows a glyph for every character typed instead of the text.
+pub fn build_password_edit(parent: &mut ChildBuilder, font_system: &mut FontSystem) -> Entity {
+    spawn_textedit(parent, "", font_system, Password::default())
+}
+
+fn spawn_textedit(
+    parent: &mut ChildBuilder,
+    initial: impl AsRef<str>,
+    font_system: &mut FontSystem,
+    extra: impl Bundle,
 ) -> Entity {
     let mut attrs = Attrs::new().metrics(Metrics::new(16.0, 16.0));
     attrs = attrs.family(Family::Name("Fira Mono"));
@@ -261,6 +275,7 @@ pub fn build_textedit(
             },
             CosmicBackgroundColor(Color::srgb(0.1, 0.1, 0.1)),
             CursorColor(Color::WHITE),
+            extra,
         ))
         .observe(focus_on_click)
         .id()
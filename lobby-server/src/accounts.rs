@@ -0,0 +1,838 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use protocol::{
+    LobbyError, MatchRecord, MatchResult, PlayerId, PlayerInfo, PlayerMatchStats, PlayerProfile,
+    Team,
+};
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use uuid::Uuid;
+
+const MAX_NAME_LEN: usize = 24;
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// The rating every player starts out with.
+pub const DEFAULT_RATING: f64 = 1500.0;
+
+/// How far a single match can move a player's rating.
+const K_FACTOR: f64 = 32.0;
+
+/// How many wrong passwords a name may be tried with from one address before
+/// [`AccountStore::login`] refuses it from there for [`LOGIN_LOCKOUT`].
+const MAX_FAILED_LOGINS: u32 = 5;
+const LOGIN_LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How many matches [`AccountStore::match_history`] returns.
+const MATCH_HISTORY_LEN: u32 = 20;
+
+/// A logged in player, along with what the server needs to know about it.
+pub struct Account {
+    pub player: PlayerInfo,
+    pub rating: f64,
+    pub friends: Vec<PlayerInfo>,
+    /// Players whose friend requests haven't been answered yet.
+    pub friend_requests: Vec<PlayerInfo>,
+}
+
+/// What [`AccountStore::send_friend_request`] did.
+pub enum FriendRequestOutcome {
+    /// The request is stored until the other player answers it.
+    Sent(PlayerInfo),
+    /// The other player had already sent a request, so the two are friends now.
+    Accepted(PlayerInfo),
+}
+
+/// Player accounts, their friends and their match history, persisted in a SQLite database.
+///
+/// All operations block, and password hashing is deliberately slow,
+/// so this should only be used from [`tokio::task::spawn_blocking`].
+pub struct AccountStore {
+    db: Connection,
+    /// Hash checked against when logging in with a name that has no account,
+    /// so that takes as long as a wrong password.
+    dummy_hash: String,
+    /// Failed logins by lowercased name, whether or not it has an account, and the address
+    /// they came from, so nobody can lock a player out of their own account.
+    failed_logins: HashMap<(String, IpAddr), FailedLogins>,
+}
+
+struct FailedLogins {
+    count: u32,
+    /// When the first of them failed.
+    since: Instant,
+}
+
+impl AccountStore {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let db = Connection::open(path)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS accounts (
+                id TEXT PRIMARY KEY NOT NULL,
+                name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+                password_hash TEXT NOT NULL
+            );",
+        )?;
+
+        // Databases from before match history existed are upgraded in place
+        let version: u32 = db.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version < 1 {
+            db.execute_batch(&format!(
+                "BEGIN;
+                ALTER TABLE accounts ADD COLUMN rating REAL NOT NULL DEFAULT {DEFAULT_RATING};
+                CREATE TABLE matches (
+                    id TEXT PRIMARY KEY NOT NULL,
+                    played_at INTEGER NOT NULL,
+                    duration_secs REAL NOT NULL,
+                    winner INTEGER
+                );
+                CREATE TABLE match_players (
+                    match_id TEXT NOT NULL REFERENCES matches (id),
+                    player_id TEXT NOT NULL REFERENCES accounts (id),
+                    team INTEGER NOT NULL,
+                    champion TEXT NOT NULL,
+                    kills INTEGER NOT NULL,
+                    deaths INTEGER NOT NULL,
+                    assists INTEGER NOT NULL,
+                    gold INTEGER NOT NULL,
+                    rating_change REAL NOT NULL,
+                    PRIMARY KEY (match_id, player_id)
+                );
+                CREATE INDEX match_players_by_player ON match_players (player_id);
+                PRAGMA user_version = 1;
+                COMMIT;"
+            ))?;
+        }
+        if version < 2 {
+            // Friendships are stored in both directions
+            db.execute_batch(
+                "BEGIN;
+                CREATE TABLE friends (
+                    player_id TEXT NOT NULL REFERENCES accounts (id),
+                    friend_id TEXT NOT NULL REFERENCES accounts (id),
+                    PRIMARY KEY (player_id, friend_id)
+                );
+                CREATE TABLE friend_requests (
+                    from_id TEXT NOT NULL REFERENCES accounts (id),
+                    to_id TEXT NOT NULL REFERENCES accounts (id),
+                    PRIMARY KEY (from_id, to_id)
+                );
+                CREATE INDEX friend_requests_by_recipient ON friend_requests (to_id);
+                PRAGMA user_version = 2;
+                COMMIT;",
+            )?;
+        }
+        if version < 3 {
+            // SQLite can't drop NOT NULL from a column, so scores that aren't counted yet
+            // need the table rebuilt to be stored as NULL rather than 0
+            db.execute_batch(
+                "BEGIN;
+                ALTER TABLE matches ADD COLUMN forfeit INTEGER NOT NULL DEFAULT 0;
+                CREATE TABLE match_players_new (
+                    match_id TEXT NOT NULL REFERENCES matches (id),
+                    player_id TEXT NOT NULL REFERENCES accounts (id),
+                    team INTEGER NOT NULL,
+                    champion TEXT NOT NULL,
+                    kills INTEGER,
+                    deaths INTEGER,
+                    assists INTEGER,
+                    gold INTEGER,
+                    rating_change REAL NOT NULL,
+                    PRIMARY KEY (match_id, player_id)
+                );
+                INSERT INTO match_players_new SELECT * FROM match_players;
+                DROP TABLE match_players;
+                ALTER TABLE match_players_new RENAME TO match_players;
+                CREATE INDEX match_players_by_player ON match_players (player_id);
+                PRAGMA user_version = 3;
+                COMMIT;",
+            )?;
+        }
+        let dummy_hash = Argon2::default()
+            .hash_password(b"", &SaltString::generate(&mut OsRng))
+            .map_err(|e| anyhow::anyhow!("Could not hash password: {e}"))?
+            .to_string();
+        Ok(Self {
+            db,
+            dummy_hash,
+            failed_logins: HashMap::new(),
+        })
+    }
+
+    pub fn register(&self, name: &str, password: &str) -> Result<Account, LobbyError> {
+        let name = name.trim();
+        if name.is_empty()
+            || name.chars().count() > MAX_NAME_LEN
+            || name.chars().any(char::is_control)
+        {
+            return Err(LobbyError::InvalidName);
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(LobbyError::PasswordTooShort(MIN_PASSWORD_LEN));
+        }
+
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| {
+                println!("Failed to hash password: {e}");
+                LobbyError::AccountStoreFailed
+            })?
+            .to_string();
+
+        let id = PlayerId::new();
+        match self.db.execute(
+            "INSERT INTO accounts (id, name, password_hash) VALUES (?1, ?2, ?3)",
+            params![id.get().to_string(), name, hash],
+        ) {
+            Ok(_) => Ok(Account {
+                player: PlayerInfo {
+                    id,
+                    name: name.into(),
+                },
+                rating: DEFAULT_RATING,
+                friends: vec![],
+                friend_requests: vec![],
+            }),
+            // The only constraint that can fail here is the unique name
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(LobbyError::NameTaken)
+            }
+            Err(e) => Err(db_error(e)),
+        }
+    }
+
+    /// Logs in a player connecting from `address`.
+    pub fn login(
+        &mut self,
+        name: &str,
+        password: &str,
+        address: IpAddr,
+    ) -> Result<Account, LobbyError> {
+        // Names are case insensitive
+        let key = (name.trim().to_lowercase(), address);
+        self.failed_logins
+            .retain(|_, failed| failed.since.elapsed() < LOGIN_LOCKOUT);
+        if self
+            .failed_logins
+            .get(&key)
+            .is_some_and(|failed| failed.count >= MAX_FAILED_LOGINS)
+        {
+            return Err(LobbyError::TooManyLoginAttempts);
+        }
+
+        let result = self.check_credentials(name, password);
+        match &result {
+            Ok(_) => {
+                self.failed_logins.remove(&key);
+            }
+            Err(LobbyError::InvalidCredentials) => {
+                self.failed_logins
+                    .entry(key)
+                    .or_insert(FailedLogins {
+                        count: 0,
+                        since: Instant::now(),
+                    })
+                    .count += 1;
+            }
+            Err(_) => {}
+        }
+        result
+    }
+
+    fn check_credentials(&self, name: &str, password: &str) -> Result<Account, LobbyError> {
+        let account = self
+            .db
+            .query_row(
+                "SELECT id, name, password_hash, rating FROM accounts WHERE name = ?1",
+                params![name.trim()],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, f64>(3)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(db_error)?;
+
+        // Don't tell the client whether it was the name or the password that was wrong,
+        // not even by how long checking takes
+        let Some((id, name, hash, rating)) = account else {
+            let hash =
+                PasswordHash::new(&self.dummy_hash).expect("the dummy hash is generated by us");
+            let _ = Argon2::default().verify_password(password.as_bytes(), &hash);
+            return Err(LobbyError::InvalidCredentials);
+        };
+        let hash = PasswordHash::new(&hash).map_err(|e| {
+            println!("Invalid password hash stored for {name:?}: {e}");
+            LobbyError::AccountStoreFailed
+        })?;
+        if Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_err()
+        {
+            return Err(LobbyError::InvalidCredentials);
+        }
+
+        let id = Uuid::parse_str(&id).map_err(|e| {
+            println!("Invalid id stored for {name:?}: {e}");
+            LobbyError::AccountStoreFailed
+        })?;
+        let id = PlayerId::from(id);
+        let (friends, friend_requests) = self.friend_list(id)?;
+        Ok(Account {
+            player: PlayerInfo { id, name },
+            rating,
+            friends,
+            friend_requests,
+        })
+    }
+
+    /// The player's friends, and the players whose friend requests it hasn't answered yet.
+    pub fn friend_list(
+        &self,
+        id: PlayerId,
+    ) -> Result<(Vec<PlayerInfo>, Vec<PlayerInfo>), LobbyError> {
+        let query = |sql: &str| {
+            let mut statement = self.db.prepare(sql).map_err(db_error)?;
+            let players = statement
+                .query_map(params![id.get().to_string()], player_info)
+                .map_err(db_error)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(db_error);
+            players
+        };
+        let friends = query(
+            "SELECT a.id, a.name FROM friends f JOIN accounts a ON a.id = f.friend_id
+                WHERE f.player_id = ?1 ORDER BY a.name",
+        )?;
+        let requests = query(
+            "SELECT a.id, a.name FROM friend_requests r JOIN accounts a ON a.id = r.from_id
+                WHERE r.to_id = ?1 ORDER BY a.name",
+        )?;
+        Ok((friends, requests))
+    }
+
+    pub fn send_friend_request(
+        &mut self,
+        from: PlayerId,
+        name: &str,
+    ) -> Result<FriendRequestOutcome, LobbyError> {
+        let tx = self.db.transaction().map_err(db_error)?;
+        let to = tx
+            .query_row(
+                "SELECT id, name FROM accounts WHERE name = ?1",
+                params![name.trim()],
+                player_info,
+            )
+            .optional()
+            .map_err(db_error)?
+            .filter(|to| to.id != from)
+            .ok_or(LobbyError::PlayerNotFound)?;
+        let (from_text, to_text) = (from.get().to_string(), to.id.get().to_string());
+
+        let already_friends = tx
+            .query_row(
+                "SELECT 1 FROM friends WHERE player_id = ?1 AND friend_id = ?2",
+                params![from_text, to_text],
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(db_error)?
+            .is_some();
+        if already_friends {
+            return Err(LobbyError::AlreadyFriends);
+        }
+
+        // Two players asking each other is as good as accepting
+        let answered = tx
+            .execute(
+                "DELETE FROM friend_requests WHERE from_id = ?1 AND to_id = ?2",
+                params![to_text, from_text],
+            )
+            .map_err(db_error)?;
+        let outcome = if answered > 0 {
+            add_friends(&tx, from, to.id)?;
+            FriendRequestOutcome::Accepted(to)
+        } else {
+            tx.execute(
+                "INSERT OR IGNORE INTO friend_requests (from_id, to_id) VALUES (?1, ?2)",
+                params![from_text, to_text],
+            )
+            .map_err(db_error)?;
+            FriendRequestOutcome::Sent(to)
+        };
+        tx.commit().map_err(db_error)?;
+        Ok(outcome)
+    }
+
+    /// Accepts the friend request `from` sent to `player`, returning the new friend.
+    pub fn accept_friend_request(
+        &mut self,
+        player: PlayerId,
+        from: PlayerId,
+    ) -> Result<PlayerInfo, LobbyError> {
+        let tx = self.db.transaction().map_err(db_error)?;
+        let answered = tx
+            .execute(
+                "DELETE FROM friend_requests WHERE from_id = ?1 AND to_id = ?2",
+                params![from.get().to_string(), player.get().to_string()],
+            )
+            .map_err(db_error)?;
+        if answered == 0 {
+            return Err(LobbyError::FriendRequestNotFound);
+        }
+        add_friends(&tx, player, from)?;
+        let friend = tx
+            .query_row(
+                "SELECT id, name FROM accounts WHERE id = ?1",
+                params![from.get().to_string()],
+                player_info,
+            )
+            .map_err(db_error)?;
+        tx.commit().map_err(db_error)?;
+        Ok(friend)
+    }
+
+    /// Ends a friendship, or removes a friend request in either direction.
+    pub fn remove_friend(&mut self, player: PlayerId, other: PlayerId) -> Result<(), LobbyError> {
+        let (player, other) = (player.get().to_string(), other.get().to_string());
+        let tx = self.db.transaction().map_err(db_error)?;
+        let removed = tx
+            .execute(
+                "DELETE FROM friends
+                    WHERE (player_id = ?1 AND friend_id = ?2) OR (player_id = ?2 AND friend_id = ?1)",
+                params![player, other],
+            )
+            .map_err(db_error)?
+            + tx.execute(
+                "DELETE FROM friend_requests
+                    WHERE (from_id = ?1 AND to_id = ?2) OR (from_id = ?2 AND to_id = ?1)",
+                params![player, other],
+            )
+            .map_err(db_error)?;
+        if removed == 0 {
+            return Err(LobbyError::NotFriends);
+        }
+        tx.commit().map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Stores a finished match and, if it was `rated`, updates the ratings of its players,
+    /// returning their new ratings.
+    pub fn record_match(
+        &mut self,
+        result: &MatchResult,
+        rated: bool,
+    ) -> Result<HashMap<PlayerId, f64>, LobbyError> {
+        let tx = self.db.transaction().map_err(db_error)?;
+
+        let mut ratings = vec![];
+        for stats in &result.players {
+            let rating = tx
+                .query_row(
+                    "SELECT rating FROM accounts WHERE id = ?1",
+                    params![stats.player.id.get().to_string()],
+                    |row| row.get::<_, f64>(0),
+                )
+                .map_err(db_error)?;
+            ratings.push((stats.player.id, stats.team, rating));
+        }
+        let changes = match result.winner {
+            Some(winner) if rated => rating_changes(&ratings, winner),
+            _ => HashMap::new(),
+        };
+
+        let match_id = Uuid::new_v4().to_string();
+        let played_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        tx.execute(
+            "INSERT INTO matches (id, played_at, duration_secs, winner, forfeit)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                match_id,
+                played_at,
+                result.duration.as_secs_f64(),
+                result.winner.map(|t| t.0),
+                result.forfeit
+            ],
+        )
+        .map_err(db_error)?;
+
+        let mut new_ratings = HashMap::new();
+        for (stats, (id, _, rating)) in result.players.iter().zip(&ratings) {
+            let change = changes.get(id).copied().unwrap_or(0.0);
+            tx.execute(
+                "INSERT INTO match_players
+                    (match_id, player_id, team, champion, kills, deaths, assists, gold, rating_change)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    match_id,
+                    id.get().to_string(),
+                    stats.team.0,
+                    stats.champion,
+                    stats.kills,
+                    stats.deaths,
+                    stats.assists,
+                    stats.gold,
+                    change
+                ],
+            )
+            .map_err(db_error)?;
+            tx.execute(
+                "UPDATE accounts SET rating = ?1 WHERE id = ?2",
+                params![rating + change, id.get().to_string()],
+            )
+            .map_err(db_error)?;
+            new_ratings.insert(*id, rating + change);
+        }
+
+        tx.commit().map_err(db_error)?;
+        Ok(new_ratings)
+    }
+
+    pub fn profile(&self, id: PlayerId) -> Result<PlayerProfile, LobbyError> {
+        let id_text = id.get().to_string();
+        let (name, rating) = self
+            .db
+            .query_row(
+                "SELECT name, rating FROM accounts WHERE id = ?1",
+                params![id_text],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?)),
+            )
+            .optional()
+            .map_err(db_error)?
+            .ok_or(LobbyError::PlayerNotFound)?;
+        // Comparing against a missing winner gives NULL, which SUM skips
+        let (matches_played, wins, losses) = self
+            .db
+            .query_row(
+                "SELECT COUNT(*), COALESCE(SUM(m.winner = p.team), 0), COALESCE(SUM(m.winner != p.team), 0)
+                    FROM match_players p JOIN matches m ON m.id = p.match_id
+                    WHERE p.player_id = ?1",
+                params![id_text],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .map_err(db_error)?;
+        Ok(PlayerProfile {
+            player: PlayerInfo { id, name },
+            rating,
+            matches_played,
+            wins,
+            losses,
+        })
+    }
+
+    /// The player's most recent matches, most recent first.
+    pub fn match_history(&self, id: PlayerId) -> Result<Vec<MatchRecord>, LobbyError> {
+        let mut matches_query = self
+            .db
+            .prepare(
+                "SELECT m.id, m.played_at, m.duration_secs, m.winner, m.forfeit
+                    FROM matches m JOIN match_players p ON p.match_id = m.id
+                    WHERE p.player_id = ?1
+                    ORDER BY m.played_at DESC
+                    LIMIT ?2",
+            )
+            .map_err(db_error)?;
+        let mut players_query = self
+            .db
+            .prepare(
+                "SELECT p.player_id, a.name, p.team, p.champion,
+                        p.kills, p.deaths, p.assists, p.gold, p.rating_change
+                    FROM match_players p JOIN accounts a ON a.id = p.player_id
+                    WHERE p.match_id = ?1",
+            )
+            .map_err(db_error)?;
+
+        let matches = matches_query
+            .query_map(params![id.get().to_string(), MATCH_HISTORY_LEN], |row| {
+                Ok((
+                    get_uuid(row, 0)?,
+                    row.get::<_, u64>(1)?,
+                    row.get::<_, f64>(2)?,
+                    row.get::<_, Option<usize>>(3)?,
+                    row.get::<_, bool>(4)?,
+                ))
+            })
+            .map_err(db_error)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(db_error)?;
+
+        let mut records = vec![];
+        for (match_id, played_at, duration_secs, winner, forfeit) in matches {
+            let mut players = vec![];
+            let mut rating_changes = HashMap::new();
+            let rows = players_query
+                .query_map(params![match_id.to_string()], |row| {
+                    Ok((
+                        PlayerMatchStats {
+                            player: PlayerInfo {
+                                id: PlayerId::from(get_uuid(row, 0)?),
+                                name: row.get(1)?,
+                            },
+                            team: Team(row.get(2)?),
+                            champion: row.get(3)?,
+                            kills: row.get(4)?,
+                            deaths: row.get(5)?,
+                            assists: row.get(6)?,
+                            gold: row.get(7)?,
+                        },
+                        row.get::<_, f64>(8)?,
+                    ))
+                })
+                .map_err(db_error)?;
+            for row in rows {
+                let (stats, change) = row.map_err(db_error)?;
+                rating_changes.insert(stats.player.id, change);
+                players.push(stats);
+            }
+            records.push(MatchRecord {
+                id: match_id,
+                played_at,
+                result: MatchResult {
+                    winner: winner.map(Team),
+                    forfeit,
+                    duration: Duration::from_secs_f64(duration_secs),
+                    players,
+                },
+                rating_changes,
+            });
+        }
+        Ok(records)
+    }
+}
+
+/// The Elo rating change of every player in a match, from their ratings before it.
+///
+/// Every team plays against the average rating of the other teams,
+/// and everyone on a team gains or loses the same amount.
+fn rating_changes(players: &[(PlayerId, Team, f64)], winner: Team) -> HashMap<PlayerId, f64> {
+    let mut teams = HashMap::<Team, (f64, usize)>::new();
+    for (_, team, rating) in players {
+        let (sum, count) = teams.entry(*team).or_default();
+        *sum += rating;
+        *count += 1;
+    }
+    let averages = teams
+        .into_iter()
+        .map(|(team, (sum, count))| (team, sum / count as f64))
+        .collect::<HashMap<_, _>>();
+
+    let team_change = |team: Team| {
+        let others = averages
+            .iter()
+            .filter(|(t, _)| **t != team)
+            .map(|(_, rating)| *rating)
+            .collect::<Vec<_>>();
+        if others.is_empty() {
+            return 0.0;
+        }
+        let opponent = others.iter().sum::<f64>() / others.len() as f64;
+        let expected = 1.0 / (1.0 + 10f64.powf((opponent - averages[&team]) / 400.0));
+        let score = if team == winner { 1.0 } else { 0.0 };
+        K_FACTOR * (score - expected)
+    };
+
+    players
+        .iter()
+        .map(|(id, team, _)| (*id, team_change(*team)))
+        .collect()
+}
+
+fn add_friends(db: &Connection, a: PlayerId, b: PlayerId) -> Result<(), LobbyError> {
+    let (a, b) = (a.get().to_string(), b.get().to_string());
+    db.execute(
+        "INSERT OR IGNORE INTO friends (player_id, friend_id) VALUES (?1, ?2), (?2, ?1)",
+        params![a, b],
+    )
+    .map_err(db_error)?;
+    Ok(())
+}
+
+/// Reads a player from its `id` and `name` columns, in that order.
+fn player_info(row: &Row) -> rusqlite::Result<PlayerInfo> {
+    Ok(PlayerInfo {
+        id: PlayerId::from(get_uuid(row, 0)?),
+        name: row.get(1)?,
+    })
+}
+
+/// Reads an id, which is stored as text.
+fn get_uuid(row: &Row, index: usize) -> rusqlite::Result<Uuid> {
+    let text = row.get::<_, String>(index)?;
+    Uuid::parse_str(&text)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e)))
+}
+
+fn db_error(e: rusqlite::Error) -> LobbyError {
+    println!("Account database error: {e}");
+    LobbyError::AccountStoreFailed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn even_match_moves_half_the_k_factor() {
+        let (a, b, c, d) = (
+            PlayerId::new(),
+            PlayerId::new(),
+            PlayerId::new(),
+            PlayerId::new(),
+        );
+        let players = [
+            (a, Team(0), 1000.0),
+            (b, Team(0), 1000.0),
+            (c, Team(1), 1000.0),
+            (d, Team(1), 1000.0),
+        ];
+        let changes = rating_changes(&players, Team(0));
+
+        assert_eq!(changes[&a], K_FACTOR / 2.0);
+        assert_eq!(changes[&b], K_FACTOR / 2.0);
+        assert_eq!(changes[&c], -K_FACTOR / 2.0);
+        assert_eq!(changes[&d], -K_FACTOR / 2.0);
+    }
+
+    #[test]
+    fn upsets_move_ratings_further() {
+        let (strong, weak) = (PlayerId::new(), PlayerId::new());
+        let players = [(strong, Team(0), 1400.0), (weak, Team(1), 1000.0)];
+
+        let expected = rating_changes(&players, Team(0));
+        let upset = rating_changes(&players, Team(1));
+
+        assert!(expected[&strong] > 0.0 && expected[&strong] < K_FACTOR / 2.0);
+        assert!(upset[&weak] > K_FACTOR / 2.0 && upset[&weak] < K_FACTOR);
+        // Ratings are only moved between the teams
+        assert!((expected[&strong] + expected[&weak]).abs() < 1e-9);
+        assert!((upset[&strong] + upset[&weak]).abs() < 1e-9);
+    }
+
+    const ATTACKER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const OWNER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(198, 51, 100, 1));
+
+    #[test]
+    fn failed_logins_lock_the_name_out() {
+        let mut store = AccountStore::open(Path::new(":memory:")).unwrap();
+        store.register("Player", "correct horse").unwrap();
+
+        for _ in 0..MAX_FAILED_LOGINS {
+            assert!(matches!(
+                store.login("player", "wrong password", ATTACKER),
+                Err(LobbyError::InvalidCredentials)
+            ));
+        }
+        assert!(matches!(
+            store.login("Player", "correct horse", ATTACKER),
+            Err(LobbyError::TooManyLoginAttempts)
+        ));
+
+        // Names without an account are locked out the same way, so lockouts don't reveal them
+        for _ in 0..MAX_FAILED_LOGINS {
+            assert!(matches!(
+                store.login("Nobody", "wrong password", ATTACKER),
+                Err(LobbyError::InvalidCredentials)
+            ));
+        }
+        assert!(matches!(
+            store.login("Nobody", "wrong password", ATTACKER),
+            Err(LobbyError::TooManyLoginAttempts)
+        ));
+    }
+
+    #[test]
+    fn lockouts_leave_other_addresses_alone() {
+        let mut store = AccountStore::open(Path::new(":memory:")).unwrap();
+        store.register("Player", "correct horse").unwrap();
+
+        for _ in 0..MAX_FAILED_LOGINS {
+            let _ = store.login("Player", "wrong password", ATTACKER);
+        }
+
+        assert!(store.login("Player", "correct horse", OWNER).is_ok());
+    }
+
+    #[test]
+    fn lone_team_keeps_its_rating() {
+        let player = PlayerId::new();
+        let changes = rating_changes(&[(player, Team(0), 1200.0)], Team(0));
+
+        assert_eq!(changes[&player], 0.0);
+    }
+
+    /// A won match between two new accounts, and the store they are in.
+    fn one_on_one() -> (AccountStore, MatchResult) {
+        let store = AccountStore::open(Path::new(":memory:")).unwrap();
+        let players = [("winner", Team(0)), ("loser", Team(1))]
+            .map(|(name, team)| PlayerMatchStats {
+                player: store.register(name, "password").unwrap().player,
+                team,
+                champion: "aurelia".into(),
+                kills: None,
+                deaths: None,
+                assists: None,
+                gold: None,
+            })
+            .into();
+        let result = MatchResult {
+            winner: Some(Team(0)),
+            forfeit: false,
+            duration: Duration::from_secs(600),
+            players,
+        };
+        (store, result)
+    }
+
+    #[test]
+    fn rated_matches_move_ratings() {
+        let (mut store, result) = one_on_one();
+        let ratings = store.record_match(&result, true).unwrap();
+
+        let winner = store.profile(result.players[0].player.id).unwrap();
+        assert!(winner.rating > DEFAULT_RATING);
+        assert_eq!(ratings[&winner.player.id], winner.rating);
+    }
+
+    #[test]
+    fn unrated_matches_leave_ratings_alone() {
+        let (mut store, result) = one_on_one();
+        let ratings = store.record_match(&result, false).unwrap();
+
+        for stats in &result.players {
+            let profile = store.profile(stats.player.id).unwrap();
+            assert_eq!(profile.rating, DEFAULT_RATING);
+            assert_eq!(ratings[&stats.player.id], DEFAULT_RATING);
+            // The match still counts towards the history
+            assert_eq!(profile.matches_played, 1);
+        }
+    }
+
+    #[test]
+    fn match_history_keeps_untracked_scores_unknown() {
+        let (mut store, mut result) = one_on_one();
+        result.forfeit = true;
+        store.record_match(&result, false).unwrap();
+
+        let history = store.match_history(result.players[0].player.id).unwrap();
+        let recorded = &history[0].result;
+        assert!(recorded.forfeit);
+        assert!(recorded.players.iter().all(|stats| stats.kills.is_none()));
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use serde::Deserialize;
+use wtransport::{
+    config::Ipv6DualStackConfig,
+    tls::{Sha256Digest, Sha256DigestFmt},
+    Identity, ServerConfig,
+};
+
+const DEFAULT_PORT: u16 = 54765;
+
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 15;
+
+/// Where and how the lobby server accepts players.
+/// Every setting can be given in the `--config` file or as an option, and options take precedence.
+#[derive(Clone, Debug, Default, Deserialize, clap::Args)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Address to listen on [default: ::, which also accepts IPv4]
+    #[arg(long)]
+    pub bind_address: Option<IpAddr>,
+    /// Port to listen on [default: 54765]
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// PEM file with the TLS certificate chain. Without it, a self-signed certificate is
+    /// generated, which clients can only accept by its hash.
+    #[arg(long)]
+    pub certificate: Option<PathBuf>,
+    /// PEM file with the private key of the certificate.
+    #[arg(long)]
+    pub private_key: Option<PathBuf>,
+    /// How often to ping idle players so their connections don't time out; 0 to never ping
+    /// [default: 15]
+    #[arg(long)]
+    pub keep_alive_secs: Option<u64>,
+}
+
+impl NetworkConfig {
+    /// Reads a JSON config file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Fills in the settings missing from `self` with those of `other`.
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            bind_address: self.bind_address.or(other.bind_address),
+            port: self.port.or(other.port),
+            certificate: self.certificate.or(other.certificate),
+            private_key: self.private_key.or(other.private_key),
+            keep_alive_secs: self.keep_alive_secs.or(other.keep_alive_secs),
+        }
+    }
+
+    async fn identity(&self) -> anyhow::Result<Identity> {
+        match (&self.certificate, &self.private_key) {
+            (Some(certificate), Some(private_key)) => {
+                Identity::load_pemfiles(certificate, private_key)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Could not load {} and {}",
+                            certificate.display(),
+                            private_key.display()
+                        )
+                    })
+            }
+            (None, None) => Ok(Identity::self_signed(["localhost", "127.0.0.1", "::1"])?),
+            _ => anyhow::bail!("The certificate and private key must be given together"),
+        }
+    }
+
+    pub async fn server_config(&self) -> anyhow::Result<ServerConfig> {
+        let ip = self
+            .bind_address
+            .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        let port = self.port.unwrap_or(DEFAULT_PORT);
+        let identity = self.identity().await?;
+        println!(
+            "Certificate hash: {}",
+            certificate_hash(&identity).fmt(Sha256DigestFmt::DottedHex)
+        );
+
+        let builder = ServerConfig::builder();
+        let builder = match ip {
+            IpAddr::V6(ip) => builder.with_bind_address_v6(
+                SocketAddrV6::new(ip, port, 0, 0),
+                Ipv6DualStackConfig::Allow,
+            ),
+            IpAddr::V4(_) => builder.with_bind_address(SocketAddr::new(ip, port)),
+        };
+        let keep_alive = self.keep_alive_secs.unwrap_or(DEFAULT_KEEP_ALIVE_SECS);
+        Ok(builder
+            .with_identity(identity)
+            .keep_alive_interval((keep_alive > 0).then(|| Duration::from_secs(keep_alive)))
+            .build())
+    }
+}
+
+/// The hash clients can pin the certificate by.
+fn certificate_hash(identity: &Identity) -> Sha256Digest {
+    identity.certificate_chain().as_slice()[0].hash()
+}
@@ -1,41 +1,85 @@
-#![feature(decl_macro)]
 #![feature(try_blocks)]
 #![feature(never_type)]
 #![feature(never_type_fallback)]
 #![feature(new_range_api)]
-#![feature(async_closure)]
 
-use core::range::{Range, RangeInclusive};
+mod accounts;
+mod config;
+mod pool;
+mod ports;
+
+use core::range::RangeInclusive;
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet},
-    net::{Ipv6Addr, SocketAddrV6},
+    collections::{HashMap, HashSet, VecDeque},
     path::PathBuf,
-    process::{Command, ExitStatus},
-    sync::{Arc, Once},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
+use accounts::{Account, AccountStore, FriendRequestOutcome};
 use clap::Parser;
-use lobby_server::{
-    ChampSelectState, ChampionSelection, Lobby, LobbyId, LobbySettings, LobbyShortInfo, LobbyState,
-    MessageFromGameServerToLobby, MessageFromLobbyToGameServer, MessageFromPlayer,
-    MessageFromServer, PlayerId, PlayerInfo, PlayerSelection, ReadMessage as _, Team,
-    WriteMessage as _,
+use config::NetworkConfig;
+use engine::{champion::ChampionRegistry, map::MapRegistry};
+use pool::{secrets_match, GameServer, GameServerExit, GameServerPool, Location};
+use ports::{GameServerPorts, PortAllocator};
+use protocol::{
+    encode_message, encode_version_refusal, ChampSelectMode, ChampSelectState, ChatChannel,
+    CodecError, Credentials, DisbandReason, DraftState, DraftTurn, DraftTurnKind, Friend,
+    InviteCode, Lobby, LobbyAccess, LobbyError, LobbyFilter, LobbyId, LobbyListCursor,
+    LobbyListQuery, LobbySettings, LobbyShortInfo, LobbySort, LobbyState, LobbyStateKind,
+    LobbyUpdate, MatchResult, MessageFromGameServerToLobby, MessageFromLobbyToGameServer,
+    MessageFromPlayer, MessageFromServer, Party, PartyId, PlayerId, PlayerInfo, PlayerRequest,
+    PlayerSelection, Presence, QueueMode, ReadMessage as _, ReadyCheckState, RequestId,
+    ResumeToken, ServerMessage, Team, WriteMessage as _, AGENT_PATH,
 };
+use rand::seq::SliceRandom as _;
 use regex::{Regex, RegexBuilder};
 use tokio::task::{JoinHandle, JoinSet};
 use uuid::Uuid;
-use wtransport::{
-    config::Ipv6DualStackConfig, ClientConfig, Connection, Endpoint, Identity, ServerConfig,
-};
+use wtransport::{Connection, Endpoint, RecvStream, SendStream, ServerConfig, VarInt};
 
 #[derive(clap::Parser)]
 struct Options {
     game_server_launch_mode: GameServerLaunchMode,
     game_server_path: PathBuf,
+    /// Ports for game servers, two for each: one the lobby server controls it through,
+    /// and one players connect to.
     #[arg(value_parser = parse_port_range)]
     game_server_port_range: RangeInclusive<u16>,
+    /// Send messages as pretty-printed JSON instead of binary, for debugging.
+    #[arg(long)]
+    debug_json: bool,
+    /// SQLite database holding player accounts; created if it doesn't exist.
+    #[arg(long, default_value = "accounts.db")]
+    database: PathBuf,
+    /// How many seconds a player's lobby slot is held after losing connection,
+    /// waiting for the player to resume the session.
+    #[arg(long, default_value_t = 60)]
+    reconnect_grace_period: u64,
+    /// File with words to censor in chat, one per line.
+    #[arg(long)]
+    chat_filter: Option<PathBuf>,
+    /// The game's data files, shared with the game server and client.
+    #[arg(long, default_value = "assets")]
+    assets: PathBuf,
+    /// How many idle game servers to keep running, so matches start without waiting for one.
+    /// Idle game servers of agents count too.
+    #[arg(long, default_value_t = 2)]
+    game_server_pool_size: usize,
+    /// Lets game server agents on other machines register with this secret;
+    /// without it, agents are refused.
+    #[arg(long)]
+    agent_secret: Option<String>,
+    /// Where this machine is. Of equally busy game servers, those of agents in the same region
+    /// are preferred, like the ones we run ourselves.
+    #[arg(long, default_value = "default")]
+    region: String,
+    /// JSON file with network settings, named like the options below.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    network: NetworkConfig,
 }
 
 fn parse_port_range(arg: &str) -> anyhow::Result<RangeInclusive<u16>> {
@@ -68,43 +112,485 @@ fn parse_port_range(arg: &str) -> anyhow::Result<RangeInclusive<u16>> {
 enum GameServerLaunchMode {
     Executable,
     Cargo,
+    /// Runs every game server on a thread of the lobby server, ignoring the game server path.
+    #[cfg(feature = "in-process")]
+    InProcess,
 }
 
 #[tokio::main]
 async fn main() {
     let options = Options::parse();
+    protocol::set_json_debug(options.debug_json);
+
+    let accounts = AccountStore::open(&options.database).unwrap_or_else(|e| {
+        panic!(
+            "Could not open account database {}: {e}",
+            options.database.display()
+        )
+    });
+
+    let chat_filter = options.chat_filter.as_ref().map(|path| {
+        let words = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Could not read chat filter {}: {e}", path.display()));
+        build_chat_filter(&words)
+    });
+
+    let champions_dir = options.assets.join("champions");
+    let champions = ChampionRegistry::load(&champions_dir).unwrap_or_else(|e| {
+        panic!(
+            "Could not load champions from {}: {e:#}",
+            champions_dir.display()
+        )
+    });
+
+    let maps_dir = options.assets.join("maps");
+    let maps = MapRegistry::load(&maps_dir)
+        .unwrap_or_else(|e| panic!("Could not load maps from {}: {e:#}", maps_dir.display()));
+    assert!(
+        maps.get(DEFAULT_MAP).is_some(),
+        "{} has no {DEFAULT_MAP:?} map for new lobbies to start out with",
+        maps_dir.display()
+    );
+
+    let mut network = options.network.clone();
+    if let Some(path) = &options.config {
+        let file = NetworkConfig::load(path)
+            .unwrap_or_else(|e| panic!("Could not read config {}: {e:#}", path.display()));
+        network = network.or(file);
+    }
+    let server_config = network
+        .server_config()
+        .await
+        .unwrap_or_else(|e| panic!("Could not set up the server: {e:#}"));
+
+    ServerState::new(options, accounts, chat_filter, champions, maps)
+        .run(server_config)
+        .await;
+}
+
+/// Builds a regex matching any of the words in `words`, one per line.
+fn build_chat_filter(words: &str) -> Regex {
+    let words = words
+        .lines()
+        .map(str::trim)
+        .filter(|w| !w.is_empty())
+        .map(regex::escape)
+        .collect::<Vec<_>>();
+    RegexBuilder::new(&format!(r"\b(?:{})\b", words.join("|")))
+        .case_insensitive(true)
+        .build()
+        .unwrap()
+}
+
+const MAX_CHAT_MESSAGE_LEN: usize = 500;
+
+const MAX_INVITE_CODES: usize = 16;
+
+const MAX_PARTY_SIZE: usize = 5;
+
+const LOBBY_LIST_PAGE_SIZE: usize = 50;
+
+/// How long players have to accept a ready check.
+const READY_CHECK_DURATION: Duration = Duration::from_secs(15);
+
+/// The map new lobbies start out with.
+const DEFAULT_MAP: &str = "Default";
+
+/// How long players have to pick a champion, in custom lobbies until the leader changes it.
+const DEFAULT_PICK_TIME_SECS: u64 = 60;
+
+/// How often the queues are searched for matches.
+const MATCHMAKING_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How far apart in rating players can be matched right after joining the queue.
+const MATCH_RATING_SPREAD: f64 = 100.0;
+
+/// How much further the rating spread reaches for every second spent in the queue,
+/// so that nobody waits forever.
+const MATCH_RATING_SPREAD_PER_SEC: f64 = 10.0;
+
+/// How many of the most recent queue times the expected queue time is averaged over.
+const QUEUE_TIME_SAMPLES: usize = 20;
+
+fn new_invite_code() -> InviteCode {
+    // No 0/O or 1/I, so codes can be read out loud
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+    let bytes = Uuid::new_v4().into_bytes();
+    // Bytes 6 and 8 hold the uuid version and variant, so they aren't random
+    let code = bytes[..6]
+        .iter()
+        .chain(&bytes[10..12])
+        .map(|b| ALPHABET[*b as usize % ALPHABET.len()] as char)
+        .collect();
+    InviteCode(code)
+}
+
+fn lobby_access(lobby: &Lobby) -> LobbyAccess {
+    let mut invite_codes: Vec<_> = lobby.invite_codes.iter().cloned().collect();
+    invite_codes.sort_by(|a, b| a.0.cmp(&b.0));
+    LobbyAccess {
+        password_protected: lobby.password.is_some(),
+        invite_codes,
+    }
+}
+
+/// A copy of the lobby to send to a player, with its timers brought up to date.
+fn lobby_snapshot(lobby: &Lobby) -> Lobby {
+    let mut lobby = lobby.clone();
+    if let LobbyState::ChampSelect(state) = &mut lobby.lobby_state {
+        state.update_time_left();
+    }
+    lobby
+}
+
+/// The order of turns in a draft: one ban per player, then one pick per player.
+///
+/// The bans are left out if `champion_count` doesn't cover both bans and picks,
+/// and `None` is returned if it doesn't even cover the picks, as some turn could never be filled.
+fn draft_turns(
+    players: &HashMap<Team, Vec<PlayerId>>,
+    team_count: usize,
+    champion_count: usize,
+) -> Option<Vec<DraftTurn>> {
+    let player_count = players.values().map(Vec::len).sum::<usize>();
+    let kinds: &[_] = if 2 * player_count <= champion_count {
+        &[DraftTurnKind::Ban, DraftTurnKind::Pick]
+    } else if player_count <= champion_count {
+        &[DraftTurnKind::Pick]
+    } else {
+        return None;
+    };
+
+    let rounds = players.values().map(Vec::len).max().unwrap_or(0);
+    let mut turns = vec![];
+    for &kind in kinds {
+        for round in 0..rounds {
+            let mut teams = (0..team_count).map(Team).collect::<Vec<_>>();
+            // Picks snake, so the team picking last in a round also picks first in the next
+            if kind == DraftTurnKind::Pick && round % 2 == 1 {
+                teams.reverse();
+            }
+            for team in teams {
+                if let Some(player) = players.get(&team).and_then(|p| p.get(round)) {
+                    turns.push(DraftTurn {
+                        team,
+                        player: *player,
+                        kind,
+                    });
+                }
+            }
+        }
+    }
+    Some(turns)
+}
+
+/// The lobbies matching `filter` that come after `cursor` when sorted by `sort`,
+/// at most one page of them, and the cursor of the next page if there are more.
+fn lobby_list_page(
+    lobbies: impl IntoIterator<Item = LobbyShortInfo>,
+    filter: &LobbyFilter,
+    sort: LobbySort,
+    cursor: Option<&LobbyListCursor>,
+) -> (Vec<LobbyShortInfo>, Option<LobbyListCursor>) {
+    let mut list = lobbies
+        .into_iter()
+        .filter(|lobby| filter.matches(lobby))
+        .filter(|lobby| {
+            cursor.is_none_or(|cursor| sort.compare(&lobby.into(), cursor) == Ordering::Greater)
+        })
+        .collect::<Vec<_>>();
+    list.sort_by(|a, b| sort.compare(&a.into(), &b.into()));
+
+    let next = if list.len() > LOBBY_LIST_PAGE_SIZE {
+        list.truncate(LOBBY_LIST_PAGE_SIZE);
+        list.last().map(LobbyListCursor::from)
+    } else {
+        None
+    };
+    (list, next)
+}
+
+/// How many more players fit on the lobby's teams.
+fn free_team_slots(lobby: &Lobby) -> usize {
+    (lobby.settings.team_count * lobby.settings.player_limit_per_team)
+        .saturating_sub(lobby.players.values().map(Vec::len).sum())
+}
+
+fn free_spectator_slots(lobby: &Lobby) -> usize {
+    lobby
+        .settings
+        .spectator_slots
+        .saturating_sub(lobby.spectators.len())
+}
+
+/// Picks a team for every player in `group`, which is kept together on one team if any has room.
+fn teams_for_group(
+    players: &HashMap<Team, Vec<PlayerId>>,
+    settings: &LobbySettings,
+    group: &[PlayerId],
+) -> Vec<(PlayerId, Team)> {
+    let mut players = players.clone();
+    let together = (0..settings.team_count)
+        .map(|i| (Team(i), players.get(&Team(i)).map_or(0, Vec::len)))
+        .filter(|(_, len)| len + group.len() <= settings.player_limit_per_team)
+        .min_by_key(|(_, len)| *len);
+    group
+        .iter()
+        .map(|player| {
+            let team = match together {
+                Some((team, _)) => team,
+                None => smallest_team(&players, settings.team_count),
+            };
+            players.entry(team).or_default().push(*player);
+            (*player, team)
+        })
+        .collect()
+}
+
+/// The team with the fewest players, which is where new players are put.
+fn smallest_team(players: &HashMap<Team, Vec<PlayerId>>, team_count: usize) -> Team {
+    (0..team_count)
+        .map(|i| (Team(i), players.get(&Team(i)).unwrap().len()))
+        .min_by_key(|x| x.1)
+        .expect("There should always be at least 1 team")
+        .0
+}
+
+/// The players that join lobbies and queues along with `player_id`: its whole party, if it has one.
+///
+/// Only the party leader can take the party along, and only while nobody in it is busy.
+fn party_group(
+    parties: &HashMap<PartyId, Party>,
+    players: &HashMap<PlayerId, PlayerInfoWithConn>,
+    player_id: PlayerId,
+) -> Result<Vec<PlayerId>, LobbyError> {
+    let Some(party) = players
+        .get(&player_id)
+        .and_then(|p| p.in_party)
+        .and_then(|id| parties.get(&id))
+    else {
+        return Ok(vec![player_id]);
+    };
+    if party.leader != player_id {
+        return Err(LobbyError::NotPartyLeader);
+    }
+    let busy = party.members.iter().any(|member| {
+        players
+            .get(&member.id)
+            .is_none_or(|p| p.in_lobby.is_some() || p.in_queue.is_some())
+    });
+    if busy {
+        return Err(LobbyError::PartyBusy);
+    }
+    Ok(party.members.iter().map(|m| m.id).collect())
+}
+
+/// Players waiting in the queue together, who are matched as a unit.
+struct QueueEntry {
+    players: Vec<PlayerId>,
+    /// The average rating of the players.
+    rating: f64,
+    joined: Instant,
+}
+
+/// Finds a match of two full teams in `queue`, which is ordered by the time players joined.
+///
+/// Whoever waited longest is matched first, with the players closest to them in rating;
+/// the teams are then balanced by their total rating.
+/// Returns the indices of the entries on each team.
+fn find_match(queue: &[QueueEntry], team_size: usize, now: Instant) -> Option<[Vec<usize>; 2]> {
+    for (anchor, anchor_entry) in queue.iter().enumerate() {
+        let waited = now.duration_since(anchor_entry.joined).as_secs_f64();
+        let spread = MATCH_RATING_SPREAD + MATCH_RATING_SPREAD_PER_SEC * waited;
+        let distance = |i: usize| (queue[i].rating - anchor_entry.rating).abs();
+
+        let mut candidates = (0..queue.len())
+            .filter(|i| *i != anchor && distance(*i) <= spread)
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| distance(*a).total_cmp(&distance(*b)));
+
+        // Fill up the match with the closest candidates
+        let mut chosen = vec![anchor];
+        let mut player_count = anchor_entry.players.len();
+        for i in candidates {
+            if player_count == team_size * 2 {
+                break;
+            }
+            if player_count + queue[i].players.len() <= team_size * 2 {
+                chosen.push(i);
+                player_count += queue[i].players.len();
+            }
+        }
+        if player_count != team_size * 2 {
+            continue;
+        }
 
-    ServerState::new(options).run().await;
+        // Biggest groups first, as they are the hardest to fit,
+        // then strongest first, each joining the weaker team that has room for it
+        chosen.sort_by(|a, b| {
+            let (a, b) = (&queue[*a], &queue[*b]);
+            b.players
+                .len()
+                .cmp(&a.players.len())
+                .then(b.rating.total_cmp(&a.rating))
+        });
+        let mut teams: [Vec<usize>; 2] = Default::default();
+        let mut sizes = [0; 2];
+        let mut ratings = [0.0; 2];
+        for i in chosen {
+            let len = queue[i].players.len();
+            let fits = |team: usize| sizes[team] + len <= team_size;
+            let team = match (fits(0), fits(1)) {
+                (true, true) if ratings[0] <= ratings[1] => 0,
+                (true, true) => 1,
+                (true, false) => 0,
+                (false, true) => 1,
+                (false, false) => break,
+            };
+            teams[team].push(i);
+            sizes[team] += len;
+            ratings[team] += queue[i].rating * len as f64;
+        }
+        if sizes == [team_size; 2] {
+            return Some(teams);
+        }
+    }
+    None
+}
+
+/// Allows short bursts of chat messages, but limits how many can be sent over time.
+struct ChatRateLimit {
+    tokens: f32,
+    last_refill: Instant,
+}
+
+impl ChatRateLimit {
+    const BURST: f32 = 5.0;
+    const PER_SECOND: f32 = 1.0;
+
+    fn new() -> Self {
+        Self {
+            tokens: Self::BURST,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * Self::PER_SECOND).min(Self::BURST);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
 }
 
-const MAPS: [MapDef; 1] = [MapDef {
-    name: "Default",
-    min_teams: 2,
-    max_teams: 2,
-}];
+/// Refuses a player's initial handshake and closes the session.
+async fn refuse_handshake(
+    mut send_stream: SendStream,
+    request_id: RequestId,
+    error: LobbyError,
+) -> anyhow::Result<()> {
+    send_stream
+        .write_message(ServerMessage {
+            in_reply_to: Some(request_id),
+            message: MessageFromServer::RequestRefused(error),
+        })
+        .await?;
+    send_stream.finish().await?;
+    Ok(())
+}
+
+/// How a match on a game server went, unless the game server failed.
+enum MatchOutcome {
+    Finished(MatchResult),
+    /// The game server didn't start the match, but is ready for the next one.
+    Refused(String),
+}
 
-struct MapDef {
-    name: &'static str,
-    min_teams: usize,
-    max_teams: usize,
+/// Starts a match on a game server and hands the players their connect tokens,
+/// then waits for the game server to report the result.
+async fn play_match(
+    connection: &Connection,
+    lobby_id: LobbyId,
+    message: MessageFromLobbyToGameServer,
+    send: &tokio::sync::mpsc::UnboundedSender<Event>,
+) -> anyhow::Result<MatchOutcome> {
+    println!("Starting match on game server...");
+    connection
+        .open_uni()
+        .await?
+        .await?
+        .write_message(message)
+        .await?;
+    let message = connection.accept_uni().await?.read_message().await?;
+    let players = match message {
+        MessageFromGameServerToLobby::PlayerTokensGenerated { players } => players,
+        MessageFromGameServerToLobby::MatchRefused(reason) => {
+            return Ok(MatchOutcome::Refused(reason));
+        }
+        _ => anyhow::bail!("Unexpected message from game server: {message:?}"),
+    };
+    send.send(Event::Callback(Box::new(move |s| {
+        s.update_lobby(lobby_id, LobbyUpdate::GameStarted);
+        for (player, token) in players {
+            s.send_message(player, MessageFromServer::GameStarted(token));
+        }
+    })))
+    .unwrap();
+
+    // The game server reports the result on a new stream once the match is over
+    let message = connection.accept_uni().await?.read_message().await?;
+    let MessageFromGameServerToLobby::MatchFinished(result) = message else {
+        anyhow::bail!("Unexpected message from game server: {message:?}");
+    };
+    Ok(MatchOutcome::Finished(result))
 }
 
 // #[derive(Debug)]
 enum Event {
     ConnectionMade(Connection),
-    PlayerNameUpdated(PlayerId, String),
-    MessageReceived(PlayerId, MessageFromPlayer),
-    ConnectionLost(PlayerId),
+    /// A game server agent connected, to register a game server.
+    AgentConnected(Connection),
+    PlayerAuthenticated {
+        account: Account,
+        request_id: RequestId,
+        connection: Connection,
+        streams: (SendStream, RecvStream),
+    },
+    ResumeRequested {
+        token: ResumeToken,
+        request_id: RequestId,
+        connection: Connection,
+        streams: (SendStream, RecvStream),
+    },
+    MessageReceived(PlayerId, PlayerRequest),
+    /// Carries the resume token of the session that was lost.
+    ConnectionLost(PlayerId, ResumeToken),
     Callback(Box<dyn FnOnce(&mut ServerState) + Send + Sync + 'static>),
     Shutdown,
 }
 
 struct ServerState {
     options: Options,
-    used_game_server_ports: HashSet<u16>,
+    game_server_ports: PortAllocator,
     lobbies: HashMap<LobbyId, Lobby>,
-    game_servers: HashMap<LobbyId, tokio::sync::oneshot::Sender<()>>,
+    /// The game servers running the games of lobbies.
+    game_servers: HashMap<LobbyId, GameServer>,
+    game_server_pool: GameServerPool,
     players: HashMap<PlayerId, PlayerInfoWithConn>,
+    chat_filter: Option<Regex>,
+    champions: ChampionRegistry,
+    maps: MapRegistry,
+    /// Players waiting for a match, in the order they joined.
+    queues: HashMap<QueueMode, Vec<QueueEntry>>,
+    /// How long the players in the most recent matches waited, for estimating queue times.
+    queue_times: HashMap<QueueMode, VecDeque<Duration>>,
+    parties: HashMap<PartyId, Party>,
+    accounts: Arc<std::sync::Mutex<AccountStore>>,
     event_receiver: tokio::sync::mpsc::UnboundedReceiver<Event>,
     event_sender: tokio::sync::mpsc::UnboundedSender<Event>,
     should_exit: bool,
@@ -113,17 +599,46 @@ struct ServerState {
 struct PlayerInfoWithConn {
     player: PlayerInfo,
     in_lobby: Option<LobbyId>,
-    conn: Connection,
+    in_queue: Option<QueueMode>,
+    rating: f64,
+    in_party: Option<PartyId>,
+    friends: Vec<PlayerInfo>,
+    /// Players whose friend requests haven't been answered yet.
+    friend_requests: Vec<PlayerInfo>,
+    /// What the player's friends were last told it's doing.
+    presence: Presence,
+    /// Encoded frames waiting to be written to the player's stream, in order.
+    outgoing: tokio::sync::mpsc::UnboundedSender<Arc<[u8]>>,
+    session: JoinHandle<()>,
+    chat_rate_limit: ChatRateLimit,
+    /// Issued anew for every session; identifies the session and lets the client resume it.
+    resume_token: ResumeToken,
+    /// Set while the player's lobby slot is being held after a lost connection.
+    disconnected_since: Option<Instant>,
 }
 
 impl ServerState {
-    fn new(options: Options) -> Self {
+    fn new(
+        options: Options,
+        accounts: AccountStore,
+        chat_filter: Option<Regex>,
+        champions: ChampionRegistry,
+        maps: MapRegistry,
+    ) -> Self {
         let (event_sender, event_receiver) = tokio::sync::mpsc::unbounded_channel();
         Self {
+            chat_filter,
+            champions,
+            maps,
+            queues: HashMap::new(),
+            queue_times: HashMap::new(),
+            parties: HashMap::new(),
+            accounts: Arc::new(std::sync::Mutex::new(accounts)),
+            game_server_ports: PortAllocator::new(options.game_server_port_range),
             options,
-            used_game_server_ports: HashSet::new(),
             lobbies: HashMap::new(),
             game_servers: HashMap::new(),
+            game_server_pool: GameServerPool::default(),
             players: HashMap::new(),
             event_sender,
             event_receiver,
@@ -131,11 +646,12 @@ impl ServerState {
         }
     }
 
-    async fn run(&mut self) {
+    async fn run(&mut self, server_config: ServerConfig) {
         println!(
             "{} concurrent game servers supported",
-            self.options.game_server_port_range.iter().count()
+            self.game_server_ports.capacity()
         );
+        self.refill_game_server_pool();
 
         // Add ctrl-c handler
 
@@ -155,17 +671,9 @@ impl ServerState {
         .unwrap();
 
         // Start listening server
-        let server = Endpoint::server(
-            ServerConfig::builder()
-                .with_bind_address_v6(
-                    SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 54765, 0, 0),
-                    Ipv6DualStackConfig::Allow,
-                )
-                .with_identity(Identity::self_signed(["localhost", "127.0.0.1", "::1"]).unwrap())
-                .keep_alive_interval(Some(Duration::from_secs(15)))
-                .build(),
-        )
-        .unwrap();
+        let server = Endpoint::server(server_config).unwrap();
+
+        self.matchmaking_tick();
 
         let mut accept = Box::pin(server.accept());
 
@@ -178,9 +686,13 @@ impl ServerState {
                     accept = Box::pin(server.accept());
                     tokio::spawn(async move {
                         match session.await {
-                            Ok(x) => match x.accept().await {
-                                Ok(x) => { let _ = send.send(Event::ConnectionMade(x)); },
-                                Err(e) => println!("Session request not accepted: {e}"),
+                            Ok(x) => {
+                                let agent = x.path() == AGENT_PATH;
+                                match x.accept().await {
+                                    Ok(x) if agent => { let _ = send.send(Event::AgentConnected(x)); },
+                                    Ok(x) => { let _ = send.send(Event::ConnectionMade(x)); },
+                                    Err(e) => println!("Session request not accepted: {e}"),
+                                }
                             },
                             Err(e) => println!("Session not accepted: {e}"),
                         }
@@ -199,111 +711,591 @@ impl ServerState {
     async fn handle_event(&mut self, msg: Event) {
         // println!("Event received: {msg:?}");
         match msg {
+            Event::AgentConnected(connection) => self.accept_game_server_agent(connection),
             Event::ConnectionMade(connection) => {
-                let player_id = PlayerId::new();
-                let conn = connection.clone();
-                self.players.insert(
-                    player_id,
-                    PlayerInfoWithConn {
-                        player: PlayerInfo {
-                            id: player_id,
-                            name: String::new(),
-                        },
-                        in_lobby: None,
-                        conn,
-                    },
-                );
-
                 let send = self.event_sender.clone();
+                let accounts = self.accounts.clone();
 
                 tokio::spawn(async move {
                     let x: anyhow::Result<()> = try {
-                        let mut recv_stream = connection.accept_uni().await?;
-                        let msg = recv_stream.read_message().await?;
-                        let MessageFromPlayer::InitialHandshake { name } = msg else {
+                        // Every session uses a single bidirectional stream, opened by the client,
+                        // so that messages arrive in the order they were sent.
+                        let (mut send_stream, mut recv_stream) = connection.accept_bi().await?;
+                        let msg = match recv_stream.read_message().await {
+                            Ok(msg) => msg,
+                            Err(e) => {
+                                if let Some(CodecError::VersionMismatch { .. }) = e.downcast_ref() {
+                                    // Let the client know, so it can show a proper error
+                                    // instead of just seeing the connection drop.
+                                    send_stream
+                                        .write_message_raw(
+                                            &encode_version_refusal::<ServerMessage>(),
+                                        )
+                                        .await?;
+                                    send_stream.finish().await?;
+                                }
+                                Err(e)?
+                            }
+                        };
+                        let PlayerRequest {
+                            id: request_id,
+                            message: MessageFromPlayer::InitialHandshake { credentials },
+                        } = msg
+                        else {
                             Err(anyhow::anyhow!("Wrong message received"))?;
                             unreachable!();
                         };
 
-                        let _ = send.send(Event::PlayerNameUpdated(player_id, name));
-
-                        connection
-                            .open_uni()
-                            .await?
-                            .await?
-                            .write_message(MessageFromServer::InitialHandshakeResponse {
-                                id: player_id,
-                            })
-                            .await?;
-
-                        let send = send.clone();
-                        tokio::spawn(async move {
-                            let Err(e): anyhow::Result<!> = try {
-                                loop {
-                                    let mut recv_stream = connection.accept_uni().await?;
-                                    let msg = recv_stream.read_message().await?;
-
-                                    if send.send(Event::MessageReceived(player_id, msg)).is_err() {
-                                        return;
+                        println!("Authenticating {credentials:?}");
+                        let result = match credentials {
+                            Credentials::Resume(token) => {
+                                // Only the main loop knows about sessions
+                                let _ = send.send(Event::ResumeRequested {
+                                    token,
+                                    request_id,
+                                    connection,
+                                    streams: (send_stream, recv_stream),
+                                });
+                                return;
+                            }
+                            credentials => {
+                                let address = connection.remote_address().ip();
+                                tokio::task::spawn_blocking(move || {
+                                    let mut accounts = accounts.lock().unwrap();
+                                    match credentials {
+                                        Credentials::Register { name, password } => {
+                                            accounts.register(&name, &password)
+                                        }
+                                        Credentials::Login { name, password } => {
+                                            accounts.login(&name, &password, address)
+                                        }
+                                        Credentials::Resume(_) => unreachable!(),
                                     }
-                                }
-                            };
-                            println!("Error (1): {e}");
-                            let _ = send.send(Event::ConnectionLost(player_id));
-                        });
+                                })
+                                .await?
+                            }
+                        };
+
+                        match result {
+                            Ok(account) => {
+                                let _ = send.send(Event::PlayerAuthenticated {
+                                    account,
+                                    request_id,
+                                    connection,
+                                    streams: (send_stream, recv_stream),
+                                });
+                            }
+                            Err(error) => {
+                                refuse_handshake(send_stream, request_id, error).await?;
+                            }
+                        }
                     };
 
                     if let Err(e) = x {
-                        println!("Error (2): {e}");
-                        let _ = send.send(Event::ConnectionLost(player_id));
+                        println!("Error during handshake: {e}");
                     }
                 });
             }
-            Event::PlayerNameUpdated(player_id, name) => {
-                if let Some(player) = self.players.get_mut(&player_id) {
-                    player.player.name = name;
-                }
+            Event::PlayerAuthenticated {
+                account,
+                request_id,
+                connection,
+                streams,
+            } => {
+                let player_id = account.player.id;
+                let in_lobby = match self.players.get(&player_id) {
+                    // Logging in again while the old session is being held takes it over
+                    Some(old) if old.disconnected_since.is_some() => old.in_lobby,
+                    Some(_) => {
+                        tokio::spawn(refuse_handshake(
+                            streams.0,
+                            request_id,
+                            LobbyError::AlreadyLoggedIn,
+                        ));
+                        return;
+                    }
+                    None => None,
+                };
+                println!("{:?} logged in as {player_id:?}", account.player.name);
+                self.start_session(account, in_lobby, request_id, connection, streams);
+            }
+            Event::ResumeRequested {
+                token,
+                request_id,
+                connection,
+                streams,
+            } => {
+                let Some(old) = self.players.values().find(|p| p.resume_token == token) else {
+                    tokio::spawn(refuse_handshake(
+                        streams.0,
+                        request_id,
+                        LobbyError::InvalidResumeToken,
+                    ));
+                    return;
+                };
+                println!("{:?} resumed their session", old.player.name);
+                let account = Account {
+                    player: old.player.clone(),
+                    rating: old.rating,
+                    friends: old.friends.clone(),
+                    friend_requests: old.friend_requests.clone(),
+                };
+                let in_lobby = old.in_lobby;
+                self.start_session(account, in_lobby, request_id, connection, streams);
             }
             Event::MessageReceived(player_id, msg) => {
                 self.handle_message(player_id, msg);
             }
-            Event::ConnectionLost(player_id) => {
-                // We need to handle removing the player from the lobby it is in, if any.
-                self.handle_player_left_lobby(player_id);
-                self.players.remove(&player_id);
+            Event::ConnectionLost(player_id, token) => {
+                let grace_period = Duration::from_secs(self.options.reconnect_grace_period);
+                let Some(player) = self.players.get_mut(&player_id) else {
+                    return;
+                };
+                // The player might have resumed on a new connection already
+                if player.resume_token != token || player.disconnected_since.is_some() {
+                    return;
+                }
+                if grace_period.is_zero() {
+                    self.remove_player(player_id);
+                    return;
+                }
+
+                // Hold on to the player's lobby slot for a while, in case they come back
+                println!("{:?} lost connection", player.player.name);
+                player.session.abort();
+                let disconnected_since = Instant::now();
+                player.disconnected_since = Some(disconnected_since);
+                self.schedule(grace_period, move |s| {
+                    if s.players.get(&player_id).is_some_and(|p| {
+                        p.resume_token == token && p.disconnected_since == Some(disconnected_since)
+                    }) {
+                        s.remove_player(player_id);
+                    }
+                });
             }
             Event::Callback(func) => {
                 func(self);
             }
             Event::Shutdown => {
-                let handles = self.broadcast_global_message(MessageFromServer::ServerShutdown);
+                self.broadcast_global_message(MessageFromServer::ServerShutdown);
                 self.should_exit = true;
-                JoinSet::from_iter(handles).join_all().await;
+                // Dropping the players closes their queues,
+                // letting each session flush its remaining messages and finish.
+                let sessions = self.players.drain().map(|(_, p)| p.session);
+                JoinSet::from_iter(sessions).join_all().await;
+            }
+        }
+    }
+
+    /// Spawns the tasks running a player's session on a freshly authenticated connection,
+    /// replacing the player's previous session if there is one.
+    fn start_session(
+        &mut self,
+        Account {
+            player,
+            rating,
+            friends,
+            friend_requests,
+        }: Account,
+        in_lobby: Option<LobbyId>,
+        request_id: RequestId,
+        connection: Connection,
+        (mut send_stream, mut recv_stream): (SendStream, RecvStream),
+    ) {
+        let player_id = player.id;
+        let resume_token = ResumeToken::new();
+        let (outgoing, mut outgoing_recv) = tokio::sync::mpsc::unbounded_channel::<Arc<[u8]>>();
+
+        let send = self.event_sender.clone();
+
+        let session = tokio::spawn(async move {
+            // Keep the connection alive for as long as the session runs
+            let _connection = connection;
+
+            let read = async {
+                let Err(e): anyhow::Result<!> = try {
+                    loop {
+                        let msg = recv_stream.read_message().await?;
+
+                        if send.send(Event::MessageReceived(player_id, msg)).is_err() {
+                            return Ok(());
+                        }
+                    }
+                };
+                Err(e)
+            };
+
+            let write = async {
+                // Write queued messages until the player is removed,
+                // which closes the queue.
+                while let Some(frame) = outgoing_recv.recv().await {
+                    send_stream.write_message_raw(&frame).await?;
+                }
+                send_stream.finish().await?;
+                anyhow::Ok(())
+            };
+
+            let x = tokio::select! {
+                x = read => x,
+                x = write => x,
+            };
+
+            if let Err(e) = x {
+                println!("Session error: {e}");
+                let _ = send.send(Event::ConnectionLost(player_id, resume_token));
+            }
+        });
+
+        // A resumed session is still in the same queue and party, if it was before
+        let (in_queue, in_party) = self
+            .players
+            .get(&player_id)
+            .map_or((None, None), |old| (old.in_queue, old.in_party));
+        let old = self.players.insert(
+            player_id,
+            PlayerInfoWithConn {
+                player,
+                in_lobby,
+                in_queue,
+                rating,
+                in_party,
+                friends: friends.clone(),
+                friend_requests: friend_requests.clone(),
+                presence: Presence::Offline,
+                outgoing,
+                session,
+                chat_rate_limit: ChatRateLimit::new(),
+                resume_token,
+                disconnected_since: None,
+            },
+        );
+        if let Some(old) = old {
+            old.session.abort();
+        }
+
+        self.reply(
+            player_id,
+            request_id,
+            MessageFromServer::InitialHandshakeResponse {
+                id: player_id,
+                resume_token,
+            },
+        );
+
+        // Updates to the lobby were missed while disconnected, so send all of it again
+        if let Some(lobby) = in_lobby.and_then(|id| self.lobbies.get(&id)) {
+            let lobby = lobby_snapshot(lobby);
+            self.send_message(player_id, MessageFromServer::YouJoinedLobby(lobby.id));
+            self.send_message(player_id, MessageFromServer::LobbyInfo(Box::new(lobby)));
+        }
+        if let Some(mode) = in_queue {
+            let message = self.queue_status(player_id, mode);
+            self.send_message(player_id, message);
+        }
+        if let Some(party) = in_party.and_then(|id| self.parties.get(&id)) {
+            let message = MessageFromServer::PartyUpdated(Some(party.clone()));
+            self.send_message(player_id, message);
+        }
+
+        let friends = friends
+            .into_iter()
+            .map(|player| Friend {
+                presence: self
+                    .players
+                    .get(&player.id)
+                    .map_or(Presence::Offline, |p| p.presence),
+                player,
+            })
+            .collect();
+        self.send_message(
+            player_id,
+            MessageFromServer::FriendList {
+                friends,
+                requests: friend_requests,
+            },
+        );
+        self.refresh_presence(player_id);
+    }
+
+    /// Removes a player from the server, and from the lobby it is in, if any.
+    fn remove_player(&mut self, player_id: PlayerId) {
+        self.leave_queue(player_id);
+        self.leave_party(player_id);
+        self.handle_player_left_lobby(player_id);
+        let Some(player) = self.players.remove(&player_id) else {
+            return;
+        };
+
+        let friend = Friend {
+            player: player.player,
+            presence: Presence::Offline,
+        };
+        for other in player.friends {
+            self.send_message(other.id, MessageFromServer::FriendUpdated(friend.clone()));
+        }
+    }
+
+    /// Runs `callback` on the server state after `delay`.
+    fn schedule(
+        &self,
+        delay: Duration,
+        callback: impl FnOnce(&mut ServerState) + Send + Sync + 'static,
+    ) {
+        let send = self.event_sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = send.send(Event::Callback(Box::new(callback)));
+        });
+    }
+
+    fn handle_message(&mut self, player_id: PlayerId, request: PlayerRequest) {
+        println!("Message received from {player_id:?}: {request:?}");
+        if !self.players.contains_key(&player_id) {
+            return;
+        }
+
+        // Every request gets exactly one reply carrying its id,
+        // even if the handler has nothing more specific to say than "ok".
+        let message = match request.message {
+            // These are answered from the account database, once the query is done
+            MessageFromPlayer::GetProfile(id) => {
+                self.query_accounts(player_id, request.id, move |accounts| {
+                    accounts.profile(id).map(MessageFromServer::Profile)
+                });
+                return;
+            }
+            MessageFromPlayer::GetMatchHistory(id) => {
+                self.query_accounts(player_id, request.id, move |accounts| {
+                    let matches = accounts.match_history(id)?;
+                    Ok(MessageFromServer::MatchHistory {
+                        player: id,
+                        matches,
+                    })
+                });
+                return;
+            }
+            MessageFromPlayer::SendFriendRequest(name) => {
+                let from = self.players[&player_id].player.clone();
+                self.update_accounts(
+                    player_id,
+                    request.id,
+                    move |accounts| accounts.send_friend_request(player_id, &name),
+                    move |s, outcome| {
+                        match outcome {
+                            FriendRequestOutcome::Sent(to) => s.add_friend_request(from, to.id),
+                            FriendRequestOutcome::Accepted(to) => s.add_friends(from, to),
+                        }
+                        MessageFromServer::RequestAccepted
+                    },
+                );
+                return;
+            }
+            MessageFromPlayer::AcceptFriendRequest(id) => {
+                let player = self.players[&player_id].player.clone();
+                self.update_accounts(
+                    player_id,
+                    request.id,
+                    move |accounts| accounts.accept_friend_request(player_id, id),
+                    move |s, friend| {
+                        s.add_friends(player, friend);
+                        MessageFromServer::RequestAccepted
+                    },
+                );
+                return;
+            }
+            MessageFromPlayer::RemoveFriend(id) => {
+                self.update_accounts(
+                    player_id,
+                    request.id,
+                    move |accounts| accounts.remove_friend(player_id, id),
+                    move |s, ()| {
+                        s.remove_friend(player_id, id);
+                        MessageFromServer::RequestAccepted
+                    },
+                );
+                return;
+            }
+            message => message,
+        };
+        let reply = match self.handle_request(player_id, message) {
+            Ok(Some(reply)) => reply,
+            Ok(None) => MessageFromServer::RequestAccepted,
+            Err(error) => MessageFromServer::RequestRefused(error),
+        };
+        self.reply(player_id, request.id, reply);
+    }
+
+    /// Runs `query` on the account database in the background, then replies with its result.
+    fn query_accounts(
+        &self,
+        player_id: PlayerId,
+        request_id: RequestId,
+        query: impl FnOnce(&mut AccountStore) -> Result<MessageFromServer, LobbyError> + Send + 'static,
+    ) {
+        self.update_accounts(player_id, request_id, query, |_, reply| reply);
+    }
+
+    /// Runs `query` on the account database in the background,
+    /// then passes its result to `apply`, which returns the reply.
+    fn update_accounts<T: Send + Sync + 'static>(
+        &self,
+        player_id: PlayerId,
+        request_id: RequestId,
+        query: impl FnOnce(&mut AccountStore) -> Result<T, LobbyError> + Send + 'static,
+        apply: impl FnOnce(&mut ServerState, T) -> MessageFromServer + Send + Sync + 'static,
+    ) {
+        let accounts = self.accounts.clone();
+        let send = self.event_sender.clone();
+        tokio::spawn(async move {
+            let result = tokio::task::spawn_blocking(move || query(&mut accounts.lock().unwrap()))
+                .await
+                .unwrap_or(Err(LobbyError::AccountStoreFailed));
+            let _ = send.send(Event::Callback(Box::new(move |s| {
+                let reply = match result {
+                    Ok(result) => apply(s, result),
+                    Err(error) => MessageFromServer::RequestRefused(error),
+                };
+                s.reply(player_id, request_id, reply)
+            })));
+        });
+    }
+
+    /// Lets `to` know about a friend request it was sent, if it's online.
+    fn add_friend_request(&mut self, from: PlayerInfo, to: PlayerId) {
+        let Some(player) = self.players.get_mut(&to) else {
+            return;
+        };
+        if player.friend_requests.iter().any(|p| p.id == from.id) {
+            return;
+        }
+        player.friend_requests.push(from.clone());
+        self.send_message(to, MessageFromServer::FriendRequestReceived(from));
+    }
+
+    /// Adds the players to each other's friends, for whichever of them is online.
+    fn add_friends(&mut self, a: PlayerInfo, b: PlayerInfo) {
+        for (player_id, friend) in [(a.id, b.clone()), (b.id, a)] {
+            let presence = self
+                .players
+                .get(&friend.id)
+                .map_or(Presence::Offline, |p| p.presence);
+            let Some(player) = self.players.get_mut(&player_id) else {
+                continue;
+            };
+            player.friend_requests.retain(|p| p.id != friend.id);
+            if !player.friends.iter().any(|p| p.id == friend.id) {
+                player.friends.push(friend.clone());
             }
+            let friend = Friend {
+                player: friend,
+                presence,
+            };
+            self.send_message(player_id, MessageFromServer::FriendUpdated(friend));
+        }
+    }
+
+    fn remove_friend(&mut self, a: PlayerId, b: PlayerId) {
+        for (player_id, other) in [(a, b), (b, a)] {
+            let Some(player) = self.players.get_mut(&player_id) else {
+                continue;
+            };
+            player.friends.retain(|p| p.id != other);
+            player.friend_requests.retain(|p| p.id != other);
+            self.send_message(player_id, MessageFromServer::FriendRemoved(other));
+        }
+    }
+
+    /// What the player is doing, from the state of its lobby.
+    fn presence(&self, player_id: PlayerId) -> Presence {
+        let Some(player) = self.players.get(&player_id) else {
+            return Presence::Offline;
+        };
+        let Some(lobby) = player.in_lobby.and_then(|id| self.lobbies.get(&id)) else {
+            return Presence::Online;
+        };
+        match lobby.lobby_state {
+            LobbyState::Normal | LobbyState::ReadyCheck(_) => Presence::InLobby(lobby.id),
+            LobbyState::ChampSelect(_) => Presence::InChampSelect,
+            LobbyState::InGame => Presence::InGame,
         }
     }
 
-    fn handle_message(&mut self, player_id: PlayerId, msg: MessageFromPlayer) {
-        println!("Message received from {player_id:?}: {msg:?}");
+    /// Tells the player's friends what it's doing now, if that changed since they were last told.
+    fn refresh_presence(&mut self, player_id: PlayerId) {
+        let presence = self.presence(player_id);
         let Some(player) = self.players.get_mut(&player_id) else {
             return;
         };
+        if player.presence == presence {
+            return;
+        }
+        player.presence = presence;
+
+        let friend = Friend {
+            player: player.player.clone(),
+            presence,
+        };
+        let friends = player.friends.iter().map(|p| p.id).collect::<Vec<_>>();
+        for other in friends {
+            self.send_message(other, MessageFromServer::FriendUpdated(friend.clone()));
+        }
+    }
+
+    /// Stores the result of a lobby's match, then updates the ratings of its players.
+    fn record_match(&mut self, lobby_id: LobbyId, mut result: MatchResult) {
+        let Some(lobby) = self.lobbies.get(&lobby_id) else {
+            return;
+        };
+        // Only trust the game server about players that were actually in the match
+        result.players.retain(|stats| {
+            lobby
+                .players
+                .get(&stats.team)
+                .is_some_and(|players| players.contains(&stats.player.id))
+        });
+        // Players leaving is all that ends a match for now, which is too easy to arrange
+        // to be worth rating
+        let rated = lobby.rated && !result.forfeit;
+
+        let accounts = self.accounts.clone();
+        let send = self.event_sender.clone();
+        tokio::spawn(async move {
+            let ratings = tokio::task::spawn_blocking(move || {
+                accounts.lock().unwrap().record_match(&result, rated)
+            })
+            .await;
+            let Ok(Ok(ratings)) = ratings else {
+                println!("Failed to record the match of {lobby_id:?}");
+                return;
+            };
+            let _ = send.send(Event::Callback(Box::new(move |s| {
+                for (player_id, rating) in ratings {
+                    if let Some(player) = s.players.get_mut(&player_id) {
+                        player.rating = rating;
+                    }
+                }
+            })));
+        });
+    }
+
+    fn handle_request(
+        &mut self,
+        player_id: PlayerId,
+        msg: MessageFromPlayer,
+    ) -> Result<Option<MessageFromServer>, LobbyError> {
+        let Some(player) = self.players.get_mut(&player_id) else {
+            return Ok(None);
+        };
 
         macro_rules! guards {
             (ret $e:expr) => {
-                {
-                    self.send_message(player_id, MessageFromServer::RequestRefused($e.into()));
-                    return;
-                }
+                return Err($e)
             };
             ($([$($tt:tt)*])*) => {
                 $(guards!($($tt)*);)*
             };
             (Ok($pat:pat) = $guard:expr) => {
-                let $pat = match $guard {
-                    Ok(val) => val,
-                    Err(e) => guards!(ret e),
-                };
+                let $pat = $guard?;
             };
             (Ok($pat:pat) = $guard:expr => $msg:expr) => {
                 let $pat = match $guard {
@@ -321,22 +1313,24 @@ impl ServerState {
                 if $guard { guards!(ret $msg) }
             };
             ($guard:expr) => {
-                if let Err(e) = $guard { guards!(ret e) }
+                $guard?;
             };
         }
 
+        // Players waiting in the queue can't be in a lobby either
         macro_rules! not_in_lobby {
             () => {
-                match player.in_lobby {
-                    Some(_) => Err("You are already in a lobby."),
-                    None => Ok(()),
+                match (player.in_lobby, player.in_queue) {
+                    (Some(_), _) => Err(LobbyError::AlreadyInLobby),
+                    (_, Some(_)) => Err(LobbyError::AlreadyInQueue),
+                    (None, None) => Ok(()),
                 }
             };
         }
 
         macro_rules! in_lobby {
             () => {
-                player.in_lobby.ok_or("You are not in a lobby.")
+                player.in_lobby.ok_or(LobbyError::NotInLobby)
             };
         }
 
@@ -344,7 +1338,7 @@ impl ServerState {
             ($lobby_id:expr) => {
                 self.lobbies
                     .get_mut(&$lobby_id)
-                    .ok_or("That lobby does not exist.")
+                    .ok_or(LobbyError::LobbyNotFound)
             };
         }
 
@@ -353,167 +1347,332 @@ impl ServerState {
                 if matches!($lobby.lobby_state, LobbyState::Normal) {
                     Ok(())
                 } else {
-                    Err("Lobby is in invalid state.")
+                    Err(LobbyError::InvalidState {
+                        expected: LobbyStateKind::Normal,
+                        actual: $lobby.lobby_state.kind(),
+                    })
                 }
             };
         }
 
-        macro_rules! champ_select {
+        macro_rules! ready_check {
             ($lobby:expr) => {
-                if let LobbyState::ChampSelect(state) = &mut $lobby.lobby_state {
-                    Ok(state)
-                } else {
-                    Err("Lobby is in invalid state.")
+                match &mut $lobby.lobby_state {
+                    LobbyState::ReadyCheck(state) => Ok(state),
+                    state => Err(LobbyError::InvalidState {
+                        expected: LobbyStateKind::ReadyCheck,
+                        actual: state.kind(),
+                    }),
                 }
             };
         }
 
-        // let lobby_exists = |lobby_id| {
-        //     let Some(lobby) = self.lobbies.get_mut(&lobby_id) else {
-        //         self.send_message(
-        //             player_id,
-        //             MessageFromServer::RequestRefused("That lobby does not exist.".into()),
-        //         );
-        //         return;
-        //     };
-        // };
+        macro_rules! champ_select {
+            ($lobby:expr) => {
+                match &mut $lobby.lobby_state {
+                    LobbyState::ChampSelect(state) => Ok(state),
+                    state => Err(LobbyError::InvalidState {
+                        expected: LobbyStateKind::ChampSelect,
+                        actual: state.kind(),
+                    }),
+                }
+            };
+        }
 
         match msg {
             MessageFromPlayer::InitialHandshake { .. } => {}
+            MessageFromPlayer::GetProfile(_)
+            | MessageFromPlayer::GetMatchHistory(_)
+            | MessageFromPlayer::SendFriendRequest(_)
+            | MessageFromPlayer::AcceptFriendRequest(_)
+            | MessageFromPlayer::RemoveFriend(_) => {
+                unreachable!("Handled by handle_message")
+            }
             MessageFromPlayer::CreateLobby => {
+                let name = format!("{}'s Lobby", player.player.name);
                 guards! {
                     [not_in_lobby!()]
+                    [Ok(group) = party_group(&self.parties, &self.players, player_id)]
                 }
 
                 let lobby_id = LobbyId::new();
+                let settings = LobbySettings {
+                    name,
+                    map: DEFAULT_MAP.into(),
+                    team_count: 2,
+                    player_limit_per_team: 5,
+                    players_can_change_team: true,
+                    lobby_is_open: true,
+                    pick_time_secs: DEFAULT_PICK_TIME_SECS,
+                    champ_select_mode: ChampSelectMode::Blind,
+                    spectator_slots: 2,
+                    spectator_delay_secs: 0,
+                };
+                let mut players: HashMap<Team, Vec<PlayerId>> =
+                    [(Team(0), vec![]), (Team(1), vec![])].into();
+                for (player, team) in teams_for_group(&players, &settings, &group) {
+                    players.get_mut(&team).unwrap().push(player);
+                }
                 let lobby = Lobby {
                     id: lobby_id,
-                    settings: LobbySettings {
-                        name: format!("{}'s Lobby", player.player.name),
-                        map: "Default".into(),
-                        team_count: 2,
-                        player_limit_per_team: 5,
-                        players_can_change_team: true,
-                        lobby_is_open: true,
-                    },
+                    settings,
                     leader: player_id,
-                    players: [(Team(0), vec![player_id]), (Team(1), vec![])].into(),
+                    players,
+                    spectators: vec![],
                     lobby_state: LobbyState::Normal,
+                    revision: 0,
+                    password: None,
+                    invite_codes: HashSet::new(),
+                    rated: false,
                 };
 
                 self.lobbies.insert(lobby_id, lobby);
-                player.in_lobby = Some(lobby_id);
+                for member in group {
+                    self.players.get_mut(&member).unwrap().in_lobby = Some(lobby_id);
+                    self.refresh_presence(member);
+                    if member != player_id {
+                        self.send_message(member, MessageFromServer::YouJoinedLobby(lobby_id));
+                    }
+                }
 
-                self.send_message(player_id, MessageFromServer::YouJoinedLobby(lobby_id));
-                self.broadcast_lobby_message(
-                    lobby_id,
-                    Some(player_id),
-                    MessageFromServer::PlayerJoinedYourLobby(player_id),
-                );
+                return Ok(Some(MessageFromServer::YouJoinedLobby(lobby_id)));
             }
-            MessageFromPlayer::JoinLobby(lobby_id) => {
+            MessageFromPlayer::JoinLobby(lobby_id, password) => {
                 guards! {
                     [not_in_lobby!()]
                     [Ok(lobby) = lobby_exists!(lobby_id)]
                     [normal_lobby!(lobby)]
-                    [!lobby.settings.lobby_is_open => "The lobby is closed."]
-                    [lobby.players.values().map(Vec::len).sum::<usize>() >= lobby.settings.team_count * lobby.settings.player_limit_per_team => "The lobby is full"]
+                    [!lobby.settings.lobby_is_open => LobbyError::LobbyClosed]
+                    [lobby.password.is_some() && password.is_none() => LobbyError::PasswordRequired]
+                    [lobby.password.as_ref().zip(password.as_ref()).is_some_and(|(expected, given)| !secrets_match(given.as_bytes(), expected.as_bytes())) => LobbyError::WrongPassword]
+                    [Ok(group) = party_group(&self.parties, &self.players, player_id)]
+                    [free_team_slots(lobby) < group.len() && free_spectator_slots(lobby) < group.len() => LobbyError::LobbyFull]
                 }
 
-                // Find which team to join
-                // We want to join the team with the fewest players
+                self.add_to_lobby(lobby_id, player_id, &group);
+                return Ok(Some(MessageFromServer::YouJoinedLobby(lobby_id)));
+            }
+            MessageFromPlayer::JoinFriendLobby(friend) => {
+                // Friends don't get past a password, as anyone can befriend one of the players
+                guards! {
+                    [not_in_lobby!()]
+                    [!player.friends.iter().any(|p| p.id == friend) => LobbyError::NotFriends]
+                    [Some(lobby_id) = self.players.get(&friend).and_then(|p| p.in_lobby) => LobbyError::FriendNotInLobby]
+                    [Ok(lobby) = lobby_exists!(lobby_id)]
+                    [normal_lobby!(lobby)]
+                    [!lobby.settings.lobby_is_open => LobbyError::LobbyClosed]
+                    [lobby.password.is_some() => LobbyError::PasswordRequired]
+                    [Ok(group) = party_group(&self.parties, &self.players, player_id)]
+                    [free_team_slots(lobby) < group.len() && free_spectator_slots(lobby) < group.len() => LobbyError::LobbyFull]
+                }
 
-                let team_player_count = (0..lobby.settings.team_count)
-                    .map(|i| (Team(i), lobby.players.get(&Team(i)).unwrap().len()))
-                    .min_by_key(|x| x.1)
-                    .expect("There should always be at least 1 team");
+                self.add_to_lobby(lobby_id, player_id, &group);
+                return Ok(Some(MessageFromServer::YouJoinedLobby(lobby_id)));
+            }
+            MessageFromPlayer::JoinLobbyWithCode(code) => {
+                // Invite codes get past both a closed lobby and its password
+                guards! {
+                    [not_in_lobby!()]
+                    [Some(lobby) = self.lobbies.values_mut().find(|lobby| lobby.invite_codes.contains(&code)) => LobbyError::InvalidInviteCode]
+                    [normal_lobby!(lobby)]
+                    [Ok(group) = party_group(&self.parties, &self.players, player_id)]
+                    [free_team_slots(lobby) < group.len() && free_spectator_slots(lobby) < group.len() => LobbyError::LobbyFull]
+                }
 
-                player.in_lobby = Some(lobby_id);
+                let lobby_id = lobby.id;
+                self.add_to_lobby(lobby_id, player_id, &group);
+                return Ok(Some(MessageFromServer::YouJoinedLobby(lobby_id)));
+            }
+            MessageFromPlayer::SetLobbyPassword(password) => {
+                guards! {
+                    [Ok(lobby_id) = in_lobby!()]
+                    [Ok(lobby) = lobby_exists!(lobby_id)]
+                    [lobby.leader != player_id => LobbyError::NotLeader]
+                }
 
-                lobby
-                    .players
-                    .get_mut(&team_player_count.0)
-                    .unwrap()
-                    .push(player_id);
-                self.send_message(player_id, MessageFromServer::YouJoinedLobby(lobby_id));
-                self.broadcast_lobby_message(
-                    lobby_id,
-                    Some(player_id),
-                    MessageFromServer::PlayerJoinedYourLobby(player_id),
-                );
+                lobby.password = password.filter(|p| !p.is_empty());
+                return Ok(Some(MessageFromServer::LobbyAccess(lobby_access(lobby))));
+            }
+            MessageFromPlayer::GetLobbyAccess => {
+                guards! {
+                    [Ok(lobby_id) = in_lobby!()]
+                    [Ok(lobby) = lobby_exists!(lobby_id)]
+                    [lobby.leader != player_id => LobbyError::NotLeader]
+                }
+
+                return Ok(Some(MessageFromServer::LobbyAccess(lobby_access(lobby))));
+            }
+            MessageFromPlayer::CreateInviteCode => {
+                guards! {
+                    [Ok(lobby_id) = in_lobby!()]
+                    [Ok(lobby) = lobby_exists!(lobby_id)]
+                    [lobby.leader != player_id => LobbyError::NotLeader]
+                    [lobby.invite_codes.len() >= MAX_INVITE_CODES => LobbyError::TooManyInviteCodes(MAX_INVITE_CODES)]
+                }
+
+                // Codes must be unique across all lobbies, as they are all a player has to go on
+                let code = loop {
+                    let code = new_invite_code();
+                    if !self
+                        .lobbies
+                        .values()
+                        .any(|lobby| lobby.invite_codes.contains(&code))
+                    {
+                        break code;
+                    }
+                };
+                let lobby = self.lobbies.get_mut(&lobby_id).unwrap();
+                lobby.invite_codes.insert(code);
+                return Ok(Some(MessageFromServer::LobbyAccess(lobby_access(lobby))));
+            }
+            MessageFromPlayer::RevokeInviteCode(code) => {
+                guards! {
+                    [Ok(lobby_id) = in_lobby!()]
+                    [Ok(lobby) = lobby_exists!(lobby_id)]
+                    [lobby.leader != player_id => LobbyError::NotLeader]
+                    [!lobby.invite_codes.remove(&code) => LobbyError::InvalidInviteCode]
+                }
+
+                return Ok(Some(MessageFromServer::LobbyAccess(lobby_access(lobby))));
             }
             MessageFromPlayer::LeaveLobby => {
-                self.send_message(player_id, MessageFromServer::YouLeftLobby);
                 self.handle_player_left_lobby(player_id);
+                return Ok(Some(MessageFromServer::YouLeftLobby));
             }
             MessageFromPlayer::SwitchTeam(id, team) => {
                 guards! {
                     [Ok(lobby_id) = in_lobby!()]
                     [Ok(lobby) = lobby_exists!(lobby_id)]
                     [normal_lobby!(lobby)]
-                    [!lobby.settings.players_can_change_team && lobby.leader != player_id => "Team switching is disabled in this lobby."]
-                    [id != player_id && lobby.leader != player_id => "Cannot switch team of other player."]
-                    [!lobby.players.contains_key(&team) => format!("{team} does not exist.")]
-                    [lobby.players.get(&team).unwrap().len() >= lobby.settings.player_limit_per_team => format!("{team} is full.")]
+                    [!lobby.settings.players_can_change_team && lobby.leader != player_id => LobbyError::TeamSwitchingDisabled]
+                    [id != player_id && lobby.leader != player_id => LobbyError::CannotMoveOtherPlayer]
+                    [!lobby.members().any(|p| *p == id) => LobbyError::PlayerNotFound]
+                    [!lobby.players.contains_key(&team) => LobbyError::TeamNotFound(team)]
+                    [lobby.players.get(&team).unwrap().len() >= lobby.settings.player_limit_per_team => LobbyError::TeamFull(team)]
                 }
 
-                for players in lobby.players.values_mut() {
-                    if let Some(pos) = players.iter().position(|p| *p == id) {
-                        players.remove(pos);
-                        break;
-                    }
+                self.update_lobby(lobby_id, LobbyUpdate::PlayerSwitchedTeam(id, team));
+            }
+            MessageFromPlayer::Spectate(id) => {
+                guards! {
+                    [Ok(lobby_id) = in_lobby!()]
+                    [Ok(lobby) = lobby_exists!(lobby_id)]
+                    [normal_lobby!(lobby)]
+                    [id != player_id && lobby.leader != player_id => LobbyError::CannotMoveOtherPlayer]
+                    [!lobby.players.values().flatten().any(|p| *p == id) => LobbyError::PlayerNotFound]
+                    [free_spectator_slots(lobby) == 0 => LobbyError::SpectatorsFull]
                 }
 
-                lobby.players.get_mut(&team).unwrap().push(id);
-
-                self.broadcast_lobby_message(
-                    lobby_id,
-                    None,
-                    MessageFromServer::PlayerSwitchedTeam(id, team),
-                );
+                self.update_lobby(lobby_id, LobbyUpdate::PlayerSpectating(id));
             }
             MessageFromPlayer::GetLobbyInfo(lobby_id) => {
                 guards!(Ok(lobby) = lobby_exists!(lobby_id));
 
-                let message = MessageFromServer::LobbyInfo(lobby.clone());
-                self.send_message(player_id, message);
-            }
-            MessageFromPlayer::GetLobbyList => {
-                let list = self
-                    .lobbies
-                    .values()
-                    .map(|lobby| LobbyShortInfo {
-                        id: lobby.id,
-                        name: lobby.settings.name.clone(),
-                        player_count: lobby.players.values().map(Vec::len).sum(),
-                        max_player_count: lobby.settings.team_count
-                            * lobby.settings.player_limit_per_team,
-                    })
-                    .collect();
-                self.send_message(player_id, MessageFromServer::LobbyList(list));
+                return Ok(Some(MessageFromServer::LobbyInfo(Box::new(
+                    lobby_snapshot(lobby),
+                ))));
+            }
+            MessageFromPlayer::GetLobbyList(LobbyListQuery {
+                filter,
+                sort,
+                cursor,
+            }) => {
+                let lobbies = self.lobbies.values().map(|lobby| LobbyShortInfo {
+                    id: lobby.id,
+                    name: lobby.settings.name.clone(),
+                    map: lobby.settings.map.clone(),
+                    state: lobby.lobby_state.kind(),
+                    leader_name: self
+                        .players
+                        .get(&lobby.leader)
+                        .map(|p| p.player.name.clone())
+                        .unwrap_or_default(),
+                    player_count: lobby.players.values().map(Vec::len).sum(),
+                    max_player_count: lobby.settings.team_count
+                        * lobby.settings.player_limit_per_team,
+                    password_protected: lobby.password.is_some(),
+                });
+                let (list, next) = lobby_list_page(lobbies, &filter, sort, cursor.as_ref());
+                return Ok(Some(MessageFromServer::LobbyList {
+                    after: cursor,
+                    lobbies: list,
+                    next,
+                }));
             }
             MessageFromPlayer::GetPlayerInfo(id) => {
-                match self.players.get(&id) {
-                    Some(player) => {
-                        self.send_message(
-                            player_id,
-                            MessageFromServer::PlayerInfo(player.player.clone()),
-                        );
+                guards!(Some(player) = self.players.get(&id) => LobbyError::PlayerNotFound);
+
+                return Ok(Some(MessageFromServer::PlayerInfo(player.player.clone())));
+            }
+            MessageFromPlayer::Disconnecting => {
+                self.remove_player(player_id);
+            }
+            MessageFromPlayer::SendChat { channel, text } => {
+                let text = text.trim();
+                guards! {
+                    [text.is_empty() => LobbyError::EmptyChatMessage]
+                    [text.chars().count() > MAX_CHAT_MESSAGE_LEN => LobbyError::ChatMessageTooLong(MAX_CHAT_MESSAGE_LEN)]
+                }
+
+                // Find out where the message goes before counting it against the rate limit
+                let (lobby_id, team) = match channel {
+                    ChatChannel::Lobby => (Some(in_lobby!()?), None),
+                    ChatChannel::Team => {
+                        let lobby_id = in_lobby!()?;
+                        // Spectators aren't on a team to talk to
+                        guards!(Some(team) = self.lobbies.get(&lobby_id).and_then(|lobby| {
+                            lobby
+                                .players
+                                .iter()
+                                .find(|(_, players)| players.contains(&player_id))
+                                .map(|(team, _)| *team)
+                        }) => LobbyError::NotOnATeam);
+                        (Some(lobby_id), Some(team))
                     }
-                    None => {
-                        // TODO: figure out what's supposed to be done here
+                    ChatChannel::Whisper(to) => {
+                        guards!(!self.players.contains_key(&to) => LobbyError::PlayerNotFound);
+                        (None, None)
+                    }
+                };
+                let player = self.players.get_mut(&player_id).unwrap();
+                guards!(!player.chat_rate_limit.try_take() => LobbyError::ChatRateLimited);
+
+                let text = match &self.chat_filter {
+                    Some(filter) => filter
+                        .replace_all(text, |c: &regex::Captures| "*".repeat(c[0].chars().count()))
+                        .into_owned(),
+                    None => text.to_owned(),
+                };
+                let message = MessageFromServer::ChatMessage {
+                    from: player.player.clone(),
+                    channel,
+                    text,
+                };
+
+                match (channel, lobby_id, team) {
+                    (ChatChannel::Whisper(to), _, _) => {
+                        self.send_message(to, message.clone());
+                        // Echo it back, so the sender sees its own whispers in the log
+                        if to != player_id {
+                            self.send_message(player_id, message);
+                        }
+                    }
+                    (_, Some(lobby_id), Some(team)) => {
+                        self.broadcast_team_message(lobby_id, team, message);
                     }
+                    (_, Some(lobby_id), None) => {
+                        self.broadcast_lobby_message(lobby_id, None, message);
+                    }
+                    (_, None, _) => unreachable!(),
                 }
             }
-            MessageFromPlayer::Disconnecting => {
-                let _ = self.event_sender.send(Event::ConnectionLost(player_id));
-            }
             MessageFromPlayer::KickPlayer(id) => {
                 guards! {
                     [Ok(lobby_id) = in_lobby!()]
                     [Ok(lobby) = lobby_exists!(lobby_id)]
                     [normal_lobby!(lobby)]
-                    [lobby.leader != player_id => "You are not the lobby leader."]
+                    [lobby.leader != player_id => LobbyError::NotLeader]
+                    [!lobby.members().any(|p| *p == id) => LobbyError::PlayerNotFound]
                 }
 
                 self.send_message(id, MessageFromServer::YouLeftLobby);
@@ -524,68 +1683,62 @@ impl ServerState {
                     [Ok(lobby_id) = in_lobby!()]
                     [Ok(lobby) = lobby_exists!(lobby_id)]
                     [normal_lobby!(lobby)]
-                    [lobby.leader != player_id => "You are not the lobby leader."]
-                    [lobby_settings.name.is_empty() => "Lobby name cannot be empty."]
-                    [lobby_settings.name.chars().all(char::is_whitespace) => "Lobby name cannot be only whitespace."]
-                    [Some(map) = MAPS.iter().find(|map| map.name == lobby_settings.map) => format!("No map {:?} exists.", lobby_settings.map)]
-                    [lobby_settings.team_count < 1 => "There must be at least 1 team."]
-                    // [!(map.min_teams..=map.max_teams).contains(&lobby_settings.team_count) => format!("Map {:?} doesn't support {} teams;\nmust be between {} and {}", map.name, lobby_settings.team_count, map.min_teams, map.max_teams)]
+                    [lobby.leader != player_id => LobbyError::NotLeader]
+                    [lobby_settings.name.is_empty() => LobbyError::EmptyLobbyName]
+                    [lobby_settings.name.chars().all(char::is_whitespace) => LobbyError::EmptyLobbyName]
+                    [Some(map) = self.maps.get(&lobby_settings.map) => LobbyError::UnknownMap(lobby_settings.map.clone())]
+                    [lobby_settings.team_count < 1 => LobbyError::NoTeams]
+                    [!(map.min_teams..=map.max_teams).contains(&lobby_settings.team_count) => LobbyError::UnsupportedTeamCount { min: map.min_teams, max: map.max_teams }]
+                    [lobby_settings.spectator_slots < lobby.spectators.len() => LobbyError::SpectatorSlotsInUse(lobby.spectators.len())]
                 }
 
                 if lobby_settings == lobby.settings {
-                    return;
+                    return Ok(None);
                 }
 
+                let mut players = lobby.players.clone();
                 let mut players_to_reshuffle = vec![];
 
                 match lobby_settings.team_count.cmp(&lobby.settings.team_count) {
                     Ordering::Less => {
                         for team in (lobby_settings.team_count..lobby.settings.team_count).map(Team)
                         {
-                            players_to_reshuffle.append(&mut lobby.players.remove(&team).unwrap());
+                            players_to_reshuffle.append(&mut players.remove(&team).unwrap());
                         }
                     }
                     Ordering::Greater => {
                         for team in (lobby.settings.team_count..lobby_settings.team_count).map(Team)
                         {
-                            lobby.players.insert(team, vec![]);
+                            players.insert(team, vec![]);
                         }
                     }
                     _ => {}
                 }
 
                 if lobby_settings.player_limit_per_team < lobby.settings.player_limit_per_team
-                    || lobby
-                        .players
+                    || players
                         .values()
                         .any(|v| v.len() > lobby_settings.player_limit_per_team)
                 {
-                    for players in lobby.players.values_mut() {
-                        if players.len() > lobby_settings.player_limit_per_team {
+                    for team_players in players.values_mut() {
+                        if team_players.len() > lobby_settings.player_limit_per_team {
                             players_to_reshuffle
-                                .extend(players.drain(lobby_settings.player_limit_per_team..));
+                                .extend(team_players.drain(lobby_settings.player_limit_per_team..));
                         }
                     }
                 }
 
                 for player in players_to_reshuffle {
-                    let team_player_count = (0..lobby_settings.team_count)
-                        .map(|i| (Team(i), lobby.players.get(&Team(i)).unwrap().len()))
-                        .min_by_key(|x| x.1)
-                        .expect("There should always be at least 1 teams");
-
-                    lobby
-                        .players
-                        .get_mut(&team_player_count.0)
-                        .unwrap()
-                        .push(player);
+                    let team = smallest_team(&players, lobby_settings.team_count);
+                    players.get_mut(&team).unwrap().push(player);
                 }
 
-                lobby.settings = lobby_settings.clone();
-                self.broadcast_lobby_message(
+                self.update_lobby(
                     lobby_id,
-                    None,
-                    MessageFromServer::SettingsUpdated(lobby_settings),
+                    LobbyUpdate::SettingsUpdated {
+                        settings: lobby_settings,
+                        players,
+                    },
                 );
             }
             MessageFromPlayer::SwitchPlaces(player_a, player_b) => {
@@ -593,84 +1746,85 @@ impl ServerState {
                     [Ok(lobby_id) = in_lobby!()]
                     [Ok(lobby) = lobby_exists!(lobby_id)]
                     [normal_lobby!(lobby)]
-                    [!lobby.settings.players_can_change_team && lobby.leader != player_id => "Team switching is disabled in this lobby."]
-                    [lobby.leader != player_id => "Non-leader cannot switch places of players."]
+                    [!lobby.settings.players_can_change_team && lobby.leader != player_id => LobbyError::TeamSwitchingDisabled]
+                    [lobby.leader != player_id => LobbyError::NotLeader]
                 }
 
-                let Some(pos_a) = lobby
-                    .players
-                    .iter()
-                    .find_map(|(t, v)| v.iter().position(|p| *p == player_a).map(|i| (*t, i)))
-                else {
-                    self.send_message(
-                        player_id,
-                        MessageFromServer::RequestRefused("Player does not exist".into()),
-                    );
-                    return;
-                };
-                let Some(pos_b) = lobby
-                    .players
-                    .iter()
-                    .find_map(|(t, v)| v.iter().position(|p| *p == player_b).map(|i| (*t, i)))
-                else {
-                    self.send_message(
-                        player_id,
-                        MessageFromServer::RequestRefused("Player does not exist".into()),
-                    );
-                    return;
-                };
-
-                lobby.players.get_mut(&pos_a.0).unwrap()[pos_a.1] = player_b;
-                lobby.players.get_mut(&pos_b.0).unwrap()[pos_b.1] = player_a;
+                let is_member = |id| lobby.members().any(|p| *p == id);
+                if !is_member(player_a) || !is_member(player_b) {
+                    guards!(ret LobbyError::PlayerNotFound);
+                }
 
-                self.broadcast_lobby_message(
-                    lobby_id,
-                    None,
-                    MessageFromServer::PlayersSwitched(player_a, player_b),
-                );
+                self.update_lobby(lobby_id, LobbyUpdate::PlayersSwitched(player_a, player_b));
             }
             MessageFromPlayer::EnterChampSelect => {
                 guards! {
                     [Ok(lobby_id) = in_lobby!()]
                     [Ok(lobby) = lobby_exists!(lobby_id)]
                     [normal_lobby!(lobby)]
-                    [lobby.leader != player_id => "Non-leader cannot trigger champ select."]
+                    [lobby.leader != player_id => LobbyError::NotLeader]
                 }
 
-                let new_state = LobbyState::ChampSelect(ChampSelectState {
-                    available_champs: (1..=100).map(|d| format!("Champ {d}")).collect(),
-                    selected_champs: lobby
-                        .players
-                        .values()
-                        .flatten()
-                        .map(|p| (*p, None))
-                        .collect(),
+                // The leader asked for it, so they have already accepted
+                let check_id = Uuid::new_v4();
+                let state = ReadyCheckState {
+                    id: check_id,
+                    duration: READY_CHECK_DURATION,
+                    accepted: [player_id].into(),
+                };
+                self.update_lobby(lobby_id, LobbyUpdate::ReadyCheckStarted(state));
+                self.schedule(READY_CHECK_DURATION, move |s| {
+                    s.expire_ready_check(lobby_id, check_id)
                 });
+                self.finish_ready_check(lobby_id);
+            }
+            MessageFromPlayer::AcceptReadyCheck => {
+                guards! {
+                    [Ok(lobby_id) = in_lobby!()]
+                    [Ok(lobby) = lobby_exists!(lobby_id)]
+                    [Ok(state) = ready_check!(lobby)]
+                }
 
-                lobby.lobby_state = new_state;
+                if !state.accepted.contains(&player_id) {
+                    self.update_lobby(lobby_id, LobbyUpdate::ReadyCheckAccepted(player_id));
+                    self.finish_ready_check(lobby_id);
+                }
+            }
+            MessageFromPlayer::DeclineReadyCheck => {
+                guards! {
+                    [Ok(lobby_id) = in_lobby!()]
+                    [Ok(lobby) = lobby_exists!(lobby_id)]
+                    [Ok(_) = ready_check!(lobby)]
+                }
 
-                self.broadcast_lobby_message(lobby_id, None, MessageFromServer::ChampSelectEntered);
+                self.update_lobby(lobby_id, LobbyUpdate::ReadyCheckFailed(vec![player_id]));
             }
             MessageFromPlayer::SelectChampion(champion) => {
                 guards! {
                     [Ok(lobby_id) = in_lobby!()]
                     [Ok(lobby) = lobby_exists!(lobby_id)]
                     [Ok(state) = champ_select!(lobby)]
-                    [!state.available_champs.contains(&champion) => "That champion does not exist."]
-                    [state.selected_champs.get(&player_id).unwrap().as_ref().map(|x| x.locked).unwrap_or(false) => "You cannot change locked selection."]
+                    [!state.available_champs.contains(&champion) => LobbyError::UnknownChampion]
                 }
 
-                state.selected_champs.insert(
-                    player_id,
-                    Some(ChampionSelection {
-                        champion: champion.clone(),
-                        locked: false,
-                    }),
-                );
-                self.broadcast_lobby_message(
+                if let Some(draft) = &state.draft {
+                    guards! {
+                        [draft.current_turn().is_none_or(|t| t.player != player_id) => LobbyError::NotYourTurn]
+                        [state.champion_taken(&champion) => LobbyError::ChampionUnavailable]
+                    }
+
+                    self.update_lobby(lobby_id, LobbyUpdate::DraftHovered(champion));
+                    return Ok(None);
+                }
+
+                guards! {
+                    [Some(selection) = state.selected_champs.get(&player_id) => LobbyError::Spectating]
+                    [selection.as_ref().map(|x| x.locked).unwrap_or(false) => LobbyError::SelectionLocked]
+                }
+
+                self.update_lobby(
                     lobby_id,
-                    None,
-                    MessageFromServer::PlayerSelectedChampion(player_id, champion),
+                    LobbyUpdate::PlayerSelectedChampion(player_id, champion),
                 );
             }
             MessageFromPlayer::LockChampSelection => {
@@ -678,34 +1832,144 @@ impl ServerState {
                     [Ok(lobby_id) = in_lobby!()]
                     [Ok(lobby) = lobby_exists!(lobby_id)]
                     [Ok(state) = champ_select!(lobby)]
-                    [!state.selected_champs.get(&player_id).unwrap().is_some() => "Cannot lock empty selection."]
                 }
 
-                state
-                    .selected_champs
-                    .get_mut(&player_id)
-                    .unwrap()
-                    .as_mut()
-                    .unwrap()
-                    .locked = true;
-
-                if state
-                    .selected_champs
-                    .values()
-                    .all(|s| s.as_ref().is_some_and(|s| s.locked))
-                {
+                if let Some(draft) = &state.draft {
+                    guards! {
+                        [draft.current_turn().is_none_or(|t| t.player != player_id) => LobbyError::NotYourTurn]
+                        [Some(champion) = draft.hovered.clone() => LobbyError::NoChampionSelected]
+                    }
+
+                    self.end_draft_turn(lobby_id, Some(champion));
+                    return Ok(None);
+                }
+
+                guards! {
+                    [Some(selection) = state.selected_champs.get(&player_id) => LobbyError::Spectating]
+                    [selection.is_none() => LobbyError::NoChampionSelected]
+                }
+
+                self.update_lobby(lobby_id, LobbyUpdate::ChampSelectionLocked(player_id));
+
+                if self.all_selections_locked(lobby_id) {
                     // All players locked: start game
                     self.start_game(lobby_id);
                 }
+            }
+            MessageFromPlayer::JoinQueue { mode } => {
+                guards! {
+                    [not_in_lobby!()]
+                    [Ok(group) = party_group(&self.parties, &self.players, player_id)]
+                    [group.len() > mode.team_size() => LobbyError::PartyTooLarge(mode.team_size())]
+                }
 
-                self.broadcast_lobby_message(
-                    lobby_id,
-                    None,
-                    MessageFromServer::ChampSelectionLocked(player_id),
+                let mut rating = 0.0;
+                for member in &group {
+                    let member = self.players.get_mut(member).unwrap();
+                    member.in_queue = Some(mode);
+                    rating += member.rating / group.len() as f64;
+                }
+                let entry = QueueEntry {
+                    players: group.clone(),
+                    rating,
+                    joined: Instant::now(),
+                };
+                self.queues.entry(mode).or_default().push(entry);
+                for member in group {
+                    if member != player_id {
+                        let message = self.queue_status(member, mode);
+                        self.send_message(member, message);
+                    }
+                }
+
+                // Look for a match right away, but only after the reply has been sent
+                self.schedule(Duration::ZERO, move |s| s.make_matches(mode));
+                return Ok(Some(self.queue_status(player_id, mode)));
+            }
+            MessageFromPlayer::LeaveQueue => {
+                guards! {
+                    [player.in_queue.is_none() => LobbyError::NotInQueue]
+                }
+
+                self.leave_queue(player_id);
+                return Ok(Some(MessageFromServer::YouLeftQueue));
+            }
+            MessageFromPlayer::InviteToParty(id) => {
+                let (in_party, in_queue, inviter) =
+                    (player.in_party, player.in_queue, player.player.clone());
+                guards! {
+                    [id == player_id || !self.players.contains_key(&id) => LobbyError::PlayerNotFound]
+                    [in_queue.is_some() => LobbyError::PartyBusy]
+                }
+
+                let party_id = match in_party {
+                    Some(party_id) => {
+                        guards! {
+                            [Some(party) = self.parties.get_mut(&party_id) => LobbyError::PartyNotFound]
+                            [party.leader != player_id => LobbyError::NotPartyLeader]
+                            [party.members.iter().any(|m| m.id == id) => LobbyError::AlreadyInParty]
+                            [party.members.len() >= MAX_PARTY_SIZE => LobbyError::PartyFull(MAX_PARTY_SIZE)]
+                        }
+                        party.invites.insert(id);
+                        party_id
+                    }
+                    None => {
+                        // A party of one, until someone accepts
+                        let party_id = PartyId::new();
+                        let party = Party {
+                            id: party_id,
+                            leader: player_id,
+                            members: vec![inviter.clone()],
+                            invites: [id].into(),
+                        };
+                        self.parties.insert(party_id, party);
+                        self.players.get_mut(&player_id).unwrap().in_party = Some(party_id);
+                        self.send_party_update(party_id);
+                        party_id
+                    }
+                };
+                self.send_message(
+                    id,
+                    MessageFromServer::PartyInvite {
+                        party: party_id,
+                        from: inviter,
+                    },
                 );
             }
-            MessageFromPlayer::StartGame => todo!(),
+            MessageFromPlayer::AcceptPartyInvite(party_id) => {
+                let (in_party, in_queue, info) =
+                    (player.in_party, player.in_queue, player.player.clone());
+                guards! {
+                    [in_party.is_some() => LobbyError::AlreadyInParty]
+                    [in_queue.is_some() => LobbyError::AlreadyInQueue]
+                    [Some(party) = self.parties.get_mut(&party_id) => LobbyError::PartyNotFound]
+                    [!party.invites.contains(&player_id) => LobbyError::PartyNotFound]
+                    [party.members.len() >= MAX_PARTY_SIZE => LobbyError::PartyFull(MAX_PARTY_SIZE)]
+                    [self.players.get(&party.leader).is_some_and(|p| p.in_queue.is_some()) => LobbyError::PartyBusy]
+                }
+
+                party.invites.remove(&player_id);
+                party.members.push(info);
+                self.players.get_mut(&player_id).unwrap().in_party = Some(party_id);
+                self.send_party_update(party_id);
+            }
+            MessageFromPlayer::LeaveParty => {
+                guards! {
+                    [player.in_party.is_none() => LobbyError::NotInParty]
+                }
+
+                if player.in_queue.is_some() {
+                    self.send_message(player_id, MessageFromServer::YouLeftQueue);
+                }
+                self.leave_party(player_id);
+                return Ok(Some(MessageFromServer::PartyUpdated(None)));
+            }
+            MessageFromPlayer::StartGame => {
+                return Err(LobbyError::GameStartsAutomatically);
+            }
         }
+
+        Ok(None)
     }
 
     fn handle_player_left_lobby(&mut self, player_id: PlayerId) {
@@ -719,260 +1983,822 @@ impl ServerState {
             return;
         };
 
-        // Remove player from lobby
-        for players in lobby.players.values_mut() {
-            if let Some(pos) = players.iter().position(|p| *p == player_id) {
-                players.remove(pos);
-                break;
-            }
-        }
         player.in_lobby = None;
 
         // If that player was the last player, delete the lobby
-        if lobby.players.values().all(Vec::is_empty) {
+        if lobby.members().all(|p| *p == player_id) {
             self.lobbies.remove(&lobby_id);
 
             // If a game server is running for this lobby, kill it
-            if let Some(kill) = self.game_servers.remove(&lobby_id) {
-                let _ = kill.send(());
+            if let Some(server) = self.game_servers.remove(&lobby_id) {
+                server.kill();
             }
+            self.refresh_presence(player_id);
             return;
         }
 
+        let was_leader = lobby.leader == player_id;
+        self.update_lobby(lobby_id, LobbyUpdate::PlayerLeft(player_id));
+        self.refresh_presence(player_id);
+
         // If that player was the leader, we need to select a new one
-        if lobby.leader == player_id {
+        if was_leader {
             // We don't really care who, so we choose the first one in the list
-            lobby.leader = *lobby.players.values().flatten().next().unwrap();
-            let message = MessageFromServer::LobbyLeaderChanged(lobby.leader);
-            self.broadcast_lobby_message(lobby_id, None, message);
+            let lobby = self.lobbies.get(&lobby_id).unwrap();
+            let new_leader = *lobby.members().next().unwrap();
+            self.update_lobby(lobby_id, LobbyUpdate::LeaderChanged(new_leader));
         }
 
-        self.broadcast_lobby_message(
-            lobby_id,
-            None,
-            MessageFromServer::PlayerLeftYourLobby(player_id),
-        );
+        let lobby = self.lobbies.get(&lobby_id).unwrap();
+        match &lobby.lobby_state {
+            // Leaving during a ready check counts as declining it
+            LobbyState::ReadyCheck(_) => {
+                self.update_lobby(lobby_id, LobbyUpdate::ReadyCheckFailed(vec![player_id]));
+            }
+            // Don't wait for the player's draft turn to run out
+            LobbyState::ChampSelect(ChampSelectState {
+                draft: Some(draft), ..
+            }) if draft
+                .current_turn()
+                .is_some_and(|turn| turn.player == player_id) =>
+            {
+                self.end_draft_turn(lobby_id, None);
+            }
+            // The player might have been the last one yet to lock in a blind pick
+            LobbyState::ChampSelect(ChampSelectState { draft: None, .. })
+                if self.all_selections_locked(lobby_id)
+                    && !self.game_server_pool.waiting.contains(&lobby_id)
+                    && !self.game_servers.contains_key(&lobby_id) =>
+            {
+                self.start_game(lobby_id);
+            }
+            _ => {}
+        }
     }
 
-    fn start_game(&mut self, lobby_id: LobbyId) {
+    /// Moves the lobby on to champ select if every player has accepted its ready check.
+    fn finish_ready_check(&mut self, lobby_id: LobbyId) {
+        let Some(lobby) = self.lobbies.get(&lobby_id) else {
+            return;
+        };
+        let LobbyState::ReadyCheck(check) = &lobby.lobby_state else {
+            return;
+        };
+        if lobby
+            .players
+            .values()
+            .flatten()
+            .all(|p| check.accepted.contains(p))
+        {
+            self.enter_champ_select(lobby_id);
+        }
+    }
+
+    fn enter_champ_select(&mut self, lobby_id: LobbyId) {
         let Some(lobby) = self.lobbies.get(&lobby_id) else {
-            todo!();
+            return;
         };
 
-        let LobbyState::ChampSelect(selections) = &lobby.lobby_state else {
-            todo!();
+        let pick_time = match lobby.settings.pick_time_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let turns = match lobby.settings.champ_select_mode {
+            ChampSelectMode::Blind if self.champions.iter().next().is_none() => None,
+            ChampSelectMode::Blind => Some(None),
+            ChampSelectMode::Draft => draft_turns(
+                &lobby.players,
+                lobby.settings.team_count,
+                self.champions.iter().count(),
+            )
+            .map(Some),
+        };
+        let Some(turns) = turns else {
+            eprintln!("Not enough champions for lobby {lobby_id:?}, disbanding it");
+            self.disband_lobby(lobby_id, Some(DisbandReason::NotEnoughChampions));
+            return;
         };
+        let draft = turns.map(|turns| {
+            Box::new(DraftState {
+                turns,
+                current: 0,
+                hovered: None,
+                bans: HashMap::new(),
+                turn_time: pick_time,
+            })
+        });
+        let state = ChampSelectState {
+            available_champs: self.champions.iter().map(|c| c.id.clone()).collect(),
+            selected_champs: lobby
+                .players
+                .values()
+                .flatten()
+                .map(|p| (*p, None))
+                .collect(),
+            time_left: pick_time,
+            draft,
+            deadline: None,
+        };
+        self.update_lobby(lobby_id, LobbyUpdate::ChampSelectEntered(state));
+        self.schedule_pick_timer(lobby_id);
+    }
 
-        if selections.selected_champs.iter().any(|s| s.1.is_none()) {
-            todo!();
+    /// Schedules [`Self::expire_pick_timer`] for the lobby's current pick deadline, if any.
+    ///
+    /// Call this whenever applying an update started a new pick timer.
+    fn schedule_pick_timer(&self, lobby_id: LobbyId) {
+        if let Some(LobbyState::ChampSelect(ChampSelectState {
+            deadline: Some(deadline),
+            ..
+        })) = self.lobbies.get(&lobby_id).map(|l| &l.lobby_state)
+        {
+            let deadline = *deadline;
+            self.schedule(
+                deadline.saturating_duration_since(Instant::now()),
+                move |s| s.expire_pick_timer(lobby_id, deadline),
+            );
         }
+    }
 
-        // find free port
+    /// Ends the current draft turn, then either starts the next turn or the game.
+    fn end_draft_turn(&mut self, lobby_id: LobbyId, champion: Option<String>) {
+        self.update_lobby(lobby_id, LobbyUpdate::DraftTurnLocked(champion));
+        if self.all_selections_locked(lobby_id) {
+            self.start_game(lobby_id);
+        } else {
+            self.schedule_pick_timer(lobby_id);
+        }
+    }
 
-        let Some(port) = self
-            .options
-            .game_server_port_range
-            .into_iter()
-            .find(|port| !self.used_game_server_ports.contains(port))
-        else {
-            todo!()
+    /// Locks in every player's selection once the pick timer `deadline` runs out,
+    /// giving a random champion to those without one.
+    ///
+    /// In a draft, this only ends the current turn; a ban is skipped if nothing was selected.
+    fn expire_pick_timer(&mut self, lobby_id: LobbyId, deadline: Instant) {
+        let Some(lobby) = self.lobbies.get(&lobby_id) else {
+            return;
+        };
+        let LobbyState::ChampSelect(state) = &lobby.lobby_state else {
+            return;
         };
+        if state.deadline != Some(deadline) {
+            return;
+        }
 
-        // Start game server
+        if let Some(draft) = &state.draft {
+            let Some(turn) = draft.current_turn() else {
+                return;
+            };
+            let champion = match (turn.kind, &draft.hovered) {
+                (_, Some(hovered)) => Some(hovered.clone()),
+                (DraftTurnKind::Ban, None) => None,
+                (DraftTurnKind::Pick, None) => {
+                    let available = state
+                        .available_champs
+                        .iter()
+                        .filter(|c| !state.champion_taken(c))
+                        .collect::<Vec<_>>();
+                    // A pick can't be skipped, or the player would never get a champion
+                    let Some(champion) = available.choose(&mut rand::thread_rng()) else {
+                        self.disband_lobby(lobby_id, Some(DisbandReason::NotEnoughChampions));
+                        return;
+                    };
+                    Some((*champion).clone())
+                }
+            };
+            self.end_draft_turn(lobby_id, champion);
+            return;
+        }
 
-        let (send, mut recv) = tokio::sync::oneshot::channel();
-        self.game_servers.insert(lobby_id, send);
+        let mut updates = vec![];
+        for (player, selection) in &state.selected_champs {
+            match selection {
+                Some(selection) if selection.locked => continue,
+                Some(_) => {}
+                None => {
+                    let Some(champion) = state.available_champs.choose(&mut rand::thread_rng())
+                    else {
+                        continue;
+                    };
+                    updates.push(LobbyUpdate::PlayerSelectedChampion(
+                        *player,
+                        champion.clone(),
+                    ));
+                }
+            }
+            updates.push(LobbyUpdate::ChampSelectionLocked(*player));
+        }
+        for update in updates {
+            self.update_lobby(lobby_id, update);
+        }
 
-        let lobby_token = Uuid::new_v4();
+        if self.all_selections_locked(lobby_id) {
+            self.start_game(lobby_id);
+        }
+    }
 
-        let mut cmdline = vec![];
-        match self.options.game_server_launch_mode {
-            GameServerLaunchMode::Executable => {
-                cmdline.push(self.options.game_server_path.to_string_lossy().to_string());
-            }
-            GameServerLaunchMode::Cargo => {
-                cmdline.extend(
-                    "cargo run --bin=server --"
-                        .split_whitespace()
-                        .map(String::from),
-                );
-            }
+    fn all_selections_locked(&self, lobby_id: LobbyId) -> bool {
+        match self.lobbies.get(&lobby_id).map(|l| &l.lobby_state) {
+            Some(LobbyState::ChampSelect(state)) => state
+                .selected_champs
+                .values()
+                .all(|s| s.as_ref().is_some_and(|s| s.locked)),
+            _ => false,
         }
-        cmdline.push(lobby_token.to_string());
-        cmdline.push(port.to_string());
+    }
 
-        let dir = if self.options.game_server_path.is_dir() {
-            self.options.game_server_path.as_path()
-        } else {
-            self.options.game_server_path.parent().unwrap()
+    /// Fails the ready check `check_id` if it is still running.
+    fn expire_ready_check(&mut self, lobby_id: LobbyId, check_id: Uuid) {
+        let Some(lobby) = self.lobbies.get(&lobby_id) else {
+            return;
         };
-
-        let Ok(mut process) = tokio::process::Command::new(&cmdline[0])
-            .args(&cmdline[1..])
-            .current_dir(dir)
-            .spawn()
-        else {
-            self.broadcast_lobby_message(
-                lobby_id,
-                None,
-                MessageFromServer::RequestRefused(
-                    "Failed to start game server;\nplease restart your game client.".into(),
-                ),
-            );
+        let LobbyState::ReadyCheck(check) = &lobby.lobby_state else {
             return;
         };
+        if check.id != check_id {
+            return;
+        }
 
-        let players = lobby
+        let not_accepted = lobby
             .players
-            .iter()
-            .map(|(k, v)| {
-                (
-                    *k,
-                    v.iter()
-                        .map(|p| PlayerSelection {
-                            player: self.players.get(p).unwrap().player.clone(),
-                            champion: selections
-                                .selected_champs
-                                .get(p)
-                                .unwrap()
-                                .as_ref()
-                                .unwrap()
-                                .champion
-                                .clone(),
-                        })
-                        .collect(),
-                )
+            .values()
+            .flatten()
+            .filter(|p| !check.accepted.contains(p))
+            .copied()
+            .collect();
+        self.update_lobby(lobby_id, LobbyUpdate::ReadyCheckFailed(not_accepted));
+    }
+
+    /// Adds `group`, the player that asked to join and its party, to a lobby.
+    ///
+    /// Once the teams are full, the group joins as spectators instead.
+    fn add_to_lobby(&mut self, lobby_id: LobbyId, player_id: PlayerId, group: &[PlayerId]) {
+        let Some(lobby) = self.lobbies.get(&lobby_id) else {
+            return;
+        };
+        let updates = if free_team_slots(lobby) >= group.len() {
+            teams_for_group(&lobby.players, &lobby.settings, group)
+                .into_iter()
+                .map(|(member, team)| (member, LobbyUpdate::PlayerJoined(member, team)))
+                .collect::<Vec<_>>()
+        } else {
+            group
+                .iter()
+                .map(|member| (*member, LobbyUpdate::PlayerSpectating(*member)))
+                .collect()
+        };
+        for (member, update) in updates {
+            self.players.get_mut(&member).unwrap().in_lobby = Some(lobby_id);
+            self.update_lobby(lobby_id, update);
+            // The player that asked gets this as its reply
+            if member != player_id {
+                self.send_message(member, MessageFromServer::YouJoinedLobby(lobby_id));
+            }
+        }
+    }
+
+    /// Removes the player from its party, which is disbanded once a single member is left.
+    ///
+    /// The party can't stay in the queue without the player, so it leaves the queue as well.
+    fn leave_party(&mut self, player_id: PlayerId) {
+        let Some(party_id) = self.players.get(&player_id).and_then(|p| p.in_party) else {
+            return;
+        };
+        self.leave_queue(player_id);
+        self.players.get_mut(&player_id).unwrap().in_party = None;
+        let Some(party) = self.parties.get_mut(&party_id) else {
+            return;
+        };
+
+        party.members.retain(|m| m.id != player_id);
+        if party.leader == player_id {
+            if let Some(next) = party.members.first() {
+                party.leader = next.id;
+            }
+        }
+
+        if party.members.len() > 1 {
+            self.send_party_update(party_id);
+            return;
+        }
+        let party = self.parties.remove(&party_id).unwrap();
+        for member in party.members {
+            if let Some(player) = self.players.get_mut(&member.id) {
+                player.in_party = None;
+            }
+            self.send_message(member.id, MessageFromServer::PartyUpdated(None));
+        }
+    }
+
+    fn send_party_update(&mut self, party_id: PartyId) {
+        let Some(party) = self.parties.get(&party_id) else {
+            return;
+        };
+        let message = MessageFromServer::PartyUpdated(Some(party.clone()));
+        let members = party.members.iter().map(|m| m.id).collect::<Vec<_>>();
+        for member in members {
+            self.send_message(member, message.clone());
+        }
+    }
+
+    /// Removes the player from the queue, along with everyone queued together with it.
+    ///
+    /// The others are told about it; the player itself is not.
+    fn leave_queue(&mut self, player_id: PlayerId) {
+        let Some(mode) = self.players.get(&player_id).and_then(|p| p.in_queue) else {
+            return;
+        };
+        let Some(queue) = self.queues.get_mut(&mode) else {
+            return;
+        };
+        let Some(index) = queue.iter().position(|e| e.players.contains(&player_id)) else {
+            return;
+        };
+        let entry = queue.remove(index);
+        for player in entry.players {
+            if let Some(p) = self.players.get_mut(&player) {
+                p.in_queue = None;
+            }
+            if player != player_id {
+                self.send_message(player, MessageFromServer::YouLeftQueue);
+            }
+        }
+    }
+
+    /// The [`MessageFromServer::YouJoinedQueue`] telling a queued player how it is doing.
+    fn queue_status(&self, player_id: PlayerId, mode: QueueMode) -> MessageFromServer {
+        let waited = self
+            .queues
+            .get(&mode)
+            .and_then(|q| q.iter().find(|e| e.players.contains(&player_id)))
+            .map_or(Duration::ZERO, |e| e.joined.elapsed());
+        let expected_wait = self
+            .queue_times
+            .get(&mode)
+            .filter(|times| !times.is_empty())
+            .map(|times| times.iter().sum::<Duration>() / times.len() as u32);
+        MessageFromServer::YouJoinedQueue {
+            mode,
+            expected_wait,
+            waited,
+        }
+    }
+
+    /// Looks for matches in every queue, then schedules itself to run again.
+    ///
+    /// Matches are also looked for whenever a player joins a queue,
+    /// but players that are far apart in rating can only be matched after waiting for a while.
+    fn matchmaking_tick(&mut self) {
+        for mode in QueueMode::ALL {
+            self.make_matches(mode);
+        }
+        self.schedule(MATCHMAKING_INTERVAL, Self::matchmaking_tick);
+    }
+
+    /// Makes as many matches as possible out of the players queued for `mode`.
+    fn make_matches(&mut self, mode: QueueMode) {
+        loop {
+            let Some(queue) = self.queues.get_mut(&mode) else {
+                return;
+            };
+            let Some(team_indices) = find_match(queue, mode.team_size(), Instant::now()) else {
+                return;
+            };
+
+            // Remove from the back, so the other indices stay valid
+            let mut indices = team_indices.iter().flatten().copied().collect::<Vec<_>>();
+            indices.sort_unstable_by(|a, b| b.cmp(a));
+            let mut entries = indices
+                .into_iter()
+                .map(|i| (i, queue.remove(i)))
+                .collect::<HashMap<_, _>>();
+            let teams = team_indices.map(|indices| {
+                indices
+                    .into_iter()
+                    .map(|i| entries.remove(&i).unwrap())
+                    .collect::<Vec<_>>()
+            });
+            self.create_match(mode, teams);
+        }
+    }
+
+    /// Puts matched players into a new lobby, and starts its champ select.
+    fn create_match(&mut self, mode: QueueMode, teams: [Vec<QueueEntry>; 2]) {
+        let times = self.queue_times.entry(mode).or_default();
+        for entry in teams.iter().flatten() {
+            times.extend(entry.players.iter().map(|_| entry.joined.elapsed()));
+        }
+        while times.len() > QUEUE_TIME_SAMPLES {
+            times.pop_front();
+        }
+
+        let players: HashMap<Team, Vec<PlayerId>> = teams
+            .iter()
+            .enumerate()
+            .map(|(i, entries)| {
+                let players = entries.iter().flat_map(|e| e.players.iter().copied());
+                (Team(i), players.collect())
             })
             .collect();
+        let everyone = players.values().flatten().copied().collect::<Vec<_>>();
+
+        let lobby_id = LobbyId::new();
+        let lobby = Lobby {
+            id: lobby_id,
+            settings: LobbySettings {
+                name: format!("{mode} Match"),
+                map: DEFAULT_MAP.into(),
+                team_count: 2,
+                player_limit_per_team: mode.team_size(),
+                players_can_change_team: false,
+                lobby_is_open: false,
+                pick_time_secs: DEFAULT_PICK_TIME_SECS,
+                champ_select_mode: mode.champ_select_mode(),
+                spectator_slots: 0,
+                spectator_delay_secs: 0,
+            },
+            leader: everyone[0],
+            players,
+            spectators: vec![],
+            lobby_state: LobbyState::Normal,
+            revision: 0,
+            password: None,
+            invite_codes: HashSet::new(),
+            rated: true,
+        };
+        self.lobbies.insert(lobby_id, lobby);
+        println!("Matched {} players for {mode}", everyone.len());
 
-        let connect_task = async move {
-            let x: anyhow::Result<MessageFromGameServerToLobby> = try {
-                let client = Endpoint::client(
-                    ClientConfig::builder()
-                        .with_bind_address_v6(
-                            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0),
-                            Ipv6DualStackConfig::Allow,
-                        )
-                        .with_no_cert_validation()
-                        .build(),
-                )?;
-                println!("Lobby server trying to connect to game server...");
-                let conn = client.connect(format!("https://localhost:{port}")).await?;
-                println!("Lobby server connected! Writing message...");
-                conn.open_uni()
-                    .await?
-                    .await?
-                    .write_message(MessageFromLobbyToGameServer::LobbyInitialMessage {
-                        token: lobby_token,
-                        players,
-                    })
-                    .await?;
-                println!("Message written! Reading message...");
-                conn.accept_uni().await?.read_message_framed().await?
+        for player_id in everyone {
+            if let Some(player) = self.players.get_mut(&player_id) {
+                player.in_queue = None;
+                player.in_lobby = Some(lobby_id);
+            }
+            self.send_message(player_id, MessageFromServer::YouJoinedLobby(lobby_id));
+        }
+        self.enter_champ_select(lobby_id);
+    }
+
+    /// Applies an update to a lobby and broadcasts it to the lobby's members.
+    ///
+    /// All changes to a lobby after its creation should go through here,
+    /// so that clients can keep their copy in sync by applying the same updates.
+    fn update_lobby(&mut self, lobby_id: LobbyId, update: LobbyUpdate) {
+        let Some(lobby) = self.lobbies.get_mut(&lobby_id) else {
+            return;
+        };
+        let message = MessageFromServer::LobbyUpdated {
+            lobby: lobby_id,
+            revision: lobby.revision + 1,
+            update,
+        };
+        lobby.apply(&message);
+        let players = lobby.members().copied().collect::<Vec<_>>();
+        self.broadcast_lobby_message(lobby_id, None, message);
+        for player in players {
+            self.refresh_presence(player);
+        }
+    }
+
+    /// Starts the game of a lobby whose champ select is over, as soon as a game server is idle.
+    fn start_game(&mut self, lobby_id: LobbyId) {
+        self.game_server_pool.waiting.push_back(lobby_id);
+        self.assign_game_servers();
+        self.refill_game_server_pool();
+    }
+
+    /// Hands idle game servers to the lobbies waiting for one.
+    fn assign_game_servers(&mut self) {
+        while !self.game_server_pool.idle.is_empty() {
+            let Some(lobby_id) = self.game_server_pool.waiting.pop_front() else {
+                break;
             };
-            println!("Message read!");
-            x
+            // The lobby might have been abandoned while waiting
+            if !self.all_selections_locked(lobby_id) || self.game_servers.contains_key(&lobby_id) {
+                continue;
+            }
+            let server = self.take_idle_game_server().unwrap();
+            self.run_match(lobby_id, server);
+        }
+        self.report_game_servers();
+    }
+
+    /// Takes the idle game server on the least busy machine, to spread matches across agents,
+    /// preferring machines in our region.
+    fn take_idle_game_server(&mut self) -> Option<GameServer> {
+        let load = |location: &Location| {
+            let running = self
+                .game_servers
+                .values()
+                .filter(|server| server.location.machine() == location.machine())
+                .count();
+            let capacity = match location {
+                Location::Local { .. } => self.game_server_ports.capacity(),
+                Location::Remote { capacity, .. } => usize::from(*capacity),
+            };
+            running as f64 / capacity.max(1) as f64
         };
+        let region = &self.options.region;
+        let idle = &self.game_server_pool.idle;
+        let index = (0..idle.len()).min_by(|&a, &b| {
+            let (a, b) = (&idle[a].location, &idle[b].location);
+            load(a)
+                .total_cmp(&load(b))
+                .then_with(|| b.is_in_region(region).cmp(&a.is_in_region(region)))
+        })?;
+        Some(self.game_server_pool.idle.swap_remove(index))
+    }
+
+    /// Starts game servers until enough of them are idle, as far as there are free ports.
+    fn refill_game_server_pool(&mut self) {
+        let lobbies = &self.lobbies;
+        let pool = &mut self.game_server_pool;
+        pool.waiting.retain(|id| lobbies.contains_key(id));
+        let wanted = self.options.game_server_pool_size + pool.waiting.len();
 
+        while self.game_server_pool.idle.len() + self.game_server_pool.starting < wanted {
+            let Some(ports) = self.game_server_ports.allocate() else {
+                break;
+            };
+            self.start_game_server(ports);
+        }
+        self.refuse_waiting_lobbies();
+        self.report_game_servers();
+    }
+
+    /// Sends the lobbies waiting for a game server back to the lobby list
+    /// when none will become idle for them, because every free port is taken by something else.
+    fn refuse_waiting_lobbies(&mut self) {
+        let pool = &self.game_server_pool;
+        if !pool.idle.is_empty() || pool.starting > 0 || !self.game_servers.is_empty() {
+            return;
+        }
+        while let Some(lobby_id) = self.game_server_pool.waiting.pop_front() {
+            eprintln!("No port free for a game server, refusing lobby {lobby_id:?}");
+            self.disband_lobby(lobby_id, Some(DisbandReason::NoGameServerAvailable));
+        }
+    }
+
+    fn report_game_servers(&mut self) {
+        self.game_server_pool.report(self.game_servers.len());
+    }
+
+    /// Launches a game server and adds it to the pool once it listens.
+    fn start_game_server(&mut self, ports: GameServerPorts) {
+        self.game_server_pool.starting += 1;
+
+        let port = ports.control;
+        let token = Uuid::new_v4();
+        let process = pool::launch(&self.options, ports, token);
         let s = self.event_sender.clone();
 
         tokio::spawn(async move {
-            let wait_for_exit =
-                async move |recv: &mut tokio::sync::oneshot::Receiver<()>,
-                            process: &mut tokio::process::Child| {
-                    tokio::select! {
-                        _ = recv => {
-                            eprintln!("Killing server");
-                            process.kill().await.unwrap();
-                            process.wait().await
+            let started = match process {
+                Ok(mut process) => {
+                    let connection = tokio::select! {
+                        connection = pool::connect(port) => connection,
+                        exit = process.wait() => {
+                            Err(anyhow::Error::new(exit).context("Exited early"))
                         }
-                        exit = process.wait() => exit
-                    }
-                };
-
-            let on_exit = |exit: std::io::Result<ExitStatus>| {
-                println!("Game server exited");
-                if exit.is_ok_and(|c| c.success()) {
-                    s.send(Event::Callback(Box::new(move |s| {
-                        let Some(lobby) = s.lobbies.get_mut(&lobby_id) else {
-                            return;
-                        };
-                        let players: Vec<_> = lobby.players.values().flatten().copied().collect();
-                        for player in players {
-                            s.send_message(player, MessageFromServer::YouLeftLobby);
-                            s.handle_player_left_lobby(player);
+                    };
+                    match connection {
+                        Ok(connection) => Ok((process, Arc::new(connection))),
+                        Err(e) => {
+                            process.kill().await;
+                            Err(e)
                         }
-                    })))
-                    .unwrap();
-                } else {
-                    // Failed!
+                    }
+                }
+                Err(e) => Err(e.into()),
+            };
+            let (mut process, connection) = match started {
+                Ok(started) => started,
+                Err(e) => {
+                    eprintln!("Game server on port {port} did not start: {e:#}");
+                    let ports_in_use = matches!(e.downcast_ref(), Some(GameServerExit::PortsInUse));
                     s.send(Event::Callback(Box::new(move |s| {
-                        s.broadcast_lobby_message(
-                            lobby_id,
-                            None,
-                            MessageFromServer::RequestRefused("Failed to start game server".into()),
-                        );
-                        let Some(lobby) = s.lobbies.get_mut(&lobby_id) else {
-                            eprintln!("????????");
+                        s.game_server_pool.starting -= 1;
+                        s.game_server_ports.release(ports);
+                        if ports_in_use {
+                            // The allocator moves on to the next ports
+                            s.refill_game_server_pool();
                             return;
-                        };
-                        let players: Vec<_> = lobby.players.values().flatten().copied().collect();
-                        for player in players {
-                            s.send_message(player, MessageFromServer::YouLeftLobby);
-                            s.handle_player_left_lobby(player);
                         }
+                        // Not refilling the pool here, so a broken game server isn't launched
+                        // over and over
+                        s.refuse_waiting_lobbies();
+                        s.report_game_servers();
                     })))
                     .unwrap();
+                    return;
                 }
-                s.send(Event::Callback(Box::new(move |s| {
-                    s.game_servers.remove(&lobby_id);
-                })))
-                .unwrap();
             };
 
-            let x = tokio::select! {
-                exit = wait_for_exit(&mut recv, &mut process) => {
-                    on_exit(exit);
-                    return;
+            let (kill, mut killed) = tokio::sync::oneshot::channel();
+            let location = Location::Local { port };
+            let server = GameServer::new(location, token, connection.clone(), kill);
+            s.send(Event::Callback(Box::new(move |s| {
+                s.game_server_pool.starting -= 1;
+                s.game_server_pool.idle.push(server);
+                s.assign_game_servers();
+            })))
+            .unwrap();
+
+            tokio::select! {
+                _ = &mut killed => {
+                    eprintln!("Killing game server on port {port}");
+                    process.kill().await;
                 }
-                x = connect_task => {
-                    x
+                exit = process.wait() => println!("Game server on port {port} exited: {exit}"),
+            }
+            let _ = s.send(Event::Callback(Box::new(move |s| {
+                s.game_server_ports.release(ports);
+                // A match running on it fails on its own once the connection closes
+                s.game_server_pool
+                    .idle
+                    .retain(|server| server.token != token);
+                s.refill_game_server_pool();
+            })));
+        });
+    }
+
+    /// Adds a game server registered by an agent to the pool, until the agent disconnects.
+    fn accept_game_server_agent(&mut self, connection: Connection) {
+        let secret = self.options.agent_secret.clone();
+        let s = self.event_sender.clone();
+
+        tokio::spawn(async move {
+            let connection = Arc::new(connection);
+            let (token, location) = match pool::accept_agent(&connection, secret.as_deref()).await {
+                Ok(registration) => registration,
+                Err(e) => {
+                    eprintln!("Game server agent refused: {e:#}");
+                    connection.close(VarInt::from_u32(0), b"refused");
+                    return;
                 }
             };
+            let name = location.to_string();
+            println!("Game server {name} registered");
 
-            match x {
-                Ok(x) => {
-                    // Success!
-                    let MessageFromGameServerToLobby::PlayerTokensGenerated { players } = x;
-                    s.send(Event::Callback(Box::new(move |s| {
-                        for (player, token) in players {
-                            s.send_message(player, MessageFromServer::GameStarted(token))
-                                .unwrap();
-                        }
-                    })))
-                    .unwrap();
-                }
-                Err(e) => {
-                    // Error!
-                    eprintln!("Error: {e}");
-                    process.kill().await.unwrap();
+            let (kill, mut killed) = tokio::sync::oneshot::channel();
+            let server = GameServer::new(location, token, connection.clone(), kill);
+            s.send(Event::Callback(Box::new(move |s| {
+                s.game_server_pool.idle.push(server);
+                s.assign_game_servers();
+            })))
+            .unwrap();
+
+            tokio::select! {
+                _ = &mut killed => {
+                    eprintln!("Dropping game server {name}");
+                    connection.close(VarInt::from_u32(0), b"killed");
                 }
+                _ = connection.closed() => println!("Game server {name} disconnected"),
             }
-            on_exit(wait_for_exit(&mut recv, &mut process).await);
+            let _ = s.send(Event::Callback(Box::new(move |s| {
+                // A match running on it fails on its own once the connection closes
+                s.game_server_pool
+                    .idle
+                    .retain(|server| server.token != token);
+                s.refill_game_server_pool();
+            })));
         });
     }
 
-    fn send_message(
-        &mut self,
-        player_id: PlayerId,
-        message: MessageFromServer,
-    ) -> Option<JoinHandle<()>> {
-        let conn = self.players.get(&player_id).map(|p| p.conn.clone())?;
-        Some(tokio::spawn(async move {
-            let _: anyhow::Result<()> = try {
-                conn.open_uni().await?.await?.write_message(message).await?;
+    /// Plays the game of a lobby on an idle game server.
+    fn run_match(&mut self, lobby_id: LobbyId, server: GameServer) {
+        let lobby = self.lobbies.get(&lobby_id).unwrap();
+        let LobbyState::ChampSelect(selections) = &lobby.lobby_state else {
+            unreachable!("all selections are locked");
+        };
+
+        let players = lobby
+            .players
+            .iter()
+            .map(|(k, v)| {
+                (
+                    *k,
+                    v.iter()
+                        .map(|p| PlayerSelection {
+                            player: self.players.get(p).unwrap().player.clone(),
+                            champion: selections
+                                .selected_champs
+                                .get(p)
+                                .unwrap()
+                                .as_ref()
+                                .unwrap()
+                                .champion
+                                .clone(),
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        let spectators = lobby
+            .spectators
+            .iter()
+            .map(|p| self.players.get(p).unwrap().player.clone())
+            .collect();
+        let spectator_delay = Duration::from_secs(lobby.settings.spectator_delay_secs);
+        let message = MessageFromLobbyToGameServer::LobbyInitialMessage {
+            token: server.token,
+            players,
+            spectators,
+            spectator_delay,
+        };
+
+        let connection = server.connection.clone();
+        self.game_servers.insert(lobby_id, server);
+
+        let s = self.event_sender.clone();
+        tokio::spawn(async move {
+            let result = play_match(&connection, lobby_id, message, &s).await;
+            let _ = s.send(Event::Callback(Box::new(move |s| {
+                s.finish_match(lobby_id, result);
+            })));
+        });
+    }
+
+    /// Takes the lobby out of its game once it is over,
+    /// returning the game server to the pool if it is still healthy.
+    /// Lobbies whose game could not be played are sent back to the lobby browser.
+    fn finish_match(&mut self, lobby_id: LobbyId, result: anyhow::Result<MatchOutcome>) {
+        let Some(server) = self.game_servers.remove(&lobby_id) else {
+            // The lobby was abandoned, which killed its game server
+            return;
+        };
+        match result {
+            Ok(MatchOutcome::Finished(result)) => {
+                self.record_match(lobby_id, result);
+                self.game_server_pool.idle.push(server);
+                self.update_lobby(lobby_id, LobbyUpdate::GameEnded);
+                // Matchmaking puts a lobby together for a single match,
+                // while custom lobbies stay for the next one
+                if self.lobbies.get(&lobby_id).is_some_and(|lobby| lobby.rated) {
+                    self.disband_lobby(lobby_id, None);
+                }
+            }
+            Ok(MatchOutcome::Refused(reason)) => {
+                eprintln!(
+                    "Game server {} refused the match: {reason}",
+                    server.location
+                );
+                self.game_server_pool.idle.push(server);
+                self.disband_lobby(lobby_id, Some(DisbandReason::MatchRefused));
+            }
+            Err(e) => {
+                eprintln!("Game server {} failed: {e:#}", server.location);
+                server.kill();
+                self.disband_lobby(lobby_id, Some(DisbandReason::GameServerFailed));
+            }
+        }
+
+        self.assign_game_servers();
+        self.refill_game_server_pool();
+    }
+
+    /// Sends every member of a lobby back to the lobby list, which closes the lobby.
+    /// Members are told the `reason`, if any.
+    fn disband_lobby(&mut self, lobby_id: LobbyId, reason: Option<DisbandReason>) {
+        if let Some(lobby) = self.lobbies.get(&lobby_id) {
+            let players: Vec<_> = lobby.members().copied().collect();
+            let message = match reason {
+                Some(reason) => MessageFromServer::LobbyDisbanded(reason),
+                None => MessageFromServer::YouLeftLobby,
             };
-        }))
+            for player in players {
+                self.send_message(player, message.clone());
+                self.handle_player_left_lobby(player);
+            }
+        }
+    }
+
+    fn send_message(&mut self, player_id: PlayerId, message: MessageFromServer) {
+        self.send_server_message(
+            player_id,
+            ServerMessage {
+                in_reply_to: None,
+                message,
+            },
+        );
+    }
+
+    fn reply(&mut self, player_id: PlayerId, request: RequestId, message: MessageFromServer) {
+        self.send_server_message(
+            player_id,
+            ServerMessage {
+                in_reply_to: Some(request),
+                message,
+            },
+        );
+    }
+
+    fn send_server_message(&mut self, player_id: PlayerId, message: ServerMessage) {
+        let Some(player) = self.players.get(&player_id) else {
+            return;
+        };
+        let frame: Arc<[u8]> = encode_message(&message).unwrap().into();
+        let _ = player.outgoing.send(frame);
     }
 
     fn broadcast_lobby_message(
@@ -980,54 +2806,59 @@ impl ServerState {
         lobby_id: LobbyId,
         exclude_player: Option<PlayerId>,
         message: MessageFromServer,
-    ) -> Vec<JoinHandle<()>> {
+    ) {
         let Some(lobby) = self.lobbies.get(&lobby_id) else {
-            return vec![];
+            return;
         };
-        let message = serde_json::to_vec_pretty(&message).unwrap();
-        let message: Arc<[u8]> = message.into();
-        lobby
-            .players
-            .values()
-            .flatten()
-            .filter_map(|player| {
-                if Some(*player) == exclude_player {
-                    return None;
-                }
-                let conn = self.players.get(player).map(|p| p.conn.clone())?;
-                let message = message.clone();
-                Some(tokio::spawn(async move {
-                    let _: anyhow::Result<()> = try {
-                        conn.open_uni()
-                            .await?
-                            .await?
-                            .write_message_raw(&message)
-                            .await?;
-                    };
-                }))
-            })
-            .collect()
+        let message = ServerMessage {
+            in_reply_to: None,
+            message,
+        };
+        let frame: Arc<[u8]> = encode_message(&message).unwrap().into();
+        for player in lobby.members() {
+            if Some(*player) == exclude_player {
+                continue;
+            }
+            if let Some(player) = self.players.get(player) {
+                let _ = player.outgoing.send(frame.clone());
+            }
+        }
     }
 
-    fn broadcast_global_message(&mut self, message: MessageFromServer) -> Vec<JoinHandle<()>> {
-        let message = serde_json::to_vec_pretty(&message).unwrap();
-        let message: Arc<[u8]> = message.into();
-        self.players
-            .values()
-            .map(|player| {
-                let conn = player.conn.clone();
-                let message = message.clone();
-                tokio::spawn(async move {
-                    let _: anyhow::Result<()> = try {
-                        conn.open_uni()
-                            .await?
-                            .await?
-                            .write_message_raw(&message)
-                            .await?;
-                    };
-                })
-            })
-            .collect()
+    fn broadcast_team_message(
+        &mut self,
+        lobby_id: LobbyId,
+        team: Team,
+        message: MessageFromServer,
+    ) {
+        let Some(players) = self
+            .lobbies
+            .get(&lobby_id)
+            .and_then(|lobby| lobby.players.get(&team))
+        else {
+            return;
+        };
+        let message = ServerMessage {
+            in_reply_to: None,
+            message,
+        };
+        let frame: Arc<[u8]> = encode_message(&message).unwrap().into();
+        for player in players {
+            if let Some(player) = self.players.get(player) {
+                let _ = player.outgoing.send(frame.clone());
+            }
+        }
+    }
+
+    fn broadcast_global_message(&mut self, message: MessageFromServer) {
+        let message = ServerMessage {
+            in_reply_to: None,
+            message,
+        };
+        let frame: Arc<[u8]> = encode_message(&message).unwrap().into();
+        for player in self.players.values() {
+            let _ = player.outgoing.send(frame.clone());
+        }
     }
 }
 
@@ -1059,3 +2890,305 @@ impl ServerState {
 //         Ok(())
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A lobby server with the game's champions and maps, but no ports to start game servers on.
+    fn state() -> ServerState {
+        let options = Options::parse_from([
+            "lobby-server",
+            "executable",
+            "server",
+            "40000",
+            "--game-server-pool-size",
+            "0",
+        ]);
+        ServerState::new(
+            options,
+            AccountStore::open(std::path::Path::new(":memory:")).unwrap(),
+            None,
+            ChampionRegistry::load("../assets/champions").unwrap(),
+            MapRegistry::load("../assets/maps").unwrap(),
+        )
+    }
+
+    /// Adds a player as if they had just signed in.
+    fn connect(state: &mut ServerState) -> PlayerId {
+        let id = PlayerId::new();
+        let (outgoing, _) = tokio::sync::mpsc::unbounded_channel();
+        let player = PlayerInfoWithConn {
+            player: PlayerInfo {
+                id,
+                name: format!("Player {}", state.players.len()),
+            },
+            in_lobby: None,
+            in_queue: None,
+            rating: accounts::DEFAULT_RATING,
+            in_party: None,
+            friends: vec![],
+            friend_requests: vec![],
+            presence: Presence::Offline,
+            outgoing,
+            session: tokio::spawn(async {}),
+            chat_rate_limit: ChatRateLimit::new(),
+            resume_token: ResumeToken::new(),
+            disconnected_since: None,
+        };
+        state.players.insert(id, player);
+        id
+    }
+
+    fn request(state: &mut ServerState, player: PlayerId, msg: MessageFromPlayer) {
+        state.handle_request(player, msg).unwrap();
+    }
+
+    /// Creates a lobby of `players`, the first one leading it, and takes it into champ select.
+    fn champ_select(state: &mut ServerState, players: &[PlayerId], pick_time_secs: u64) -> LobbyId {
+        let leader = players[0];
+        request(state, leader, MessageFromPlayer::CreateLobby);
+        let lobby_id = state.players[&leader].in_lobby.unwrap();
+        let mut settings = state.lobbies[&lobby_id].settings.clone();
+        settings.pick_time_secs = pick_time_secs;
+        request(state, leader, MessageFromPlayer::UpdateSettings(settings));
+        for player in &players[1..] {
+            request(state, *player, MessageFromPlayer::JoinLobby(lobby_id, None));
+        }
+        request(state, leader, MessageFromPlayer::EnterChampSelect);
+        for player in &players[1..] {
+            request(state, *player, MessageFromPlayer::AcceptReadyCheck);
+        }
+        assert_eq!(
+            state.lobbies[&lobby_id].lobby_state.kind(),
+            LobbyStateKind::ChampSelect
+        );
+        lobby_id
+    }
+
+    fn teams(sizes: &[usize]) -> HashMap<Team, Vec<PlayerId>> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| (Team(i), (0..*size).map(|_| PlayerId::new()).collect()))
+            .collect()
+    }
+
+    fn order(turns: &[DraftTurn], kind: DraftTurnKind) -> Vec<usize> {
+        turns
+            .iter()
+            .filter(|turn| turn.kind == kind)
+            .map(|turn| turn.team.0)
+            .collect()
+    }
+
+    fn short_info(name: &str, player_count: usize) -> LobbyShortInfo {
+        LobbyShortInfo {
+            id: LobbyId::new(),
+            name: name.into(),
+            map: DEFAULT_MAP.into(),
+            state: LobbyStateKind::Normal,
+            leader_name: "leader".into(),
+            player_count,
+            max_player_count: 10,
+            password_protected: false,
+        }
+    }
+
+    /// Every page in order, starting without a cursor.
+    fn all_pages(lobbies: &[LobbyShortInfo], sort: LobbySort) -> Vec<Vec<LobbyShortInfo>> {
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let (page, next) = lobby_list_page(
+                lobbies.to_vec(),
+                &LobbyFilter::default(),
+                sort,
+                cursor.as_ref(),
+            );
+            pages.push(page);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn lobby_list_pages_through_equal_lobbies() {
+        // All equal under every sort, so only the lobby ID tells them apart
+        let lobbies = (0..2 * LOBBY_LIST_PAGE_SIZE + 1)
+            .map(|_| short_info("Same", 3))
+            .collect::<Vec<_>>();
+
+        for sort in LobbySort::ALL {
+            let pages = all_pages(&lobbies, sort);
+            assert_eq!(
+                pages.iter().map(Vec::len).collect::<Vec<_>>(),
+                [LOBBY_LIST_PAGE_SIZE, LOBBY_LIST_PAGE_SIZE, 1]
+            );
+            let mut seen = pages.iter().flatten().map(|l| l.id).collect::<Vec<_>>();
+            seen.sort();
+            seen.dedup();
+            assert_eq!(seen.len(), lobbies.len());
+        }
+    }
+
+    #[test]
+    fn lobby_list_is_sorted() {
+        let lobbies = vec![
+            short_info("Bravo", 2),
+            short_info("alpha", 5),
+            short_info("Charlie", 2),
+        ];
+        let names = |sort| {
+            all_pages(&lobbies, sort)
+                .concat()
+                .into_iter()
+                .map(|l| l.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(LobbySort::Name), ["Bravo", "Charlie", "alpha"]);
+        assert_eq!(names(LobbySort::PlayerCount)[0], "alpha");
+    }
+
+    #[test]
+    fn lobby_list_cursor_survives_removed_lobby() {
+        let lobbies = (0..LOBBY_LIST_PAGE_SIZE + 10)
+            .map(|i| short_info(&format!("Lobby {i:03}"), 0))
+            .collect::<Vec<_>>();
+        let (first, next) = lobby_list_page(
+            lobbies.clone(),
+            &LobbyFilter::default(),
+            LobbySort::Name,
+            None,
+        );
+        let next = next.unwrap();
+
+        // The lobby the cursor points at is gone by the time the next page is fetched
+        let remaining = lobbies.into_iter().filter(|l| l.id != next.id);
+        let (second, _) = lobby_list_page(
+            remaining,
+            &LobbyFilter::default(),
+            LobbySort::Name,
+            Some(&next),
+        );
+
+        assert_eq!(first.len(), LOBBY_LIST_PAGE_SIZE);
+        assert_eq!(second.len(), 10);
+        assert_eq!(second[0].name, format!("Lobby {LOBBY_LIST_PAGE_SIZE:03}"));
+    }
+
+    #[test]
+    fn draft_picks_snake() {
+        let players = teams(&[3, 3]);
+        let turns = draft_turns(&players, 2, 12).unwrap();
+
+        assert_eq!(order(&turns, DraftTurnKind::Ban), [0, 1, 0, 1, 0, 1]);
+        assert_eq!(order(&turns, DraftTurnKind::Pick), [0, 1, 1, 0, 0, 1]);
+        // Bans come first, and every player gets one ban and one pick
+        assert!(turns[..6].iter().all(|t| t.kind == DraftTurnKind::Ban));
+        for player in players.values().flatten() {
+            assert_eq!(turns.iter().filter(|t| t.player == *player).count(), 2);
+        }
+    }
+
+    #[test]
+    fn draft_skips_missing_players() {
+        let turns = draft_turns(&teams(&[2, 1]), 2, 6).unwrap();
+
+        assert_eq!(order(&turns, DraftTurnKind::Pick), [0, 1, 0]);
+    }
+
+    #[test]
+    fn draft_drops_bans_for_small_roster() {
+        let turns = draft_turns(&teams(&[3, 3]), 2, 6).unwrap();
+
+        assert_eq!(turns.len(), 6);
+        assert!(turns.iter().all(|t| t.kind == DraftTurnKind::Pick));
+    }
+
+    #[test]
+    fn draft_needs_a_champion_per_player() {
+        assert!(draft_turns(&teams(&[3, 3]), 2, 5).is_none());
+        assert!(draft_turns(&teams(&[1, 1]), 2, 0).is_none());
+        assert_eq!(draft_turns(&teams(&[0, 0]), 2, 0).map(|t| t.len()), Some(0));
+    }
+
+    #[tokio::test]
+    async fn blind_pick_starts_when_the_last_unlocked_player_leaves() {
+        let mut state = state();
+        let (locked, afk) = (connect(&mut state), connect(&mut state));
+        let lobby_id = champ_select(&mut state, &[locked, afk], 0);
+        let champion = state.champions.iter().next().unwrap().id.clone();
+        request(
+            &mut state,
+            locked,
+            MessageFromPlayer::SelectChampion(champion),
+        );
+        request(&mut state, locked, MessageFromPlayer::LockChampSelection);
+        assert!(state.lobbies.contains_key(&lobby_id));
+
+        request(&mut state, afk, MessageFromPlayer::LeaveLobby);
+
+        // The game starts, and having no game server to start it on, the lobby is refused one
+        assert!(!state.lobbies.contains_key(&lobby_id));
+        assert_eq!(state.players[&locked].in_lobby, None);
+    }
+
+    #[tokio::test]
+    async fn friends_need_the_password_of_a_protected_lobby() {
+        let mut state = state();
+        let (leader, friend) = (connect(&mut state), connect(&mut state));
+        let leader_info = state.players[&leader].player.clone();
+        state
+            .players
+            .get_mut(&friend)
+            .unwrap()
+            .friends
+            .push(leader_info);
+
+        request(&mut state, leader, MessageFromPlayer::CreateLobby);
+        let lobby_id = state.players[&leader].in_lobby.unwrap();
+        request(
+            &mut state,
+            leader,
+            MessageFromPlayer::SetLobbyPassword(Some("scrim".into())),
+        );
+
+        let join = MessageFromPlayer::JoinFriendLobby(leader);
+        assert_eq!(
+            state.handle_request(friend, join).err(),
+            Some(LobbyError::PasswordRequired)
+        );
+        let join = MessageFromPlayer::JoinLobby(lobby_id, Some("scrim".into()));
+        request(&mut state, friend, join);
+        assert_eq!(state.players[&friend].in_lobby, Some(lobby_id));
+    }
+
+    #[tokio::test]
+    async fn spectators_cannot_use_team_chat() {
+        let mut state = state();
+        let player = connect(&mut state);
+        request(&mut state, player, MessageFromPlayer::CreateLobby);
+        request(&mut state, player, MessageFromPlayer::Spectate(player));
+
+        let team_chat = || MessageFromPlayer::SendChat {
+            channel: ChatChannel::Team,
+            text: "hi".into(),
+        };
+        for _ in 0..=ChatRateLimit::BURST as usize {
+            assert_eq!(
+                state.handle_request(player, team_chat()).err(),
+                Some(LobbyError::NotOnATeam)
+            );
+        }
+        // None of the refused messages counted against the rate limit
+        let lobby_chat = MessageFromPlayer::SendChat {
+            channel: ChatChannel::Lobby,
+            text: "hi".into(),
+        };
+        request(&mut state, player, lobby_chat);
+    }
+}
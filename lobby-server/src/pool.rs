@@ -0,0 +1,386 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    net::{Ipv6Addr, SocketAddrV6},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use protocol::{LobbyId, MessageFromGameServerToLobby, ReadMessage, PORTS_IN_USE};
+#[cfg(feature = "in-process")]
+use server::{AppExit, ServerArgs};
+use tokio::{process::Child, sync::oneshot};
+use uuid::Uuid;
+use wtransport::{config::Ipv6DualStackConfig, ClientConfig, Connection, Endpoint};
+
+use crate::{ports::GameServerPorts, GameServerLaunchMode, Options};
+
+/// How long a game server may take to start listening, which includes compiling it in
+/// [`GameServerLaunchMode::Cargo`].
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(300);
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// How long a game server agent may take to register after connecting.
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A game server we are connected to, idle or running a match.
+pub struct GameServer {
+    pub location: Location,
+    /// The game server only accepts matches that carry this token, which also identifies it.
+    pub token: Uuid,
+    /// Every match is started on a new stream of this connection.
+    pub connection: Arc<Connection>,
+    /// Kills the process when sent to or dropped.
+    kill: oneshot::Sender<()>,
+}
+
+impl GameServer {
+    pub fn new(
+        location: Location,
+        token: Uuid,
+        connection: Arc<Connection>,
+        kill: oneshot::Sender<()>,
+    ) -> Self {
+        Self {
+            location,
+            token,
+            connection,
+            kill,
+        }
+    }
+
+    pub fn kill(self) {
+        let _ = self.kill.send(());
+    }
+}
+
+/// Where a game server runs.
+pub enum Location {
+    /// Launched by us, listening on this port.
+    Local { port: u16 },
+    /// Registered by a game server agent on another machine.
+    Remote {
+        agent: Uuid,
+        public_address: String,
+        region: String,
+        /// How many game servers the agent runs.
+        capacity: u16,
+    },
+}
+
+impl Location {
+    /// Whether the game server runs in `region`; ours always do.
+    pub fn is_in_region(&self, region: &str) -> bool {
+        match self {
+            Location::Local { .. } => true,
+            Location::Remote {
+                region: location, ..
+            } => location == region,
+        }
+    }
+
+    /// Identifies the machine, to spread matches across machines: `None` for ours.
+    pub fn machine(&self) -> Option<Uuid> {
+        match self {
+            Location::Local { .. } => None,
+            Location::Remote { agent, .. } => Some(*agent),
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Local { port } => write!(f, "on port {port}"),
+            Location::Remote {
+                public_address,
+                region,
+                ..
+            } => write!(f, "at {public_address} in {region}"),
+        }
+    }
+}
+
+/// Game servers started ahead of time, so that players don't wait for one to start.
+#[derive(Default)]
+pub struct GameServerPool {
+    pub idle: Vec<GameServer>,
+    /// Processes that are not listening yet.
+    pub starting: usize,
+    /// Lobbies that finished champ select while no game server was idle, in order.
+    pub waiting: VecDeque<LobbyId>,
+    last_reported: Option<Occupancy>,
+}
+
+impl GameServerPool {
+    /// Prints how many game servers are in use, if that changed since the last report.
+    pub fn report(&mut self, in_use: usize) {
+        let occupancy = Occupancy {
+            idle: self.idle.len(),
+            starting: self.starting,
+            in_use,
+            waiting: self.waiting.len(),
+        };
+        if self.last_reported != Some(occupancy) {
+            println!("{occupancy}");
+            self.last_reported = Some(occupancy);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Occupancy {
+    idle: usize,
+    starting: usize,
+    in_use: usize,
+    waiting: usize,
+}
+
+impl Display for Occupancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Game servers: {} idle, {} starting, {} in use; {} lobbies waiting",
+            self.idle, self.starting, self.in_use, self.waiting
+        )
+    }
+}
+
+/// A running game server, either a child process or a thread of the lobby server.
+pub enum GameServerProcess {
+    Child(Child),
+    #[cfg(feature = "in-process")]
+    Thread {
+        /// Asks the game server to exit when sent to or dropped, as a thread can't be killed.
+        shutdown: Option<oneshot::Sender<()>>,
+        /// Receives how the game server exited; dropped if it panicked.
+        /// `None` once it did either.
+        exit: Option<oneshot::Receiver<AppExit>>,
+    },
+}
+
+/// How a game server exited.
+#[derive(Debug)]
+pub enum GameServerExit {
+    /// Something else took its ports since we picked them, so it should be started on others.
+    PortsInUse,
+    Other(String),
+}
+
+impl Display for GameServerExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GameServerExit::PortsInUse => f.write_str("its ports are in use"),
+            GameServerExit::Other(status) => f.write_str(status),
+        }
+    }
+}
+
+impl std::error::Error for GameServerExit {}
+
+impl GameServerProcess {
+    /// Waits for the game server to exit.
+    pub async fn wait(&mut self) -> GameServerExit {
+        match self {
+            Self::Child(child) => match child.wait().await {
+                Ok(status) if status.code() == Some(PORTS_IN_USE.into()) => {
+                    GameServerExit::PortsInUse
+                }
+                status => GameServerExit::Other(format!("{status:?}")),
+            },
+            #[cfg(feature = "in-process")]
+            Self::Thread { exit, .. } => {
+                let Some(receiver) = exit else {
+                    return GameServerExit::Other("exited".into());
+                };
+                let status = match receiver.await {
+                    Ok(exit) if exit == AppExit::from_code(PORTS_IN_USE) => {
+                        GameServerExit::PortsInUse
+                    }
+                    Ok(AppExit::Success) => GameServerExit::Other("success".into()),
+                    Ok(_) => GameServerExit::Other("error".into()),
+                    Err(_) => GameServerExit::Other("panicked".into()),
+                };
+                *exit = None;
+                status
+            }
+        }
+    }
+
+    /// Stops the game server and waits for it to exit, so its ports are free again.
+    pub async fn kill(&mut self) {
+        match self {
+            Self::Child(child) => {
+                let _ = child.kill().await;
+            }
+            #[cfg(feature = "in-process")]
+            Self::Thread { shutdown, .. } => {
+                if let Some(shutdown) = shutdown.take() {
+                    let _ = shutdown.send(());
+                }
+                self.wait().await;
+            }
+        }
+    }
+}
+
+/// Starts a game server listening on `ports`.
+pub fn launch(
+    options: &Options,
+    ports: GameServerPorts,
+    token: Uuid,
+) -> std::io::Result<GameServerProcess> {
+    let mut cmdline = vec![];
+    match options.game_server_launch_mode {
+        GameServerLaunchMode::Executable => {
+            cmdline.push(options.game_server_path.to_string_lossy().to_string());
+        }
+        #[cfg(feature = "in-process")]
+        GameServerLaunchMode::InProcess => {
+            let args = ServerArgs {
+                lobby_server_token: token,
+                port: ports.control,
+                game_port: ports.game,
+                debug_json: options.debug_json,
+                assets: options.assets.clone(),
+            };
+            let (shutdown, receiver) = oneshot::channel();
+            let (sender, exit) = oneshot::channel();
+            std::thread::Builder::new()
+                .name(format!("game server {}", ports.control))
+                .spawn(move || {
+                    let _ = sender.send(server::run_until(args, receiver));
+                })?;
+            return Ok(GameServerProcess::Thread {
+                shutdown: Some(shutdown),
+                exit: Some(exit),
+            });
+        }
+        GameServerLaunchMode::Cargo => {
+            cmdline.extend(
+                "cargo run --bin=server --"
+                    .split_whitespace()
+                    .map(String::from),
+            );
+        }
+    }
+    cmdline.push(token.to_string());
+    cmdline.push(ports.control.to_string());
+    cmdline.push(ports.game.to_string());
+    if options.debug_json {
+        cmdline.push("--debug-json".into());
+    }
+
+    let dir = if options.game_server_path.is_dir() {
+        options.game_server_path.as_path()
+    } else {
+        options.game_server_path.parent().unwrap()
+    };
+
+    let child = tokio::process::Command::new(&cmdline[0])
+        .args(&cmdline[1..])
+        .current_dir(dir)
+        // Idle game servers would otherwise outlive the lobby server
+        .kill_on_drop(true)
+        .spawn()?;
+    Ok(GameServerProcess::Child(child))
+}
+
+/// Connects to a freshly launched game server, retrying until it listens.
+pub async fn connect(port: u16) -> anyhow::Result<Connection> {
+    let client = Endpoint::client(
+        ClientConfig::builder()
+            .with_bind_address_v6(
+                SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0),
+                Ipv6DualStackConfig::Allow,
+            )
+            .with_no_cert_validation()
+            .build(),
+    )?;
+    let started = Instant::now();
+    loop {
+        match client.connect(format!("https://localhost:{port}")).await {
+            Ok(connection) => return Ok(connection),
+            Err(e) if started.elapsed() > STARTUP_TIMEOUT => return Err(e.into()),
+            Err(_) => tokio::time::sleep(CONNECT_RETRY_INTERVAL).await,
+        }
+    }
+}
+
+/// Reads the registration of a game server agent that just connected,
+/// which must carry `secret`. Without a secret, agents are refused.
+pub async fn accept_agent(
+    connection: &Connection,
+    secret: Option<&str>,
+) -> anyhow::Result<(Uuid, Location)> {
+    let Some(secret) = secret else {
+        anyhow::bail!("No --agent-secret given");
+    };
+    let message = tokio::time::timeout(REGISTER_TIMEOUT, async {
+        connection
+            .accept_uni()
+            .await?
+            .read_message::<MessageFromGameServerToLobby>()
+            .await
+    })
+    .await??;
+    let MessageFromGameServerToLobby::Register {
+        secret: given,
+        agent,
+        token,
+        public_address,
+        region,
+        capacity,
+    } = message
+    else {
+        anyhow::bail!("Unexpected message from game server agent: {message:?}");
+    };
+    anyhow::ensure!(
+        secrets_match(given.as_bytes(), secret.as_bytes()),
+        "Wrong secret"
+    );
+    let location = Location::Remote {
+        agent,
+        public_address,
+        region,
+        capacity,
+    };
+    Ok((token, location))
+}
+
+/// Compares two secrets in time that only depends on their lengths,
+/// so timing the answer doesn't give away how much of a guess was right.
+pub fn secrets_match(given: &[u8], secret: &[u8]) -> bool {
+    given.len() == secret.len()
+        && std::hint::black_box(
+            given
+                .iter()
+                .zip(secret)
+                .fold(0, |diff, (a, b)| diff | (a ^ b)),
+        ) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_must_match_exactly() {
+        assert!(secrets_match(b"hunter2", b"hunter2"));
+        assert!(!secrets_match(b"hunter3", b"hunter2"));
+        assert!(!secrets_match(b"hunter", b"hunter2"));
+        assert!(!secrets_match(b"", b"hunter2"));
+    }
+
+    #[test]
+    fn our_game_servers_are_in_every_region() {
+        let remote = Location::Remote {
+            agent: Uuid::new_v4(),
+            public_address: "eu.example.com:35475".into(),
+            region: "eu".into(),
+            capacity: 1,
+        };
+        assert!(remote.is_in_region("eu"));
+        assert!(!remote.is_in_region("us"));
+        assert!(Location::Local { port: 35475 }.is_in_region("us"));
+    }
+}
@@ -0,0 +1,139 @@
+use core::range::RangeInclusive;
+use std::{
+    collections::HashSet,
+    net::{Ipv6Addr, UdpSocket},
+};
+
+/// The two ports a game server listens on.
+#[derive(Clone, Copy, Debug)]
+pub struct GameServerPorts {
+    /// WebTransport port the lobby server controls the game server through.
+    pub control: u16,
+    /// UDP port players connect to.
+    pub game: u16,
+}
+
+/// Hands out the ports of `--game-server-port-range` to game servers, two per game server.
+pub struct PortAllocator {
+    range: RangeInclusive<u16>,
+    used: HashSet<u16>,
+    /// Where to look for free ports first, right after the last ones handed out.
+    /// Game servers whose ports got taken are retried on the next ones instead of the same.
+    next: u16,
+    /// Whether nothing outside of the allocator is bound to a port.
+    is_free: fn(u16) -> bool,
+}
+
+impl PortAllocator {
+    pub fn new(range: RangeInclusive<u16>) -> Self {
+        Self::with_free_check(range, is_free)
+    }
+
+    /// Like [`PortAllocator::new`], but asks `is_free` instead of the OS which ports are taken.
+    pub fn with_free_check(range: RangeInclusive<u16>, is_free: fn(u16) -> bool) -> Self {
+        Self {
+            next: range.start,
+            range,
+            used: HashSet::new(),
+            is_free,
+        }
+    }
+
+    /// How many game servers can run at once.
+    pub fn capacity(&self) -> usize {
+        self.range.iter().count() / 2
+    }
+
+    /// Reserves two ports that no game server of ours uses and nothing else is bound to.
+    pub fn allocate(&mut self) -> Option<GameServerPorts> {
+        let next = self.next;
+        let ahead = self.range.iter().filter(|port| *port >= next);
+        let behind = self.range.iter().filter(|port| *port < next);
+        let mut free = ahead
+            .chain(behind)
+            .filter(|port| !self.used.contains(port) && (self.is_free)(*port));
+        let ports = GameServerPorts {
+            control: free.next()?,
+            game: free.next()?,
+        };
+        self.used.insert(ports.control);
+        self.used.insert(ports.game);
+        self.next = ports.game.wrapping_add(1);
+        Some(ports)
+    }
+
+    pub fn release(&mut self, ports: GameServerPorts) {
+        self.used.remove(&ports.control);
+        self.used.remove(&ports.game);
+    }
+}
+
+/// Whether no other process is bound to `port`.
+/// Both WebTransport and the game run over UDP, so binding a UDP socket tells.
+///
+/// This is only a hint, as the port can be taken before the game server binds it;
+/// game servers exit with [`protocol::PORTS_IN_USE`] then, and are started on the next ports.
+fn is_free(port: u16) -> bool {
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Room for two game servers.
+    fn range() -> RangeInclusive<u16> {
+        (40000..=40003).into()
+    }
+
+    fn all_free(_: u16) -> bool {
+        true
+    }
+
+    #[test]
+    fn capacity_is_two_ports_per_server() {
+        let allocator = |last| PortAllocator::with_free_check((40000..=last).into(), all_free);
+        assert_eq!(allocator(40003).capacity(), 2);
+        assert_eq!(allocator(40004).capacity(), 2);
+        assert_eq!(allocator(40000).capacity(), 0);
+    }
+
+    #[test]
+    fn allocates_distinct_ports_until_exhausted() {
+        let mut ports = PortAllocator::with_free_check(range(), all_free);
+        let a = ports.allocate().unwrap();
+        let b = ports.allocate().unwrap();
+
+        let mut all = [a.control, a.game, b.control, b.game];
+        all.sort();
+        assert_eq!(all.to_vec(), range().iter().collect::<Vec<_>>());
+        assert!(ports.allocate().is_none());
+
+        ports.release(a);
+        let c = ports.allocate().unwrap();
+        assert_eq!((c.control, c.game), (a.control, a.game));
+    }
+
+    #[test]
+    fn moves_on_to_the_next_ports_after_a_release() {
+        let mut ports = PortAllocator::with_free_check(range(), all_free);
+        let a = ports.allocate().unwrap();
+        ports.release(a);
+
+        let b = ports.allocate().unwrap();
+        assert_eq!((b.control, b.game), (40002, 40003));
+        ports.release(b);
+        let c = ports.allocate().unwrap();
+        assert_eq!((c.control, c.game), (40000, 40001));
+    }
+
+    #[test]
+    fn skips_ports_bound_elsewhere() {
+        let mut ports = PortAllocator::with_free_check(range(), |port| port != 40001);
+
+        let allocated = ports.allocate().unwrap();
+        assert_eq!(allocated.control, 40000);
+        assert_eq!(allocated.game, 40002);
+        assert!(ports.allocate().is_none());
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use wtransport::{RecvStream, SendStream};
+
+use crate::{
+    MessageFromGameServerToLobby, MessageFromLobbyToGameServer, PlayerRequest, ServerMessage,
+};
+
+/// Version of the wire protocol.
+///
+/// Bump this whenever any of the message types change shape; peers speaking
+/// different versions refuse each other during the initial handshake.
+pub const PROTOCOL_VERSION: u16 = 33;
+
+/// Size of the frame header: version (u16), kind (u8), encoding (u8), body length (u32).
+///
+/// Unlike the bodies, the header layout must never change,
+/// so that peers on any version can tell which version the other side speaks.
+const HEADER_LEN: usize = 8;
+
+/// Upper bound on the body length we are willing to allocate for.
+const MAX_BODY_LEN: u32 = 16 * 1024 * 1024;
+
+static JSON_DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Switches the encoding of outgoing messages between compact binary (the default)
+/// and pretty-printed JSON. Incoming messages are always accepted in either encoding,
+/// so only the side that wants readable traffic needs to enable this.
+pub fn set_json_debug(enabled: bool) {
+    JSON_DEBUG.store(enabled, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    FromPlayer = 1,
+    FromServer = 2,
+    LobbyToGameServer = 3,
+    GameServerToLobby = 4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Encoding {
+    Binary = 0,
+    Json = 1,
+}
+
+/// A message that can be sent over a lobby stream.
+pub trait WireMessage: Serialize + DeserializeOwned {
+    const KIND: MessageKind;
+}
+
+impl WireMessage for PlayerRequest {
+    const KIND: MessageKind = MessageKind::FromPlayer;
+}
+
+impl WireMessage for ServerMessage {
+    const KIND: MessageKind = MessageKind::FromServer;
+}
+
+impl WireMessage for MessageFromLobbyToGameServer {
+    const KIND: MessageKind = MessageKind::LobbyToGameServer;
+}
+
+impl WireMessage for MessageFromGameServerToLobby {
+    const KIND: MessageKind = MessageKind::GameServerToLobby;
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    VersionMismatch { local: u16, remote: u16 },
+    UnexpectedKind { expected: MessageKind, actual: u8 },
+    UnknownEncoding(u8),
+    BodyTooLarge(u32),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::VersionMismatch { local, remote } => write!(
+                f,
+                "Protocol version mismatch: we speak version {local}, the other side speaks version {remote}.\nPlease update your game."
+            ),
+            CodecError::UnexpectedKind { expected, actual } => {
+                write!(f, "Expected a {expected:?} message, got message kind {actual}")
+            }
+            CodecError::UnknownEncoding(encoding) => write!(f, "Unknown message encoding {encoding}"),
+            CodecError::BodyTooLarge(len) => write!(f, "Message body too large ({len} bytes)"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+struct Header {
+    version: u16,
+    kind: u8,
+    encoding: u8,
+    len: u32,
+}
+
+impl Header {
+    fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0..2].copy_from_slice(&self.version.to_be_bytes());
+        bytes[2] = self.kind;
+        bytes[3] = self.encoding;
+        bytes[4..8].copy_from_slice(&self.len.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; HEADER_LEN]) -> Self {
+        Self {
+            version: u16::from_be_bytes([bytes[0], bytes[1]]),
+            kind: bytes[2],
+            encoding: bytes[3],
+            len: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+}
+
+/// Encodes a message into a complete frame, header included.
+///
+/// Useful for broadcasting, where the same frame is written to many streams.
+pub fn encode_message<T: WireMessage>(msg: &T) -> anyhow::Result<Vec<u8>> {
+    let encoding = if JSON_DEBUG.load(Ordering::Relaxed) {
+        Encoding::Json
+    } else {
+        Encoding::Binary
+    };
+    encode_with(msg, encoding)
+}
+
+fn encode_with<T: WireMessage>(msg: &T, encoding: Encoding) -> anyhow::Result<Vec<u8>> {
+    let body = match encoding {
+        Encoding::Json => serde_json::to_vec_pretty(msg)?,
+        Encoding::Binary => bincode::serialize(msg)?,
+    };
+    frame(T::KIND, encoding, &body)
+}
+
+fn frame(kind: MessageKind, encoding: Encoding, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let header = Header {
+        version: PROTOCOL_VERSION,
+        kind: kind as u8,
+        encoding: encoding as u8,
+        len: body.len().try_into()?,
+    };
+
+    let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+    frame.extend_from_slice(&header.to_bytes());
+    frame.extend_from_slice(body);
+    Ok(frame)
+}
+
+/// A frame with an empty body, sent in place of a `T` to a peer speaking another version.
+///
+/// Only the header is read before the version is checked, and its layout never changes,
+/// so the peer fails with [`CodecError::VersionMismatch`] naming our version.
+pub fn encode_version_refusal<T: WireMessage>() -> Vec<u8> {
+    frame(T::KIND, Encoding::Binary, &[]).expect("an empty body fits any header")
+}
+
+fn decode_body<T: WireMessage>(header: &Header, body: &[u8]) -> anyhow::Result<T> {
+    if header.version != PROTOCOL_VERSION {
+        Err(CodecError::VersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote: header.version,
+        })?;
+    }
+    if header.kind != T::KIND as u8 {
+        Err(CodecError::UnexpectedKind {
+            expected: T::KIND,
+            actual: header.kind,
+        })?;
+    }
+    let msg = match header.encoding {
+        e if e == Encoding::Binary as u8 => bincode::deserialize(body)?,
+        e if e == Encoding::Json as u8 => serde_json::from_slice(body)?,
+        e => Err(CodecError::UnknownEncoding(e))?,
+    };
+    Ok(msg)
+}
+
+// Only implemented and awaited within this workspace, so the futures needn't promise `Send`
+#[allow(async_fn_in_trait)]
+pub trait ReadMessage {
+    async fn read_message<T: WireMessage>(&mut self) -> anyhow::Result<T>;
+}
+
+impl ReadMessage for RecvStream {
+    async fn read_message<T: WireMessage>(&mut self) -> anyhow::Result<T> {
+        let mut header = [0; HEADER_LEN];
+        self.read_exact(&mut header).await?;
+        let header = Header::from_bytes(header);
+        if header.len > MAX_BODY_LEN {
+            Err(CodecError::BodyTooLarge(header.len))?;
+        }
+        // Always consume the whole body before validating the header,
+        // so that the stream stays in sync even if we reject the message.
+        let mut body = vec![0; header.len as _];
+        self.read_exact(&mut body).await?;
+        decode_body(&header, &body)
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait WriteMessage {
+    async fn write_message<T: WireMessage>(&mut self, msg: T) -> anyhow::Result<()>;
+    /// Writes a frame previously produced by [`encode_message`].
+    async fn write_message_raw(&mut self, frame: &[u8]) -> anyhow::Result<()>;
+}
+
+impl WriteMessage for SendStream {
+    async fn write_message<T: WireMessage>(&mut self, msg: T) -> anyhow::Result<()> {
+        self.write_all(&encode_message(&msg)?).await?;
+        Ok(())
+    }
+    async fn write_message_raw(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        self.write_all(frame).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MessageFromPlayer, RequestId};
+
+    fn decode_frame<T: WireMessage>(frame: &[u8]) -> anyhow::Result<T> {
+        let header = Header::from_bytes(frame[..HEADER_LEN].try_into().unwrap());
+        assert_eq!(header.len as usize, frame.len() - HEADER_LEN);
+        decode_body(&header, &frame[HEADER_LEN..])
+    }
+
+    fn request() -> PlayerRequest {
+        PlayerRequest {
+            id: RequestId(7),
+            message: MessageFromPlayer::SendFriendRequest("lyra".into()),
+        }
+    }
+
+    #[test]
+    fn roundtrip_in_both_encodings() {
+        for encoding in [Encoding::Binary, Encoding::Json] {
+            let frame = encode_with(&request(), encoding).unwrap();
+            let decoded: PlayerRequest = decode_frame(&frame).unwrap();
+            assert_eq!(format!("{decoded:?}"), format!("{:?}", request()));
+        }
+    }
+
+    #[test]
+    fn other_version_is_refused() {
+        let mut frame = encode_message(&request()).unwrap();
+        frame[0..2].copy_from_slice(&(PROTOCOL_VERSION + 1).to_be_bytes());
+        let error = decode_frame::<PlayerRequest>(&frame).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(CodecError::VersionMismatch { local, remote })
+                if *local == PROTOCOL_VERSION && *remote == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn version_refusal_is_a_bare_header() {
+        let frame = encode_version_refusal::<ServerMessage>();
+        assert_eq!(frame.len(), HEADER_LEN);
+        assert_eq!(frame[0..2], PROTOCOL_VERSION.to_be_bytes());
+        assert_eq!(frame[2], MessageKind::FromServer as u8);
+        assert_eq!(frame[4..8], [0; 4]);
+    }
+
+    #[test]
+    fn wrong_kind_is_refused() {
+        let frame = encode_message(&request()).unwrap();
+        let error = decode_frame::<ServerMessage>(&frame).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(CodecError::UnexpectedKind {
+                expected: MessageKind::FromServer,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,1226 @@
+mod codec;
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub use codec::{
+    encode_message, encode_version_refusal, set_json_debug, CodecError, MessageKind, ReadMessage,
+    WireMessage, WriteMessage, PROTOCOL_VERSION,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Team(pub usize);
+
+impl Display for Team {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::RED => f.write_str("Red Team"),
+            Self::BLUE => f.write_str("Blue Team"),
+            Team(n) => write!(f, "Team {n}"),
+        }
+    }
+}
+
+impl Team {
+    pub const RED: Self = Self(0);
+    pub const BLUE: Self = Self(1);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct LobbyId(Uuid);
+
+impl LobbyId {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Lobby {
+    pub id: LobbyId,
+    pub settings: LobbySettings,
+    pub leader: PlayerId,
+    pub players: HashMap<Team, Vec<PlayerId>>,
+    /// Members that watch the game instead of playing; at most [`LobbySettings::spectator_slots`].
+    pub spectators: Vec<PlayerId>,
+    pub lobby_state: LobbyState,
+    /// Incremented by one for every [`LobbyUpdate`] applied to the lobby.
+    pub revision: u64,
+    /// Only known to the server.
+    #[serde(skip)]
+    pub password: Option<String>,
+    /// Codes that let players join even if the lobby is closed or has a password.
+    /// Only known to the server; the leader can fetch them with [`MessageFromPlayer::GetLobbyAccess`].
+    #[serde(skip)]
+    pub invite_codes: HashSet<InviteCode>,
+    /// Whether matchmaking put the lobby together, which is what makes its matches rated.
+    /// Only known to the server.
+    #[serde(skip)]
+    pub rated: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PartyId(Uuid);
+
+impl PartyId {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+/// Players that join lobbies and queues together, led by the party leader.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Party {
+    pub id: PartyId,
+    pub leader: PlayerId,
+    pub members: Vec<PlayerInfo>,
+    /// Players that have been invited but haven't accepted yet.
+    /// Only known to the server.
+    #[serde(skip)]
+    pub invites: HashSet<PlayerId>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct InviteCode(pub String);
+
+impl Display for InviteCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Who can join a lobby, as seen by its leader.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LobbyAccess {
+    pub password_protected: bool,
+    pub invite_codes: Vec<InviteCode>,
+}
+
+/// The result of [`Lobby::apply`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyResult {
+    Applied,
+    /// The message is not an update to this lobby.
+    NotApplicable,
+    /// The update is older than the current revision, and has already been applied.
+    Stale,
+    /// One or more updates were missed; a fresh snapshot of the lobby is needed.
+    OutOfSync,
+}
+
+impl Lobby {
+    /// Applies a [`MessageFromServer::LobbyUpdated`] to this lobby.
+    ///
+    /// The server mutates its lobbies through this as well,
+    /// so a client applying every update in order ends up with the same lobby as the server.
+    pub fn apply(&mut self, msg: &MessageFromServer) -> ApplyResult {
+        let MessageFromServer::LobbyUpdated {
+            lobby,
+            revision,
+            update,
+        } = msg
+        else {
+            return ApplyResult::NotApplicable;
+        };
+        if *lobby != self.id {
+            return ApplyResult::NotApplicable;
+        }
+        if *revision <= self.revision {
+            return ApplyResult::Stale;
+        }
+        if *revision != self.revision + 1 {
+            return ApplyResult::OutOfSync;
+        }
+
+        match update {
+            LobbyUpdate::PlayerJoined(player, team) => {
+                self.players.entry(*team).or_default().push(*player);
+            }
+            LobbyUpdate::PlayerLeft(player) => {
+                self.remove_player(*player);
+                match &mut self.lobby_state {
+                    LobbyState::ReadyCheck(state) => {
+                        state.accepted.remove(player);
+                    }
+                    LobbyState::ChampSelect(state) => {
+                        state.selected_champs.remove(player);
+                        // The player's remaining turns are dropped;
+                        // the server skips the current one if it was theirs.
+                        if let Some(draft) = &mut state.draft {
+                            let current = draft.current;
+                            let mut index = 0;
+                            draft.turns.retain(|turn| {
+                                let keep = index <= current || turn.player != *player;
+                                index += 1;
+                                keep
+                            });
+                        }
+                    }
+                    LobbyState::Normal | LobbyState::InGame => {}
+                }
+            }
+            LobbyUpdate::PlayerSwitchedTeam(player, team) => {
+                self.remove_player(*player);
+                self.players.entry(*team).or_default().push(*player);
+            }
+            LobbyUpdate::PlayerSpectating(player) => {
+                self.remove_player(*player);
+                self.spectators.push(*player);
+            }
+            LobbyUpdate::PlayersSwitched(a, b) => {
+                for player in self
+                    .players
+                    .values_mut()
+                    .flatten()
+                    .chain(&mut self.spectators)
+                {
+                    if player == a {
+                        *player = *b;
+                    } else if player == b {
+                        *player = *a;
+                    }
+                }
+            }
+            LobbyUpdate::LeaderChanged(player) => {
+                self.leader = *player;
+            }
+            LobbyUpdate::SettingsUpdated { settings, players } => {
+                self.settings = settings.clone();
+                self.players = players.clone();
+            }
+            LobbyUpdate::ReadyCheckStarted(state) => {
+                self.lobby_state = LobbyState::ReadyCheck(state.clone());
+            }
+            LobbyUpdate::ReadyCheckAccepted(player) => {
+                if let LobbyState::ReadyCheck(state) = &mut self.lobby_state {
+                    state.accepted.insert(*player);
+                }
+            }
+            LobbyUpdate::ReadyCheckFailed(_) => {
+                self.lobby_state = LobbyState::Normal;
+            }
+            LobbyUpdate::ChampSelectEntered(state) => {
+                let mut state = state.clone();
+                state.start_timer();
+                self.lobby_state = LobbyState::ChampSelect(state);
+            }
+            LobbyUpdate::PlayerSelectedChampion(player, champion) => {
+                if let LobbyState::ChampSelect(state) = &mut self.lobby_state {
+                    state.selected_champs.insert(
+                        *player,
+                        Some(ChampionSelection {
+                            champion: champion.clone(),
+                            locked: false,
+                        }),
+                    );
+                }
+            }
+            LobbyUpdate::ChampSelectionLocked(player) => {
+                if let LobbyState::ChampSelect(state) = &mut self.lobby_state {
+                    if let Some(Some(selection)) = state.selected_champs.get_mut(player) {
+                        selection.locked = true;
+                    }
+                }
+            }
+            LobbyUpdate::DraftHovered(champion) => {
+                if let LobbyState::ChampSelect(ChampSelectState {
+                    draft: Some(draft), ..
+                }) = &mut self.lobby_state
+                {
+                    draft.hovered = Some(champion.clone());
+                }
+            }
+            LobbyUpdate::DraftTurnLocked(champion) => {
+                if let LobbyState::ChampSelect(state) = &mut self.lobby_state {
+                    if let Some(draft) = &mut state.draft {
+                        if let (Some(turn), Some(champion)) =
+                            (draft.turns.get(draft.current), champion)
+                        {
+                            match turn.kind {
+                                DraftTurnKind::Ban => draft
+                                    .bans
+                                    .entry(turn.team)
+                                    .or_default()
+                                    .push(champion.clone()),
+                                DraftTurnKind::Pick => {
+                                    state.selected_champs.insert(
+                                        turn.player,
+                                        Some(ChampionSelection {
+                                            champion: champion.clone(),
+                                            locked: true,
+                                        }),
+                                    );
+                                }
+                            }
+                        }
+                        draft.current += 1;
+                        draft.hovered = None;
+                        state.time_left = match draft.current_turn() {
+                            Some(_) => draft.turn_time,
+                            None => None,
+                        };
+                        state.start_timer();
+                    }
+                }
+            }
+            LobbyUpdate::GameStarted => {
+                self.lobby_state = LobbyState::InGame;
+            }
+            LobbyUpdate::GameEnded => {
+                self.lobby_state = LobbyState::Normal;
+            }
+        }
+
+        self.revision = *revision;
+        ApplyResult::Applied
+    }
+
+    /// Everyone in the lobby: the players on every team, then the spectators.
+    pub fn members(&self) -> impl Iterator<Item = &PlayerId> {
+        self.players.values().flatten().chain(&self.spectators)
+    }
+
+    fn remove_player(&mut self, player: PlayerId) {
+        for players in self.players.values_mut().chain([&mut self.spectators]) {
+            if let Some(pos) = players.iter().position(|p| *p == player) {
+                players.remove(pos);
+                break;
+            }
+        }
+    }
+}
+
+/// A change to a lobby, broadcast to its members in a [`MessageFromServer::LobbyUpdated`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LobbyUpdate {
+    PlayerJoined(PlayerId, Team),
+    PlayerLeft(PlayerId),
+    PlayerSwitchedTeam(PlayerId, Team),
+    /// The player joined as a spectator, or moved to the spectators from its team.
+    PlayerSpectating(PlayerId),
+    PlayersSwitched(PlayerId, PlayerId),
+    LeaderChanged(PlayerId),
+    /// Changing the settings can move players between teams, so the new teams are included.
+    SettingsUpdated {
+        settings: LobbySettings,
+        players: HashMap<Team, Vec<PlayerId>>,
+    },
+    ReadyCheckStarted(ReadyCheckState),
+    ReadyCheckAccepted(PlayerId),
+    /// The lobby goes back to [`LobbyState::Normal`].
+    /// Carries the players who declined, or didn't accept in time.
+    ReadyCheckFailed(Vec<PlayerId>),
+    /// Entered once every player has accepted the ready check.
+    ChampSelectEntered(ChampSelectState),
+    PlayerSelectedChampion(PlayerId, String),
+    ChampSelectionLocked(PlayerId),
+    /// The player whose draft turn it is selected a champion, without locking it in yet.
+    DraftHovered(String),
+    /// Ends the current draft turn, banning or picking the champion.
+    /// `None` skips the turn without a ban or pick.
+    DraftTurnLocked(Option<String>),
+    /// A game server started the lobby's game; its members are sent their connect tokens next.
+    GameStarted,
+    /// The lobby's game is over, and it goes back to [`LobbyState::Normal`].
+    GameEnded,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LobbyState {
+    Normal,
+    ReadyCheck(ReadyCheckState),
+    ChampSelect(ChampSelectState),
+    InGame,
+}
+
+impl LobbyState {
+    pub fn kind(&self) -> LobbyStateKind {
+        match self {
+            LobbyState::Normal => LobbyStateKind::Normal,
+            LobbyState::ReadyCheck(_) => LobbyStateKind::ReadyCheck,
+            LobbyState::ChampSelect(_) => LobbyStateKind::ChampSelect,
+            LobbyState::InGame => LobbyStateKind::InGame,
+        }
+    }
+}
+
+/// [`LobbyState`] without the associated data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LobbyStateKind {
+    Normal,
+    ReadyCheck,
+    ChampSelect,
+    InGame,
+}
+
+/// Every player has to accept before the lobby moves on to champ select.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReadyCheckState {
+    /// Tells ready checks in the same lobby apart.
+    pub id: Uuid,
+    /// How long players have to accept, counted from when the check started.
+    pub duration: Duration,
+    pub accepted: HashSet<PlayerId>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChampSelectState {
+    pub available_champs: Vec<String>,
+    pub selected_champs: HashMap<PlayerId, Option<ChampionSelection>>,
+    /// How much time was left to pick when this state was sent, if picking is timed.
+    /// In a draft, this is the time left for the current turn.
+    pub time_left: Option<Duration>,
+    /// Only in [`ChampSelectMode::Draft`].
+    pub draft: Option<Box<DraftState>>,
+    /// When picking ends, by the local clock.
+    /// Not sent; derived from `time_left` when the state is received.
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
+}
+
+impl ChampSelectState {
+    /// Sets the deadline from `time_left`, counting from now.
+    pub fn start_timer(&mut self) {
+        self.deadline = self.time_left.map(|t| Instant::now() + t);
+    }
+
+    /// Sets `time_left` from the deadline, so the state can be sent on.
+    pub fn update_time_left(&mut self) {
+        self.time_left = self
+            .deadline
+            .map(|d| d.saturating_duration_since(Instant::now()));
+    }
+
+    /// Whether a champion has been banned or picked in a draft, and can't be chosen anymore.
+    pub fn champion_taken(&self, champion: &str) -> bool {
+        let Some(draft) = &self.draft else {
+            return false;
+        };
+        draft.bans.values().flatten().any(|c| c == champion)
+            || self
+                .selected_champs
+                .values()
+                .flatten()
+                .any(|s| s.locked && s.champion == champion)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChampSelectMode {
+    /// Everyone picks at the same time, without seeing the other teams' picks.
+    Blind,
+    /// Players take turns banning and then picking, and every champion can only be picked once.
+    Draft,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DraftState {
+    pub turns: Vec<DraftTurn>,
+    /// Index of the current turn in `turns`; the draft is over once it reaches the end.
+    pub current: usize,
+    /// The champion selected by the player whose turn it is.
+    pub hovered: Option<String>,
+    pub bans: HashMap<Team, Vec<String>>,
+    /// How long every turn lasts, if turns are timed.
+    pub turn_time: Option<Duration>,
+}
+
+impl DraftState {
+    pub fn current_turn(&self) -> Option<&DraftTurn> {
+        self.turns.get(self.current)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DraftTurn {
+    pub team: Team,
+    pub player: PlayerId,
+    pub kind: DraftTurnKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DraftTurnKind {
+    Ban,
+    Pick,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChampionSelection {
+    pub champion: String,
+    pub locked: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbySettings {
+    pub name: String,
+    pub map: String,
+    pub team_count: usize,
+    pub player_limit_per_team: usize,
+    pub players_can_change_team: bool,
+    pub lobby_is_open: bool,
+    /// How long players have to pick a champion; 0 for no limit.
+    /// In a draft, this is the time for each turn.
+    pub pick_time_secs: u64,
+    pub champ_select_mode: ChampSelectMode,
+    pub spectator_slots: usize,
+    /// How far behind the game spectators see it, so they can't tell players what the enemy does.
+    pub spectator_delay_secs: u64,
+}
+
+/// A kind of match players can queue for with [`MessageFromPlayer::JoinQueue`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum QueueMode {
+    OneVsOne,
+    ThreeVsThree,
+    FiveVsFive,
+}
+
+impl QueueMode {
+    pub const ALL: [Self; 3] = [Self::OneVsOne, Self::ThreeVsThree, Self::FiveVsFive];
+
+    pub fn team_size(self) -> usize {
+        match self {
+            QueueMode::OneVsOne => 1,
+            QueueMode::ThreeVsThree => 3,
+            QueueMode::FiveVsFive => 5,
+        }
+    }
+
+    /// How champions are picked in the lobbies created for matches of this mode.
+    pub fn champ_select_mode(self) -> ChampSelectMode {
+        match self {
+            QueueMode::OneVsOne | QueueMode::ThreeVsThree => ChampSelectMode::Blind,
+            QueueMode::FiveVsFive => ChampSelectMode::Draft,
+        }
+    }
+}
+
+impl Display for QueueMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let size = self.team_size();
+        write!(f, "{size}v{size}")
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LobbyShortInfo {
+    pub id: LobbyId,
+    pub name: String,
+    pub map: String,
+    pub state: LobbyStateKind,
+    pub leader_name: String,
+    pub player_count: usize,
+    pub max_player_count: usize,
+    pub password_protected: bool,
+}
+
+/// Which lobbies [`MessageFromPlayer::GetLobbyList`] returns, in what order, and from where.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LobbyListQuery {
+    pub filter: LobbyFilter,
+    pub sort: LobbySort,
+    /// The `next` cursor of the previous page, or `None` for the first page.
+    pub cursor: Option<LobbyListCursor>,
+}
+
+/// Lobbies must match every filter that is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbyFilter {
+    pub map: Option<String>,
+    pub has_free_slot: bool,
+    pub no_password: bool,
+    /// Matched against the lobby name, ignoring case.
+    pub name_contains: Option<String>,
+}
+
+impl LobbyFilter {
+    pub fn matches(&self, lobby: &LobbyShortInfo) -> bool {
+        self.map.as_ref().is_none_or(|map| *map == lobby.map)
+            && (!self.has_free_slot || lobby.player_count < lobby.max_player_count)
+            && (!self.no_password || !lobby.password_protected)
+            && self
+                .name_contains
+                .as_ref()
+                .is_none_or(|name| lobby.name.to_lowercase().contains(&name.to_lowercase()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LobbySort {
+    #[default]
+    Name,
+    Map,
+    /// Fullest lobbies first.
+    PlayerCount,
+}
+
+impl LobbySort {
+    pub const ALL: [Self; 3] = [Self::Name, Self::Map, Self::PlayerCount];
+
+    /// Orders two lobby list positions, breaking ties by lobby ID so the order is total.
+    pub fn compare(self, a: &LobbyListCursor, b: &LobbyListCursor) -> Ordering {
+        let by_key = match self {
+            LobbySort::Name => a.name.cmp(&b.name),
+            LobbySort::Map => a.map.cmp(&b.map).then_with(|| a.name.cmp(&b.name)),
+            LobbySort::PlayerCount => b.player_count.cmp(&a.player_count),
+        };
+        by_key.then_with(|| a.id.cmp(&b.id))
+    }
+}
+
+impl Display for LobbySort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LobbySort::Name => "Name",
+            LobbySort::Map => "Map",
+            LobbySort::PlayerCount => "Players",
+        })
+    }
+}
+
+/// The position of a lobby in the list, under any [`LobbySort`].
+/// Pages continue right after it, so lobbies created or removed in between don't shift them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LobbyListCursor {
+    pub id: LobbyId,
+    pub name: String,
+    pub map: String,
+    pub player_count: usize,
+}
+
+impl From<&LobbyShortInfo> for LobbyListCursor {
+    fn from(lobby: &LobbyShortInfo) -> Self {
+        Self {
+            id: lobby.id,
+            name: lobby.name.clone(),
+            map: lobby.map.clone(),
+            player_count: lobby.player_count,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PlayerId(Uuid);
+
+impl PlayerId {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+
+    pub fn get(&self) -> Uuid {
+        self.0
+    }
+}
+
+impl From<Uuid> for PlayerId {
+    fn from(id: Uuid) -> Self {
+        Self(id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerInfo {
+    pub id: PlayerId,
+    pub name: String,
+}
+
+/// What a player is doing, as shown to its friends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Presence {
+    Offline,
+    Online,
+    /// In a lobby that hasn't entered champ select, which friends can join.
+    InLobby(LobbyId),
+    InChampSelect,
+    InGame,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Friend {
+    pub player: PlayerInfo,
+    pub presence: Presence,
+}
+
+/// Lets a client resume its session after losing connection, see [`Credentials::Resume`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ResumeToken(Uuid);
+
+impl ResumeToken {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+// The token is as good as a password, so keep it out of logs
+impl std::fmt::Debug for ResumeToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ResumeToken(..)")
+    }
+}
+
+/// Chosen by the client for every request, and echoed by the server
+/// in every reply, acknowledgement or refusal caused by that request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RequestId(pub u64);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlayerRequest {
+    pub id: RequestId,
+    pub message: MessageFromPlayer,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum MessageFromPlayer {
+    InitialHandshake {
+        credentials: Credentials,
+    },
+    CreateLobby,
+    /// The password is only needed if the lobby has one.
+    JoinLobby(LobbyId, Option<String>),
+    JoinLobbyWithCode(InviteCode),
+    LeaveLobby,
+    SwitchTeam(PlayerId, Team),
+    /// Moves a player from its team to the spectators; the player itself or the leader only.
+    /// [`MessageFromPlayer::SwitchTeam`] moves it back.
+    Spectate(PlayerId),
+    SwitchPlaces(PlayerId, PlayerId),
+    GetLobbyInfo(LobbyId),
+    GetLobbyList(LobbyListQuery),
+    /// Sets or removes the password of the lobby; leader only.
+    SetLobbyPassword(Option<String>),
+    GetLobbyAccess,
+    CreateInviteCode,
+    RevokeInviteCode(InviteCode),
+    GetPlayerInfo(PlayerId),
+    KickPlayer(PlayerId),
+    UpdateSettings(LobbySettings),
+    /// Starts a ready check, which enters champ select once everyone accepts; leader only.
+    EnterChampSelect,
+    AcceptReadyCheck,
+    DeclineReadyCheck,
+    SelectChampion(String),
+    LockChampSelection,
+    StartGame,
+    Disconnecting,
+    SendChat {
+        channel: ChatChannel,
+        text: String,
+    },
+    /// Waits for a match, which puts the player in a new lobby that is already in champ select.
+    JoinQueue {
+        mode: QueueMode,
+    },
+    LeaveQueue,
+    GetProfile(PlayerId),
+    /// Fetches the player's most recent matches.
+    GetMatchHistory(PlayerId),
+    /// Invites a player to the party, creating one if the player isn't in a party yet;
+    /// party leader only.
+    InviteToParty(PlayerId),
+    AcceptPartyInvite(PartyId),
+    LeaveParty,
+    /// Sends a friend request to the player with this name,
+    /// or accepts theirs if they already sent one.
+    SendFriendRequest(String),
+    AcceptFriendRequest(PlayerId),
+    /// Removes a friend, or declines their friend request.
+    RemoveFriend(PlayerId),
+    /// Joins the lobby a friend is in. Password-protected lobbies refuse this with
+    /// [`LobbyError::PasswordRequired`]; they can only be joined with the password
+    /// or an invite code.
+    JoinFriendLobby(PlayerId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChatChannel {
+    /// Everyone in the lobby.
+    Lobby,
+    /// Everyone on the sender's team in the lobby.
+    Team,
+    /// A single player, who doesn't have to be in the same lobby.
+    Whisper(PlayerId),
+}
+
+/// How a player identifies itself in the [`MessageFromPlayer::InitialHandshake`].
+#[derive(Clone, Serialize, Deserialize)]
+pub enum Credentials {
+    /// Create a new account; the name is also the player's display name.
+    Register {
+        name: String,
+        password: String,
+    },
+    Login {
+        name: String,
+        password: String,
+    },
+    /// Pick up a session that lost its connection, with the token from its handshake response.
+    Resume(ResumeToken),
+}
+
+// Never print passwords to the log
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Register { name, .. } => f
+                .debug_struct("Register")
+                .field("name", name)
+                .finish_non_exhaustive(),
+            Credentials::Login { name, .. } => f
+                .debug_struct("Login")
+                .field("name", name)
+                .finish_non_exhaustive(),
+            Credentials::Resume(_) => f.write_str("Resume(..)"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerMessage {
+    /// The request this message answers, or `None` if it was not caused by a request from this player.
+    pub in_reply_to: Option<RequestId>,
+    pub message: MessageFromServer,
+}
+
+/// Why the lobby server refused a request.
+///
+/// Only the reason is sent over the wire; the client decides how to present it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LobbyError {
+    AlreadyInLobby,
+    NotInLobby,
+    LobbyNotFound,
+    PlayerNotFound,
+    LobbyClosed,
+    LobbyFull,
+    NotLeader,
+    InvalidState {
+        expected: LobbyStateKind,
+        actual: LobbyStateKind,
+    },
+    TeamSwitchingDisabled,
+    CannotMoveOtherPlayer,
+    TeamNotFound(Team),
+    TeamFull(Team),
+    SpectatorsFull,
+    /// Spectators don't pick champions.
+    Spectating,
+    /// The lobby has this many spectators, which don't fit in fewer spectator slots.
+    SpectatorSlotsInUse(usize),
+    EmptyLobbyName,
+    UnknownMap(String),
+    NoTeams,
+    /// The map only supports this many teams.
+    UnsupportedTeamCount {
+        min: usize,
+        max: usize,
+    },
+    UnknownChampion,
+    SelectionLocked,
+    NoChampionSelected,
+    NotYourTurn,
+    /// The champion has been banned or picked already.
+    ChampionUnavailable,
+    /// Games start on their own once every champion is locked.
+    GameStartsAutomatically,
+    InvalidName,
+    NameTaken,
+    /// The password must be at least this many characters long.
+    PasswordTooShort(usize),
+    InvalidCredentials,
+    /// The name was tried with too many wrong passwords from this address;
+    /// logging in to it from there is refused for a while.
+    TooManyLoginAttempts,
+    AlreadyLoggedIn,
+    AccountStoreFailed,
+    InvalidResumeToken,
+    EmptyChatMessage,
+    /// Chat messages can be at most this many characters long.
+    ChatMessageTooLong(usize),
+    ChatRateLimited,
+    /// Team chat is only for players on a team, not spectators.
+    NotOnATeam,
+    PasswordRequired,
+    WrongPassword,
+    InvalidInviteCode,
+    /// A lobby can have at most this many invite codes at once.
+    TooManyInviteCodes(usize),
+    AlreadyInQueue,
+    NotInQueue,
+    AlreadyInParty,
+    NotInParty,
+    PartyNotFound,
+    NotPartyLeader,
+    /// A party can have at most this many members.
+    PartyFull(usize),
+    /// Someone in the party is already in a lobby or queue.
+    PartyBusy,
+    /// The party doesn't fit on a team of this size.
+    PartyTooLarge(usize),
+    AlreadyFriends,
+    NotFriends,
+    FriendRequestNotFound,
+    /// The friend is not in a lobby that can be joined.
+    FriendNotInLobby,
+}
+
+/// Why the server closed a lobby.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisbandReason {
+    /// The champion roster is too small for every player to pick a different champion.
+    NotEnoughChampions,
+    /// Every port for game servers is taken, so the game can't start.
+    NoGameServerAvailable,
+    /// The game server stopped responding during the game.
+    GameServerFailed,
+    /// The game server could not start the game.
+    MatchRefused,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum MessageFromServer {
+    InitialHandshakeResponse {
+        id: PlayerId,
+        resume_token: ResumeToken,
+    },
+    YouJoinedLobby(LobbyId),
+    YouLeftLobby,
+    /// The server closed the player's lobby, which sends the player back to the lobby list.
+    LobbyDisbanded(DisbandReason),
+    LobbyUpdated {
+        lobby: LobbyId,
+        revision: u64,
+        update: LobbyUpdate,
+    },
+    LobbyInfo(Box<Lobby>),
+    LobbyList {
+        /// The cursor of the request, `None` if this is the first page.
+        after: Option<LobbyListCursor>,
+        lobbies: Vec<LobbyShortInfo>,
+        /// Where the next page starts, `None` if this was the last one.
+        next: Option<LobbyListCursor>,
+    },
+    /// The reply to all requests about lobby access.
+    LobbyAccess(LobbyAccess),
+    PlayerInfo(PlayerInfo),
+    RequestAccepted,
+    RequestRefused(LobbyError),
+    GameStarted(ConnectTokenWrapper),
+    ServerShutdown,
+    /// A chat message, either sent to one of the player's channels or by the player itself.
+    ChatMessage {
+        from: PlayerInfo,
+        channel: ChatChannel,
+        text: String,
+    },
+    /// The player is waiting for a match; also sent again when resuming a session.
+    YouJoinedQueue {
+        mode: QueueMode,
+        /// How long the player is expected to wait in total,
+        /// if enough matches of this mode have been made to tell.
+        expected_wait: Option<Duration>,
+        /// How long the player has already waited.
+        waited: Duration,
+    },
+    /// The player left the queue without finding a match.
+    YouLeftQueue,
+    Profile(PlayerProfile),
+    /// The player's party changed; `None` if the player is no longer in one.
+    PartyUpdated(Option<Party>),
+    PartyInvite {
+        party: PartyId,
+        from: PlayerInfo,
+    },
+    MatchHistory {
+        player: PlayerId,
+        /// Most recent first.
+        matches: Vec<MatchRecord>,
+    },
+    /// Sent when the session starts.
+    FriendList {
+        friends: Vec<Friend>,
+        /// Friend requests the player hasn't answered yet.
+        requests: Vec<PlayerInfo>,
+    },
+    /// A friend was added, or their presence changed.
+    FriendUpdated(Friend),
+    /// The friend, or their friend request, was removed.
+    FriendRemoved(PlayerId),
+    FriendRequestReceived(PlayerInfo),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum MessageFromLobbyToGameServer {
+    /// Starts a match, on a new stream of the connection the lobby server keeps to the game server.
+    LobbyInitialMessage {
+        token: Uuid,
+        players: HashMap<Team, Vec<PlayerSelection>>,
+        spectators: Vec<PlayerInfo>,
+        /// How far behind the game spectators see it.
+        spectator_delay: Duration,
+    },
+}
+
+/// Game server agents connect to the lobby server's address with this path,
+/// which players don't use.
+pub const AGENT_PATH: &str = "/agent";
+
+/// Exit code of a game server whose ports something else took since the lobby server picked
+/// them, which then starts it on other ports.
+pub const PORTS_IN_USE: u8 = 2;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum MessageFromGameServerToLobby {
+    /// Sent by a game server agent right after connecting to [`AGENT_PATH`],
+    /// after which the lobby server starts matches on the connection like on its own game servers.
+    Register {
+        /// Shared by the lobby server and its agents, so strangers can't host matches.
+        secret: String,
+        /// The agent running the game server, which runs up to `capacity` of them.
+        agent: Uuid,
+        /// The token the lobby server must start matches with.
+        token: Uuid,
+        /// Where players connect to, which the game server puts in their connect tokens.
+        public_address: String,
+        /// Where the machine is; the lobby server prefers game servers in its own region.
+        region: String,
+        capacity: u16,
+    },
+    PlayerTokensGenerated {
+        players: HashMap<PlayerId, ConnectTokenWrapper>,
+    },
+    /// Sent instead of [`Self::PlayerTokensGenerated`] when the game server can't play the match,
+    /// like when it doesn't know a champion. The game server then waits for the next match.
+    MatchRefused(String),
+    /// Sent on a new stream once the match is over.
+    /// The game server then waits for the next [`MessageFromLobbyToGameServer::LobbyInitialMessage`].
+    MatchFinished(MatchResult),
+}
+
+/// How a match went, as reported by the game server.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MatchResult {
+    /// `None` if the match ended without a winner, which leaves ratings untouched.
+    pub winner: Option<Team>,
+    /// Whether the match ended because players left rather than by being won,
+    /// which never changes ratings.
+    pub forfeit: bool,
+    pub duration: Duration,
+    pub players: Vec<PlayerMatchStats>,
+}
+
+/// How a player did in a match. The scores are `None` while the game doesn't count them.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlayerMatchStats {
+    pub player: PlayerInfo,
+    pub team: Team,
+    pub champion: String,
+    pub kills: Option<u32>,
+    pub deaths: Option<u32>,
+    pub assists: Option<u32>,
+    pub gold: Option<u32>,
+}
+
+/// A finished match, as stored by the lobby server.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MatchRecord {
+    pub id: Uuid,
+    /// When the match was recorded, in seconds since the Unix epoch.
+    pub played_at: u64,
+    pub result: MatchResult,
+    pub rating_changes: HashMap<PlayerId, f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlayerProfile {
+    pub player: PlayerInfo,
+    pub rating: f64,
+    pub matches_played: u32,
+    pub wins: u32,
+    pub losses: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConnectTokenWrapper(pub Vec<u8>);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlayerSelection {
+    pub player: PlayerInfo,
+    pub champion: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lobby() -> Lobby {
+        Lobby {
+            id: LobbyId::new(),
+            settings: LobbySettings {
+                name: "Test".into(),
+                map: "test".into(),
+                team_count: 2,
+                player_limit_per_team: 5,
+                players_can_change_team: true,
+                lobby_is_open: true,
+                pick_time_secs: 60,
+                champ_select_mode: ChampSelectMode::Blind,
+                spectator_slots: 2,
+                spectator_delay_secs: 0,
+            },
+            leader: PlayerId::new(),
+            players: [(Team::RED, vec![]), (Team::BLUE, vec![])].into(),
+            spectators: vec![],
+            lobby_state: LobbyState::Normal,
+            revision: 0,
+            password: None,
+            invite_codes: HashSet::new(),
+            rated: false,
+        }
+    }
+
+    fn updated(lobby: &Lobby, revision: u64, update: LobbyUpdate) -> MessageFromServer {
+        MessageFromServer::LobbyUpdated {
+            lobby: lobby.id,
+            revision,
+            update,
+        }
+    }
+
+    fn short_info() -> LobbyShortInfo {
+        LobbyShortInfo {
+            id: LobbyId::new(),
+            name: "Friday Night Games".into(),
+            map: "Default".into(),
+            state: LobbyStateKind::Normal,
+            leader_name: "leader".into(),
+            player_count: 9,
+            max_player_count: 10,
+            password_protected: true,
+        }
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(LobbyFilter::default().matches(&short_info()));
+    }
+
+    #[test]
+    fn filter_matches_every_field() {
+        let lobby = short_info();
+        let full = LobbyShortInfo {
+            player_count: 10,
+            ..short_info()
+        };
+        let filter = |f: fn(&mut LobbyFilter)| {
+            let mut filter = LobbyFilter::default();
+            f(&mut filter);
+            filter
+        };
+
+        assert!(filter(|f| f.map = Some("Default".into())).matches(&lobby));
+        assert!(!filter(|f| f.map = Some("Other".into())).matches(&lobby));
+        assert!(filter(|f| f.has_free_slot = true).matches(&lobby));
+        assert!(!filter(|f| f.has_free_slot = true).matches(&full));
+        assert!(!filter(|f| f.no_password = true).matches(&lobby));
+        assert!(filter(|f| f.name_contains = Some("night".into())).matches(&lobby));
+        assert!(!filter(|f| f.name_contains = Some("morning".into())).matches(&lobby));
+    }
+
+    #[test]
+    fn filters_combine() {
+        let filter = LobbyFilter {
+            map: Some("Default".into()),
+            has_free_slot: true,
+            no_password: false,
+            name_contains: Some("GAMES".into()),
+        };
+        assert!(filter.matches(&short_info()));
+
+        let filter = LobbyFilter {
+            no_password: true,
+            ..filter
+        };
+        assert!(!filter.matches(&short_info()));
+    }
+
+    #[test]
+    fn sort_breaks_ties_by_id() {
+        let (a, b) = (short_info(), short_info());
+        for sort in LobbySort::ALL {
+            let (a, b) = (LobbyListCursor::from(&a), LobbyListCursor::from(&b));
+            assert_eq!(sort.compare(&a, &b), a.id.cmp(&b.id));
+            assert_eq!(sort.compare(&a, &a), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn apply_advances_revision() {
+        let mut lobby = lobby();
+        let player = PlayerId::new();
+        let msg = updated(&lobby, 1, LobbyUpdate::PlayerJoined(player, Team::BLUE));
+
+        assert_eq!(lobby.apply(&msg), ApplyResult::Applied);
+        assert_eq!(lobby.revision, 1);
+        assert_eq!(lobby.players[&Team::BLUE], [player]);
+
+        // Applying the same update again must not add the player twice
+        assert_eq!(lobby.apply(&msg), ApplyResult::Stale);
+        assert_eq!(lobby.revision, 1);
+        assert_eq!(lobby.players[&Team::BLUE], [player]);
+    }
+
+    #[test]
+    fn apply_detects_missed_updates() {
+        let mut lobby = lobby();
+        let msg = updated(
+            &lobby,
+            2,
+            LobbyUpdate::PlayerJoined(PlayerId::new(), Team::RED),
+        );
+
+        assert_eq!(lobby.apply(&msg), ApplyResult::OutOfSync);
+        assert_eq!(lobby.revision, 0);
+        assert!(lobby.players[&Team::RED].is_empty());
+    }
+
+    #[test]
+    fn apply_ignores_other_lobbies() {
+        let mut lobby = lobby();
+        let other = self::lobby();
+        let msg = updated(
+            &other,
+            1,
+            LobbyUpdate::PlayerJoined(PlayerId::new(), Team::RED),
+        );
+
+        assert_eq!(lobby.apply(&msg), ApplyResult::NotApplicable);
+        assert_eq!(
+            lobby.apply(&MessageFromServer::YouLeftLobby),
+            ApplyResult::NotApplicable
+        );
+        assert_eq!(lobby.revision, 0);
+        assert!(lobby.players[&Team::RED].is_empty());
+    }
+
+    #[test]
+    fn apply_moves_lobby_in_and_out_of_its_game() {
+        let mut lobby = lobby();
+
+        lobby.apply(&updated(&lobby, 1, LobbyUpdate::GameStarted));
+        assert_eq!(lobby.lobby_state.kind(), LobbyStateKind::InGame);
+
+        lobby.apply(&updated(&lobby, 2, LobbyUpdate::GameEnded));
+        assert_eq!(lobby.lobby_state.kind(), LobbyStateKind::Normal);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    net::{Ipv6Addr, SocketAddrV6},
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use bevy::app::AppExit;
+use protocol::{AGENT_PATH, MessageFromGameServerToLobby, WriteMessage};
+use tokio::{io::AsyncWriteExt, runtime::Runtime};
+use uuid::Uuid;
+use wtransport::{
+    ClientConfig, Connection, Endpoint, config::Ipv6DualStackConfig, tls::Sha256Digest,
+};
+
+use crate::{LobbyConnection, MatchHost, play_matches};
+
+/// How long to wait before registering again after losing the lobby server.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Runs game servers on a machine other than the lobby server's.
+/// Instead of being launched by the lobby server, the agent connects to it
+/// and registers one game server for every match it can run at once.
+#[derive(Debug, Clone, clap::Parser)]
+pub struct AgentArgs {
+    /// The lobby server to register with, like `https://lobby.example.com:54765`.
+    pub lobby_server: String,
+    /// Must match the lobby server's `--agent-secret`.
+    #[arg(long)]
+    pub secret: String,
+    /// Host name or IP address players reach this machine at; IPv6 addresses in brackets.
+    #[arg(long)]
+    pub public_host: String,
+    /// Where this machine is, reported to the lobby server, which prefers game servers
+    /// in its own `--region` when several are equally busy.
+    #[arg(long, default_value = "default")]
+    pub region: String,
+    /// How many matches to run at once.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub capacity: u16,
+    /// UDP port of the first match; the others use the ports after it.
+    #[arg(long, default_value_t = 35475)]
+    pub first_port: u16,
+    /// Only accept a lobby server whose certificate has this SHA-256 hash, as printed by the
+    /// lobby server. Can be given more than once.
+    #[arg(long = "server-cert-hash", conflicts_with = "no_cert_validation")]
+    pub server_cert_hashes: Vec<Sha256Digest>,
+    /// Accept any lobby server certificate, for local development only.
+    #[arg(long)]
+    pub no_cert_validation: bool,
+    /// Send messages to the lobby server as JSON instead of binary, for debugging.
+    #[arg(long)]
+    pub debug_json: bool,
+    /// The game's data files, shared with the lobby server and client.
+    #[arg(long, default_value = "assets")]
+    pub assets: PathBuf,
+}
+
+impl AgentArgs {
+    /// The UDP ports of the matches, `None` if they don't all fit below 65536.
+    fn game_ports(&self) -> Option<RangeInclusive<u16>> {
+        let last = self.first_port.checked_add(self.capacity.checked_sub(1)?)?;
+        Some(self.first_port..=last)
+    }
+
+    fn client_config(&self) -> ClientConfig {
+        let builder = ClientConfig::builder().with_bind_address_v6(
+            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0),
+            Ipv6DualStackConfig::Allow,
+        );
+        let builder = if self.no_cert_validation {
+            builder.with_no_cert_validation()
+        } else if !self.server_cert_hashes.is_empty() {
+            builder.with_server_certificate_hashes(self.server_cert_hashes.clone())
+        } else {
+            builder.with_native_certs()
+        };
+        builder.build()
+    }
+}
+
+/// Keeps `capacity` game servers registered with the lobby server, each on a thread of its own.
+pub fn run(args: AgentArgs) -> AppExit {
+    protocol::set_json_debug(args.debug_json);
+    let Some(ports) = args.game_ports() else {
+        eprintln!(
+            "GS: {} matches starting at port {} need ports above 65535",
+            args.capacity, args.first_port
+        );
+        return AppExit::error();
+    };
+
+    // Identifies the game servers of this agent, so the lobby server can spread matches
+    // across machines
+    let agent = Uuid::new_v4();
+    let runtime = Arc::new(
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap(),
+    );
+    let slots: Vec<_> = ports
+        .enumerate()
+        .map(|(slot, port)| {
+            let args = args.clone();
+            let runtime = runtime.clone();
+            std::thread::Builder::new()
+                .name(format!("game server {slot}"))
+                .spawn(move || run_slot(&args, agent, port, runtime))
+                .unwrap()
+        })
+        .collect();
+    for slot in slots {
+        if slot.join().is_err() {
+            return AppExit::error();
+        }
+    }
+    AppExit::Success
+}
+
+/// Registers a game server playing on `game_port`, and registers it again whenever the
+/// lobby server goes away.
+fn run_slot(args: &AgentArgs, agent: Uuid, game_port: u16, runtime: Arc<Runtime>) {
+    loop {
+        // A new token for every registration, so matches meant for an earlier one are refused
+        let token = Uuid::new_v4();
+        let public_address = format!("{}:{game_port}", args.public_host);
+        match runtime.block_on(register(args, agent, token, &public_address)) {
+            Ok(connection) => {
+                println!("GS: Registered {public_address} with the lobby server");
+                let host = MatchHost {
+                    token,
+                    public_address,
+                    game_port,
+                    assets: args.assets.clone(),
+                };
+                let lobby = LobbyConnection {
+                    runtime: runtime.clone(),
+                    connection: Arc::new(connection),
+                };
+                play_matches(&host, lobby);
+            }
+            Err(e) => eprintln!("GS: Could not register with the lobby server: {e:#}"),
+        }
+        std::thread::sleep(RECONNECT_INTERVAL);
+    }
+}
+
+/// Connects to the lobby server and announces a game server, which it then starts matches on
+/// like on the game servers it launches itself.
+async fn register(
+    args: &AgentArgs,
+    agent: Uuid,
+    token: Uuid,
+    public_address: &str,
+) -> anyhow::Result<Connection> {
+    let client = Endpoint::client(args.client_config())?;
+    let connection = client
+        .connect(format!(
+            "{}{AGENT_PATH}",
+            args.lobby_server.trim_end_matches('/')
+        ))
+        .await?;
+
+    let mut stream = connection.open_uni().await?.await?;
+    stream
+        .write_message(MessageFromGameServerToLobby::Register {
+            secret: args.secret.clone(),
+            agent,
+            token,
+            public_address: public_address.into(),
+            region: args.region.clone(),
+            capacity: args.capacity,
+        })
+        .await?;
+    stream.flush().await?;
+    stream.finish().await?;
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn args(capacity: &str, first_port: &str) -> Result<AgentArgs, clap::Error> {
+        AgentArgs::try_parse_from([
+            "agent",
+            "https://localhost:54765",
+            "--secret=secret",
+            "--public-host=localhost",
+            "--capacity",
+            capacity,
+            "--first-port",
+            first_port,
+        ])
+    }
+
+    #[test]
+    fn game_ports_must_fit_below_65536() {
+        let ports = args("3", "65533").unwrap().game_ports();
+        assert_eq!(ports, Some(65533..=65535));
+        assert_eq!(args("3", "65534").unwrap().game_ports(), None);
+        assert!(args("0", "35475").is_err());
+    }
+}
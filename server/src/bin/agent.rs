@@ -0,0 +1,7 @@
+use bevy::app::AppExit;
+use clap::Parser;
+use server::agent::AgentArgs;
+
+fn main() -> AppExit {
+    server::agent::run(AgentArgs::parse())
+}
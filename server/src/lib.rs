@@ -0,0 +1,552 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use engine::{
+    SERVER_REPLICATION_INTERVAL, champion::ChampionRegistry, net::ProtocolPlugin, shared_config,
+};
+use lightyear::{
+    connection::netcode::{PRIVATE_KEY_BYTES, USER_DATA_BYTES},
+    prelude::{server::ServerCommands, *},
+    server::plugin::ServerPlugins,
+};
+use protocol::{
+    ConnectTokenWrapper, MatchResult, MessageFromGameServerToLobby, MessageFromLobbyToGameServer,
+    PORTS_IN_USE, PlayerId, PlayerMatchStats, ReadMessage, Team, WriteMessage,
+};
+use rules::MatchSetup;
+use tokio::{io::AsyncWriteExt, sync::oneshot};
+use uuid::Uuid;
+use wtransport::{Endpoint, Identity, ServerConfig, VarInt, config::Ipv6DualStackConfig};
+
+pub mod agent;
+mod rules;
+mod spectate;
+
+pub use bevy::app::AppExit;
+
+/// How the lobby server starts us, on the command line or, in-process, through [`run`].
+#[derive(Debug, clap::Parser)]
+pub struct ServerArgs {
+    pub lobby_server_token: Uuid,
+    /// WebTransport port the lobby server connects to.
+    pub port: u16,
+    /// UDP port players connect to.
+    pub game_port: u16,
+    /// Send messages to the lobby server as JSON instead of binary, for debugging.
+    #[arg(long)]
+    pub debug_json: bool,
+    /// The game's data files, shared with the lobby server and client.
+    #[arg(long, default_value = "assets")]
+    pub assets: PathBuf,
+}
+
+#[derive(Resource)]
+struct Champions(ChampionRegistry);
+
+/// The connection to the lobby server, kept open between matches to report their results.
+#[derive(Resource, Clone)]
+struct LobbyConnection {
+    /// Keeps driving the connection in the background.
+    runtime: Arc<tokio::runtime::Runtime>,
+    connection: Arc<wtransport::Connection>,
+}
+
+/// The clients watching the game, who see it with a delay.
+///
+/// Clients can't send the game server any input yet, so there is nothing to reject from
+/// spectators; whatever input gets added must check [`Spectators::is_spectator`].
+#[derive(Resource)]
+pub struct Spectators {
+    clients: HashSet<ClientId>,
+    /// How far behind the game spectators are kept; see [`spectate::spectate`].
+    pub delay: Duration,
+}
+
+impl Spectators {
+    /// Whether `client` is watching rather than playing.
+    pub fn is_spectator(&self, client: ClientId) -> bool {
+        self.clients.contains(&client)
+    }
+}
+
+/// The netcode client ID of a player, which is also in their connect token.
+fn client_id(player: PlayerId) -> u64 {
+    player.get().as_u64_pair().0
+}
+
+/// The user data of a connect token, whose first byte marks spectators.
+fn token_user_data(spectator: bool) -> [u8; USER_DATA_BYTES] {
+    let mut data = [0; USER_DATA_BYTES];
+    data[0] = spectator.into();
+    data
+}
+
+/// Who played in the match, reported to the lobby server when it ends.
+///
+/// Nothing in the game counts kills, deaths, assists or gold yet, so only the team and champion
+/// of every player are known and the rest is reported as not tracked.
+#[derive(Resource)]
+pub struct MatchStats {
+    started: Instant,
+    pub players: Vec<PlayerMatchStats>,
+}
+
+/// Ends the match, reporting its result to the lobby server, which can then start the next one.
+#[derive(Event)]
+pub struct MatchEnded {
+    /// `None` if nobody won, like when every player left.
+    pub winner: Option<Team>,
+    /// Whether the match ended because players left, see [`MatchResult::forfeit`].
+    pub forfeit: bool,
+}
+
+/// Set once the lobby server closes its connection, which ends the match early.
+#[derive(Resource, Clone)]
+struct LobbyClosed(Arc<AtomicBool>);
+
+/// What a game server needs to play matches, whichever side opened the lobby connection.
+struct MatchHost {
+    /// Matches that don't carry this token are meant for another game server.
+    token: Uuid,
+    /// Where players reach the game, which goes into their connect tokens.
+    public_address: String,
+    game_port: u16,
+    assets: PathBuf,
+}
+
+/// Waits for the lobby server to connect, then plays its matches until it disconnects.
+pub fn run(options: ServerArgs) -> AppExit {
+    // Only ever dropped once we are done
+    let (_shutdown, never) = oneshot::channel();
+    run_until(options, never)
+}
+
+/// Like [`run`], but also returns once `shutdown` is sent to or dropped,
+/// which is how the lobby server stops the game servers it runs on a thread.
+pub fn run_until(options: ServerArgs, mut shutdown: oneshot::Receiver<()>) -> AppExit {
+    protocol::set_json_debug(options.debug_json);
+
+    // Start listening server
+    // The runtime has a worker thread of its own, so the lobby connection stays alive
+    // while the game runs.
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    // The lobby server only checked that our ports were free, which they may not be anymore.
+    // The game port is bound anew for every match, so this can't rule out losing it later.
+    if let Err(e) = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, options.game_port)) {
+        println!("GS: Could not bind game port {}: {e}", options.game_port);
+        return AppExit::from_code(PORTS_IN_USE);
+    }
+    let server = runtime.block_on(async {
+        Endpoint::server(
+            ServerConfig::builder()
+                .with_bind_address_v6(
+                    SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, options.port, 0, 0),
+                    Ipv6DualStackConfig::Allow,
+                )
+                .with_identity(Identity::self_signed(["localhost", "127.0.0.1", "::1"]).unwrap())
+                .keep_alive_interval(Some(Duration::from_secs(15)))
+                .build(),
+        )
+    });
+    let server = match server {
+        Ok(server) => server,
+        Err(e) => {
+            println!("GS: Could not bind port {}: {e}", options.port);
+            return AppExit::from_code(PORTS_IN_USE);
+        }
+    };
+    // The lobby server connects while we wait in its pool,
+    // then starts every match on a new stream of that connection
+    let connection = runtime.block_on(async {
+        // Someone else connecting, or the lobby server giving up halfway, must not take us down
+        let accept = async {
+            loop {
+                let connection: anyhow::Result<_> =
+                    async { Ok(server.accept().await.await?.accept().await?) }.await;
+                match connection {
+                    Ok(connection) => break connection,
+                    Err(e) => println!("GS: Lobby server could not connect: {e:#}"),
+                }
+            }
+        };
+        tokio::select! {
+            connection = accept => Some(connection),
+            _ = &mut shutdown => None,
+        }
+    });
+    let Some(connection) = connection else {
+        println!("GS: Shut down before the lobby server connected");
+        return AppExit::Success;
+    };
+    let lobby = LobbyConnection {
+        runtime: Arc::new(runtime),
+        connection: Arc::new(connection),
+    };
+    // Ends whatever we are doing the same way the lobby server disconnecting does
+    lobby.runtime.spawn({
+        let connection = lobby.connection.clone();
+        async move {
+            let _ = shutdown.await;
+            connection.close(VarInt::from_u32(0), b"shut down");
+        }
+    });
+    let host = MatchHost {
+        token: options.lobby_server_token,
+        public_address: format!("localhost:{}", options.game_port),
+        game_port: options.game_port,
+        assets: options.assets,
+    };
+    play_matches(&host, lobby)
+}
+
+/// Plays the matches the lobby server starts until it disconnects.
+fn play_matches(host: &MatchHost, lobby: LobbyConnection) -> AppExit {
+    // We need to generate connection tokens for every player
+    // To do so, we first need to receive the match from the lobby server
+
+    let champions = ChampionRegistry::load(host.assets.join("champions"))
+        .unwrap_or_else(|e| panic!("GS: Could not load champions: {e:#}"));
+
+    println!("GS: Generating key...");
+    let key = generate_key();
+
+    let closed = LobbyClosed(Arc::new(AtomicBool::new(false)));
+    lobby.runtime.spawn({
+        let connection = lobby.connection.clone();
+        let closed = closed.clone();
+        async move {
+            connection.closed().await;
+            closed.0.store(true, Ordering::Relaxed);
+        }
+    });
+
+    loop {
+        println!("GS: Waiting for a match...");
+        let Some((setup, spectators)) =
+            lobby
+                .runtime
+                .block_on(receive_match(&lobby.connection, host, &champions, key))
+        else {
+            println!("GS: Lobby server disconnected, shutting down");
+            return AppExit::Success;
+        };
+
+        let stats = setup
+            .players
+            .iter()
+            .flat_map(|(team, selections)| {
+                selections
+                    .iter()
+                    .cloned()
+                    .map(move |selection| PlayerMatchStats {
+                        player: selection.player,
+                        team: *team,
+                        champion: selection.champion,
+                        kills: None,
+                        deaths: None,
+                        assists: None,
+                        gold: None,
+                    })
+            })
+            .collect();
+
+        let exit = App::new()
+            .add_plugins((MinimalPlugins, build_server_plugin(key, host.game_port)))
+            .add_plugins((ProtocolPlugin, rules::rules, spectate::spectate))
+            .add_event::<MatchEnded>()
+            .add_systems(Startup, start_server)
+            .add_systems(
+                Update,
+                (report_match_result, log_connections, exit_if_lobby_closed),
+            )
+            .insert_resource(Champions(champions.clone()))
+            .insert_resource(setup)
+            .insert_resource(lobby.clone())
+            .insert_resource(closed.clone())
+            .insert_resource(MatchStats {
+                started: Instant::now(),
+                players: stats,
+            })
+            .insert_resource(spectators)
+            .run();
+        if exit.is_error() {
+            return exit;
+        }
+    }
+}
+
+/// Waits for the lobby server to start a match and answers with a connect token for every player.
+/// Matches we can't play are refused, and we wait for the next one.
+/// Returns `None` once the lobby server disconnects.
+async fn receive_match(
+    conn: &wtransport::Connection,
+    host: &MatchHost,
+    champions: &ChampionRegistry,
+    key: [u8; PRIVATE_KEY_BYTES],
+) -> Option<(MatchSetup, Spectators)> {
+    loop {
+        let stream = conn.accept_uni().await.ok()?;
+        match start_match(conn, stream, host, champions, key).await {
+            Ok(started) => return Some(started),
+            Err(e) => {
+                println!("GS: Refusing match: {e:#}");
+                let refusal: anyhow::Result<()> = async {
+                    let mut stream = conn.open_uni().await?.await?;
+                    stream
+                        .write_message(MessageFromGameServerToLobby::MatchRefused(e.to_string()))
+                        .await?;
+                    stream.flush().await?;
+                    stream.finish().await?;
+                    Ok(())
+                }
+                .await;
+                if let Err(e) = refusal {
+                    println!("GS: Could not refuse match: {e:#}");
+                }
+            }
+        }
+    }
+}
+
+/// Checks the match the lobby server sent on `stream` and sends it the players' connect tokens.
+async fn start_match(
+    conn: &wtransport::Connection,
+    mut stream: wtransport::RecvStream,
+    host: &MatchHost,
+    champions: &ChampionRegistry,
+    key: [u8; PRIVATE_KEY_BYTES],
+) -> anyhow::Result<(MatchSetup, Spectators)> {
+    let connect_message: MessageFromLobbyToGameServer = stream.read_message().await?;
+
+    println!("GS: Received LS message!");
+    let MessageFromLobbyToGameServer::LobbyInitialMessage {
+        token,
+        players,
+        spectators,
+        spectator_delay,
+    } = connect_message;
+
+    // Meant for an earlier registration of ours
+    anyhow::ensure!(token == host.token, "Wrong server token");
+
+    for selection in players.values().flatten() {
+        anyhow::ensure!(
+            champions.get(&selection.champion).is_some(),
+            "Unknown champion {:?}",
+            selection.champion
+        );
+    }
+
+    // Generate connection token for every player
+
+    let mut tokens = HashMap::new();
+
+    let player_ids = players.values().flatten().map(|sel| (sel.player.id, false));
+    let spectator_ids = spectators.iter().map(|info| (info.id, true));
+    for (id, spectator) in player_ids.chain(spectator_ids) {
+        println!("GS: Generating token...");
+        let token = ConnectToken::build(host.public_address.as_str(), 0, client_id(id), key)
+            .timeout_seconds(15)
+            .user_data(token_user_data(spectator))
+            .generate()?;
+
+        let wrapped_token = ConnectTokenWrapper(token.try_into_bytes()?.into());
+
+        tokens.insert(id, wrapped_token);
+        println!("GS: Token generated!");
+    }
+
+    println!("GS: Writing message...");
+    let mut stream = conn.open_uni().await?.await?;
+    stream
+        .write_message(MessageFromGameServerToLobby::PlayerTokensGenerated { players: tokens })
+        .await?;
+    stream.flush().await?;
+    stream.finish().await?;
+    println!("GS: Message written!");
+
+    let spectators = Spectators {
+        clients: spectators
+            .iter()
+            .map(|info| ClientId::Netcode(client_id(info.id)))
+            .collect(),
+        delay: spectator_delay,
+    };
+
+    Ok((MatchSetup { players }, spectators))
+}
+
+/// Opens the game port to players; lightyear doesn't on its own.
+fn start_server(mut commands: Commands) {
+    commands.start_server();
+}
+
+fn exit_if_lobby_closed(closed: Res<LobbyClosed>, mut exit: EventWriter<AppExit>) {
+    if closed.0.load(Ordering::Relaxed) {
+        println!("GS: Lobby server disconnected, ending the match");
+        exit.send(AppExit::error());
+    }
+}
+
+fn log_connections(mut events: EventReader<server::ConnectEvent>, spectators: Res<Spectators>) {
+    for event in events.read() {
+        let client = event.client_id();
+        if spectators.is_spectator(client) {
+            println!(
+                "GS: Spectator {client:?} connected, delayed by {:?}",
+                spectators.delay
+            );
+        } else {
+            println!("GS: Player {client:?} connected");
+        }
+    }
+}
+
+fn report_match_result(
+    mut ended: EventReader<MatchEnded>,
+    stats: Res<MatchStats>,
+    lobby: Res<LobbyConnection>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let Some(ended) = ended.read().next() else {
+        return;
+    };
+    let result = MatchResult {
+        winner: ended.winner,
+        forfeit: ended.forfeit,
+        duration: stats.started.elapsed(),
+        players: stats.players.clone(),
+    };
+
+    println!("GS: Match ended, reporting result...");
+    let report: anyhow::Result<()> = lobby.runtime.block_on(async {
+        let mut stream = lobby.connection.open_uni().await?.await?;
+        stream
+            .write_message(MessageFromGameServerToLobby::MatchFinished(result))
+            .await?;
+        stream.finish().await?;
+        Ok(())
+    });
+    if let Err(e) = report {
+        println!("GS: Could not report match result: {e}");
+    }
+    exit.send(AppExit::Success);
+}
+
+pub fn build_server_plugin(private_key: [u8; PRIVATE_KEY_BYTES], port: u16) -> ServerPlugins {
+    let io = server::IoConfig {
+        transport: server::ServerTransport::UdpSocket(SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port,
+        )),
+        ..default()
+    };
+    let config = server::NetcodeConfig {
+        private_key,
+        ..default()
+    };
+
+    let net_config = server::NetConfig::Netcode { config, io };
+    let config = server::ServerConfig {
+        shared: shared_config(),
+        net: vec![net_config],
+        replication: ReplicationConfig {
+            send_interval: SERVER_REPLICATION_INTERVAL,
+            ..default()
+        },
+        ..default()
+    };
+    ServerPlugins::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use lightyear::prelude::client::{self, ClientCommands};
+
+    use super::*;
+
+    #[derive(Resource, Default)]
+    struct Connected(bool);
+
+    /// A client connecting with `token`, set up like the game does.
+    fn client_app(token: ConnectToken) -> App {
+        let io = client::IoConfig {
+            transport: client::ClientTransport::UdpSocket(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                0,
+            )),
+            ..default()
+        };
+        let config = client::ClientConfig {
+            shared: shared_config(),
+            net: client::NetConfig::Netcode {
+                auth: client::Authentication::Token(token),
+                config: client::NetcodeConfig::default(),
+                io,
+            },
+            ..default()
+        };
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, client::ClientPlugins::new(config)))
+            .add_plugins(ProtocolPlugin)
+            .add_systems(Startup, |mut commands: Commands| commands.connect_client());
+        app
+    }
+
+    #[test]
+    fn players_can_connect_to_a_match() {
+        let port = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let key = generate_key();
+
+        let mut server = App::new();
+        server
+            .add_plugins((MinimalPlugins, build_server_plugin(key, port)))
+            .add_plugins(ProtocolPlugin)
+            .init_resource::<Connected>()
+            .add_systems(Startup, start_server)
+            .add_systems(
+                Update,
+                |mut events: EventReader<server::ConnectEvent>,
+                 mut connected: ResMut<Connected>| {
+                    connected.0 |= events.read().next().is_some();
+                },
+            );
+
+        let token = ConnectToken::build(
+            (Ipv6Addr::LOCALHOST, port),
+            0,
+            client_id(PlayerId::new()),
+            key,
+        )
+        .timeout_seconds(15)
+        .user_data(token_user_data(false))
+        .generate()
+        .unwrap();
+        let mut client = client_app(token);
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !server.world().resource::<Connected>().0 {
+            assert!(Instant::now() < deadline, "the client never connected");
+            server.update();
+            client.update();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
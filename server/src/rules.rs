@@ -0,0 +1,224 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use engine::net::Champion;
+use lightyear::prelude::*;
+use protocol::{PlayerSelection, Team};
+
+use crate::{Champions, MatchEnded, Spectators, client_id, spectate::replicate_to_players};
+
+/// Players that haven't connected within this long count as having left.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Ends the match once every player of all teams but one left, with the remaining team winning.
+///
+/// There is no gameplay to win a match by yet, so leaving is the only way to lose.
+pub fn rules(app: &mut App) {
+    app.add_systems(Startup, spawn_match)
+        .add_systems(Update, (track_connections, end_match).chain());
+}
+
+/// What the lobby server started the match with.
+#[derive(Resource)]
+pub struct MatchSetup {
+    pub players: HashMap<Team, Vec<PlayerSelection>>,
+}
+
+/// Who plays on which team, and who is still around.
+#[derive(Resource)]
+struct MatchState {
+    started: Instant,
+    teams: HashMap<Team, HashSet<ClientId>>,
+    connected: HashSet<ClientId>,
+    /// Players that connected and disconnected again.
+    left: HashSet<ClientId>,
+}
+
+impl MatchState {
+    fn new(players: &HashMap<Team, Vec<PlayerSelection>>) -> Self {
+        Self {
+            started: Instant::now(),
+            teams: players
+                .iter()
+                .map(|(team, selections)| {
+                    let clients = selections
+                        .iter()
+                        .map(|s| ClientId::Netcode(client_id(s.player.id)))
+                        .collect();
+                    (*team, clients)
+                })
+                .collect(),
+            connected: HashSet::new(),
+            left: HashSet::new(),
+        }
+    }
+
+    fn connect(&mut self, client: ClientId) {
+        let is_player = self.teams.values().any(|clients| clients.contains(&client));
+        if is_player {
+            self.connected.insert(client);
+            self.left.remove(&client);
+        }
+    }
+
+    fn disconnect(&mut self, client: ClientId) {
+        if self.connected.remove(&client) {
+            self.left.insert(client);
+        }
+    }
+
+    /// Whether `client` left, or never showed up.
+    fn is_gone(&self, client: &ClientId) -> bool {
+        self.left.contains(client)
+            || (!self.connected.contains(client) && self.started.elapsed() > CONNECT_TIMEOUT)
+    }
+
+    /// The winner once the match is over, `Some(None)` if nobody won.
+    fn outcome(&self) -> Option<Option<Team>> {
+        let (gone, remaining): (Vec<_>, Vec<_>) = self
+            .teams
+            .iter()
+            .partition(|(_, clients)| clients.iter().all(|c| self.is_gone(c)));
+        match remaining[..] {
+            _ if gone.is_empty() => None,
+            [] => Some(None),
+            [(team, _)] => Some(Some(*team)),
+            _ => None,
+        }
+    }
+}
+
+fn spawn_match(
+    setup: Res<MatchSetup>,
+    champions: Res<Champions>,
+    spectators: Res<Spectators>,
+    mut commands: Commands,
+) {
+    for (team, selections) in &setup.players {
+        for selection in selections {
+            // The lobby server only picks champions we know
+            let Some(def) = champions.0.get(&selection.champion) else {
+                continue;
+            };
+            commands.spawn((
+                Champion {
+                    id: def.id.clone(),
+                    team: team.0,
+                    client: client_id(selection.player.id),
+                },
+                replicate_to_players(&spectators),
+            ));
+        }
+    }
+
+    commands.insert_resource(MatchState::new(&setup.players));
+}
+
+fn track_connections(
+    mut connects: EventReader<server::ConnectEvent>,
+    mut disconnects: EventReader<server::DisconnectEvent>,
+    mut state: ResMut<MatchState>,
+) {
+    for event in connects.read() {
+        state.connect(event.client_id());
+    }
+    for event in disconnects.read() {
+        state.disconnect(event.client_id());
+    }
+}
+
+fn end_match(state: Res<MatchState>, mut ended: EventWriter<MatchEnded>, mut done: Local<bool>) {
+    if *done {
+        return;
+    }
+    let Some(winner) = state.outcome() else {
+        return;
+    };
+    match winner {
+        Some(team) => println!("GS: Every other team left, {team} wins"),
+        None => println!("GS: Every player left"),
+    }
+    *done = true;
+    // Leaving is the only way a match ends so far
+    ended.send(MatchEnded {
+        winner,
+        forfeit: true,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{PlayerId, PlayerInfo};
+
+    use super::*;
+
+    /// A match of one player against another.
+    fn one_on_one() -> (MatchState, ClientId, ClientId) {
+        let (red, blue) = (PlayerId::new(), PlayerId::new());
+        let players = [(Team::RED, red), (Team::BLUE, blue)]
+            .into_iter()
+            .map(|(team, id)| {
+                let player = PlayerInfo {
+                    id,
+                    name: format!("{team}"),
+                };
+                let champion = "Champion".to_string();
+                (team, vec![PlayerSelection { player, champion }])
+            })
+            .collect();
+        (
+            MatchState::new(&players),
+            ClientId::Netcode(client_id(red)),
+            ClientId::Netcode(client_id(blue)),
+        )
+    }
+
+    #[test]
+    fn team_that_stays_wins_once_the_other_left() {
+        let (mut state, red, blue) = one_on_one();
+        state.connect(red);
+        state.connect(blue);
+        assert_eq!(state.outcome(), None);
+
+        state.disconnect(blue);
+        assert_eq!(state.outcome(), Some(Some(Team::RED)));
+    }
+
+    #[test]
+    fn waits_for_players_that_have_not_connected_yet() {
+        let (mut state, red, _) = one_on_one();
+        state.connect(red);
+        assert_eq!(state.outcome(), None);
+    }
+
+    #[test]
+    fn players_that_never_connect_forfeit() {
+        let (mut state, red, _) = one_on_one();
+        state.connect(red);
+        state.started -= CONNECT_TIMEOUT * 2;
+        assert_eq!(state.outcome(), Some(Some(Team::RED)));
+    }
+
+    #[test]
+    fn nobody_wins_once_everyone_left() {
+        let (mut state, red, blue) = one_on_one();
+        state.connect(red);
+        state.connect(blue);
+        state.disconnect(red);
+        state.disconnect(blue);
+        assert_eq!(state.outcome(), Some(None));
+    }
+
+    #[test]
+    fn spectators_are_not_players() {
+        let (mut state, red, blue) = one_on_one();
+        state.connect(ClientId::Netcode(client_id(PlayerId::new())));
+        state.connect(red);
+        state.connect(blue);
+        state.disconnect(red);
+        assert_eq!(state.outcome(), Some(Some(Team::BLUE)));
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+use bevy::prelude::*;
+use engine::{SERVER_REPLICATION_INTERVAL, net::Champion};
+use lightyear::prelude::*;
+
+use crate::Spectators;
+
+/// Keeps spectators [`Spectators::delay`] behind the game, so they can't tell players what
+/// the enemy does.
+///
+/// Players are sent the game itself, and spectators a copy of it: the state of the game is
+/// recorded every replication interval and copied to the mirrored entities once it is old enough.
+pub fn spectate(app: &mut App) {
+    app.insert_resource(History {
+        snapshots: VecDeque::new(),
+        mirrors: HashMap::new(),
+        timer: Timer::new(SERVER_REPLICATION_INTERVAL, TimerMode::Repeating),
+    })
+    .add_systems(
+        Update,
+        (record_history, replay_history)
+            .chain()
+            .run_if(anyone_spectating),
+    );
+}
+
+/// Replicates an entity of the game to the players only; spectators get its mirror.
+pub fn replicate_to_players(spectators: &Spectators) -> server::Replicate {
+    let spectators = spectators.clients.iter().copied().collect();
+    server::Replicate {
+        target: server::ReplicateToClient {
+            target: NetworkTarget::AllExcept(spectators),
+        },
+        ..default()
+    }
+}
+
+fn replicate_to_spectators(spectators: &Spectators) -> server::Replicate {
+    let spectators = spectators.clients.iter().copied().collect();
+    server::Replicate {
+        target: server::ReplicateToClient {
+            target: NetworkTarget::Only(spectators),
+        },
+        ..default()
+    }
+}
+
+/// The delayed copy of an entity that spectators are sent, which the game itself leaves alone.
+#[derive(Component)]
+pub struct Mirror;
+
+#[derive(Resource)]
+struct History {
+    /// Oldest first.
+    snapshots: VecDeque<Snapshot>,
+    /// The mirror of every entity of the game that spectators have seen so far.
+    mirrors: HashMap<Entity, Entity>,
+    timer: Timer,
+}
+
+struct Snapshot {
+    taken: Instant,
+    entities: Vec<EntityState>,
+}
+
+struct EntityState {
+    entity: Entity,
+    champion: Champion,
+}
+
+fn anyone_spectating(spectators: Res<Spectators>) -> bool {
+    !spectators.clients.is_empty()
+}
+
+fn record_history(
+    time: Res<Time>,
+    mut history: ResMut<History>,
+    entities: Query<(Entity, &Champion), Without<Mirror>>,
+) {
+    if !history.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+    let entities = entities
+        .iter()
+        .map(|(entity, champion)| EntityState {
+            entity,
+            champion: champion.clone(),
+        })
+        .collect();
+    history.snapshots.push_back(Snapshot {
+        taken: Instant::now(),
+        entities,
+    });
+}
+
+fn replay_history(
+    spectators: Res<Spectators>,
+    mut history: ResMut<History>,
+    mut mirrors: Query<&mut Champion, With<Mirror>>,
+    mut commands: Commands,
+) {
+    // Skip to the newest snapshot spectators may see
+    let mut due = None;
+    while history
+        .snapshots
+        .front()
+        .is_some_and(|snapshot| snapshot.taken.elapsed() >= spectators.delay)
+    {
+        due = history.snapshots.pop_front();
+    }
+    let Some(snapshot) = due else {
+        return;
+    };
+
+    // Entities that were gone by then are gone for spectators as well
+    let mirror_of = &mut history.mirrors;
+    mirror_of.retain(|entity, mirror| {
+        let exists = snapshot
+            .entities
+            .iter()
+            .any(|state| state.entity == *entity);
+        if !exists {
+            commands.entity(*mirror).despawn();
+        }
+        exists
+    });
+
+    for state in snapshot.entities {
+        if let Some(mirror) = mirror_of.get(&state.entity) {
+            if let Ok(mut champion) = mirrors.get_mut(*mirror) {
+                champion.set_if_neq(state.champion);
+            }
+            continue;
+        }
+
+        let mirror = commands.spawn((Mirror, state.champion, replicate_to_spectators(&spectators)));
+        mirror_of.insert(state.entity, mirror.id());
+    }
+}